@@ -1,22 +1,28 @@
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    clock::Clock,
     entrypoint,
     entrypoint::ProgramResult,
+    hash::hash,
+    log::sol_log_data,
     msg,
     program_error::ProgramError,
     pubkey::Pubkey,
-    program::invoke,
+    program::{invoke, invoke_signed},
     program_pack::{Pack},
     sysvar::{rent::Rent, Sysvar},
 };
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account,
+};
 use spl_token::{
-    instruction::{approve, transfer},
-    state::Account as TokenAccount,
+    instruction::{approve, close_account, revoke, transfer},
+    state::{Account as TokenAccount, AccountState},
 };
 
 // Define a struct to represent the state
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default)]
 pub struct DexSlippage {
     pub owner: Pubkey,
     pub arb_tx_price: u64,
@@ -27,31 +33,412 @@ pub struct DexSlippage {
     pub slippage_percent: u8,
     pub mev_enabled: bool,
     pub liquidity_threshold: u64,
+    pub compound: bool,
+    pub mev_in_progress: bool,
+    pub outstanding_loan: u64,
+    pub pending_owner: Option<Pubkey>,
+    pub pending_owner_slot: u64,
+    pub pending_owner_window_slots: u64,
+    pub owners: [Pubkey; MAX_OWNERS],
+    pub threshold: u8,
+    // Minimum potential profit for an arbitrage to count as profitable.
+    // Replaces the hardcoded 1000 and its bitwise `&` check, which only
+    // matched profits whose bits happened to overlap 1000; it is now a plain `>=`.
+    // Older layouts are upgraded on read, see upgrade_profit_threshold.
+    pub min_profit_threshold: i64,
+    pub withdraw_delay_slots: u64,
+    pub instant_withdraw_limit: u64,
+    pub pending_withdraw_amount: u64,
+    pub pending_withdraw_slot: u64,
+    pub total_profit: u64,
+    pub last_profit_nonce: u64,
+    pub log_level: u8,
+    pub migration_destinations: [Pubkey; MAX_MIGRATION_DESTINATIONS],
+    pub allow_zero_amount: bool,
+    pub base_mint: Pubkey,
+    pub quote_mint: Pubkey,
+    pub retry_slippage_step_bps: u16,
+    pub max_retry_slippage_bps: u16,
+    pub paused: bool,
+    pub auto_pause_on_discrepancy: bool,
+    pub max_oracle_confidence_bps: u16,
+    pub liquidity_ratio_scale: u64,
+    pub optimization_iterations: u8,
+    pub arbitrage_iterations: u8,
+    pub unswept_profit: u64,
+    pub profit_in_sol: bool,
+    pub attempts_this_slot: u8,
+    pub attempts_slot: u64,
+    pub max_attempts_per_slot: u8,
+    pub rebalance_remainder_to_a: bool,
+    pub approved_delegate: Option<Pubkey>,
+    pub max_spread_bps: u16,
+    pub router_filter_mode: RouterFilterMode,
+    pub filtered_routers: [Pubkey; MAX_FILTERED_ROUTERS],
+    pub cached_plan: Option<ArbitragePlan>,
+    pub max_plan_age_slots: u64,
+    pub guardian: Pubkey,
+    pub min_slippage_change_interval_slots: u64,
+    pub last_slippage_change_slot: u64,
+    pub max_fallback_amount: u64,
+    pub position_caps: [(Pubkey, u64); MAX_POSITION_CAPS],
+    pub liquidity_threshold_mint: Pubkey,
+    pub safe_mode: bool,
+    pub daily_withdraw_limit: u64,
+    pub withdrawn_today: u64,
+    pub withdraw_window_start_slot: u64,
+    pub approval_slot: u64,
+    pub approval_ttl_slots: u64,
+    pub profit_mint: Pubkey,
+    pub profit_conversion_router: Pubkey,
+    pub trade_count: u64,
+    pub recent_profits: [u64; PROFIT_HISTORY_LEN],
+    pub recent_profits_next: u8,
+    pub max_discrepancy_tolerance: u64,
+    pub strategy: MevStrategy,
+    pub oracles: [Pubkey; MAX_ORACLES],
+    pub oracle_quorum: u8,
+    pub oracle_band_bps: u16,
+    pub buy_slippage_bps: u16,
+    pub sell_slippage_bps: u16,
+    pub compound_threshold: u64,
+    pub pending_compound: u64,
+    pub min_hops: u8,
+    pub fee_bps: u16,
+    pub fee_recipient: Pubkey,
+    pub accrued_fees: u64,
+    pub max_interleg_deviation_bps: u16,
+    pub min_operating_lamports: u64,
+    pub max_consecutive_losses: u8,
+    pub consecutive_losses: u8,
+    pub breaker_recovery_wins: u8,
+    pub breaker_recovery_remaining: u8,
+    pub router_fees: [(Pubkey, u16); MAX_ROUTER_FEES],
+    pub bundle_snapshot_account: Pubkey,
+    pub bundle_snapshot_balance: u64,
+    pub price_history: [u64; PRICE_HISTORY_LEN],
+    pub price_history_next: u8,
+    pub min_volatility_slippage_bps: u16,
+    pub max_volatility_slippage_bps: u16,
+    pub max_profit_delta_mismatch: u64,
+    pub pending_slippage_change_interval: Option<u64>,
+    pub pending_slippage_change_interval_slot: u64,
+    pub oracle_program: Pubkey,
+    pub lending_pool: Pubkey,
+}
+
+impl DexSlippage {
+    // Size of the struct in bytes, with every Option set. One term per field, in order.
+    pub const LEN: usize = 32 // owner
+        + 8 // arb_tx_price
+        + 1 // enable_trading
+        + 8 // token_pair
+        + 8 // trading_balance_in_tokens
+        + 1 // is_slippage_set
+        + 1 // slippage_percent
+        + 1 // mev_enabled
+        + 8 // liquidity_threshold
+        + 1 // compound
+        + 1 // mev_in_progress
+        + 8 // outstanding_loan
+        + 33 // pending_owner
+        + 8 // pending_owner_slot
+        + 8 // pending_owner_window_slots
+        + 32 * MAX_OWNERS // owners
+        + 1 // threshold
+        + 8 // min_profit_threshold
+        + 8 // withdraw_delay_slots
+        + 8 // instant_withdraw_limit
+        + 8 // pending_withdraw_amount
+        + 8 // pending_withdraw_slot
+        + 8 // total_profit
+        + 8 // last_profit_nonce
+        + 1 // log_level
+        + 32 * MAX_MIGRATION_DESTINATIONS // migration_destinations
+        + 1 // allow_zero_amount
+        + 32 // base_mint
+        + 32 // quote_mint
+        + 2 // retry_slippage_step_bps
+        + 2 // max_retry_slippage_bps
+        + 1 // paused
+        + 1 // auto_pause_on_discrepancy
+        + 2 // max_oracle_confidence_bps
+        + 8 // liquidity_ratio_scale
+        + 1 // optimization_iterations
+        + 1 // arbitrage_iterations
+        + 8 // unswept_profit
+        + 1 // profit_in_sol
+        + 1 // attempts_this_slot
+        + 8 // attempts_slot
+        + 1 // max_attempts_per_slot
+        + 1 // rebalance_remainder_to_a
+        + 33 // approved_delegate
+        + 2 // max_spread_bps
+        + 1 // router_filter_mode
+        + 32 * MAX_FILTERED_ROUTERS // filtered_routers
+        + 26 // cached_plan
+        + 8 // max_plan_age_slots
+        + 32 // guardian
+        + 8 // min_slippage_change_interval_slots
+        + 8 // last_slippage_change_slot
+        + 8 // max_fallback_amount
+        + 40 * MAX_POSITION_CAPS // position_caps
+        + 32 // liquidity_threshold_mint
+        + 1 // safe_mode
+        + 8 // daily_withdraw_limit
+        + 8 // withdrawn_today
+        + 8 // withdraw_window_start_slot
+        + 8 // approval_slot
+        + 8 // approval_ttl_slots
+        + 32 // profit_mint
+        + 32 // profit_conversion_router
+        + 8 // trade_count
+        + 8 * PROFIT_HISTORY_LEN // recent_profits
+        + 1 // recent_profits_next
+        + 8 // max_discrepancy_tolerance
+        + 1 // strategy
+        + 32 * MAX_ORACLES // oracles
+        + 1 // oracle_quorum
+        + 2 // oracle_band_bps
+        + 2 // buy_slippage_bps
+        + 2 // sell_slippage_bps
+        + 8 // compound_threshold
+        + 8 // pending_compound
+        + 1 // min_hops
+        + 2 // fee_bps
+        + 32 // fee_recipient
+        + 8 // accrued_fees
+        + 2 // max_interleg_deviation_bps
+        + 8 // min_operating_lamports
+        + 1 // max_consecutive_losses
+        + 1 // consecutive_losses
+        + 1 // breaker_recovery_wins
+        + 1 // breaker_recovery_remaining
+        + 34 * MAX_ROUTER_FEES // router_fees
+        + 32 // bundle_snapshot_account
+        + 8 // bundle_snapshot_balance
+        + 8 * PRICE_HISTORY_LEN // price_history
+        + 1 // price_history_next
+        + 2 // min_volatility_slippage_bps
+        + 2 // max_volatility_slippage_bps
+        + 8 // max_profit_delta_mismatch
+        + 9 // pending_slippage_change_interval
+        + 8 // pending_slippage_change_interval_slot
+        + 32 // oracle_program
+        + 32; // lending_pool
 }
 
+// Every length the state account has been allocated with, one per layout,
+// oldest first. Each layout only appended fields to the one before it.
+// Append the outgoing LEN here whenever a field is added.
+pub const STATE_LAYOUT_LENS: [usize; 51] = [
+    DexSlippageV1::LEN,
+    69, 78, 127, 288, 296, 328, 344, 345, 473, 474, 538, 542,
+    544, 546, 554, 556, 565, 575, 576, 609, 611, 868, 902, 934,
+    950, 958, 1278, 1310, 1311, 1335, 1351, 1415, 1488, 1496, 1497, 1660,
+    1664, 1680, 1681, 1723, 1725, 1733, 1737, 2009, 2049, 2118, 2126,
+    2143, 2175,
+    DexSlippage::LEN,
+];
+
+// Accounts created before a layout change keep their shorter data until
+// migrated. Since layouts only ever append fields, an older account is read by
+// zero-extending it to the current length, which decodes every newer field as
+// its default. Writes need the full length, see migrate_state.
+//
+// The account is allocated at LEN, the size with every Option set. A None
+// serializes shorter and leaves unused bytes at the end, so the current layout
+// is decoded without requiring the whole buffer to be consumed.
 impl DexSlippage {
+    pub fn from_versioned_slice(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.len() >= DexSlippage::LEN {
+            return Ok(DexSlippage::deserialize(&mut &data[..])?);
+        }
+        if !STATE_LAYOUT_LENS.contains(&data.len()) {
+            msg!("State account length {} matches no known layout", data.len());
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut extended = data.to_vec();
+        extended.resize(DexSlippage::LEN, 0);
+        let mut dex_slippage = DexSlippage::deserialize(&mut &extended[..])?;
+        upgrade_profit_threshold(&mut dex_slippage, data.len());
+        Ok(dex_slippage)
+    }
+}
+
+// First layout with min_profit_threshold, and the first one written after it
+// became signed. Accounts at 1664 may have been written either way.
+pub const MIN_PROFIT_THRESHOLD_LAYOUT_LEN: usize = 296;
+pub const SIGNED_PROFIT_THRESHOLD_LAYOUT_LEN: usize = 1680;
+
+// The threshold hardcoded before min_profit_threshold existed
+pub const LEGACY_MIN_PROFIT_THRESHOLD: i64 = 1_000;
+
+// Layouts before the field existed keep the old threshold of 1000 instead of
+// reading 0, which would count any non-negative cycle as profitable. Layouts
+// before it became signed stored a u64: one above i64::MAX could never be met,
+// so it stays unreachable rather than decoding as a loss the bot accepts.
+fn upgrade_profit_threshold(dex_slippage: &mut DexSlippage, layout_len: usize) {
+    if layout_len < MIN_PROFIT_THRESHOLD_LAYOUT_LEN {
+        dex_slippage.min_profit_threshold = LEGACY_MIN_PROFIT_THRESHOLD;
+    } else if layout_len < SIGNED_PROFIT_THRESHOLD_LAYOUT_LEN && dex_slippage.min_profit_threshold < 0 {
+        dex_slippage.min_profit_threshold = i64::MAX;
+    }
+}
+
+// Original state layout, before multi-sig, withdrawal limits and the rest
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default)]
+pub struct DexSlippageV1 {
+    pub owner: Pubkey,
+    pub arb_tx_price: u64,
+    pub enable_trading: bool,
+    pub token_pair: u64,
+    pub trading_balance_in_tokens: u64,
+    pub is_slippage_set: bool,
+    pub slippage_percent: u8,
+    pub mev_enabled: bool,
+    pub liquidity_threshold: u64,
+}
+
+impl DexSlippageV1 {
     pub const LEN: usize = 32 + 8 + 1 + 8 + 8 + 1 + 1 + 1 + 8; // Size of the struct in bytes
 }
 
+// Maximum number of co-owners that can sign privileged operations
+pub const MAX_OWNERS: usize = 5;
+
+// Maximum number of new program states the owner can allow funds to migrate to
+pub const MAX_MIGRATION_DESTINATIONS: usize = 4;
+
+// Roughly one day of 400ms slots, the window for daily_withdraw_limit
+pub const SLOTS_PER_DAY: u64 = 216_000;
+
+// Which path perform_mev runs
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum MevStrategy {
+    // Borrow twice the amount and arbitrage it atomically
+    #[default]
+    FlashloanArb,
+    // Arbitrage the amount from the bot's own balance
+    SpotArb,
+    // Provide the amount of each token as liquidity
+    LiquidityProvision,
+}
+
+// Maximum number of oracle accounts consulted for a price
+pub const MAX_ORACLES: usize = 5;
+
+// Number of most recent trade profits kept in the ring buffer
+pub const PROFIT_HISTORY_LEN: usize = 8;
+
+// Number of most recent pool prices kept for the volatility estimate
+pub const PRICE_HISTORY_LEN: usize = 8;
+
+// Fixed-point scale of a recorded pool price
+pub const PRICE_OBSERVATION_SCALE: u128 = 1_000_000_000;
+
+// Maximum number of mints with a position cap
+pub const MAX_POSITION_CAPS: usize = 8;
+
+// Maximum number of routers in the allowlist/denylist
+pub const MAX_FILTERED_ROUTERS: usize = 8;
+
+// Maximum number of routers with a swap fee configured
+pub const MAX_ROUTER_FEES: usize = 8;
+
+// How `filtered_routers` is applied when quoting through a router
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum RouterFilterMode {
+    #[default]
+    None,
+    Allowlist,
+    Denylist,
+}
+
+// Precision of the liquidity ratio when no scale is configured
+pub const DEFAULT_LIQUIDITY_RATIO_SCALE: u64 = 1_000_000;
+
+// Loop bounds for the MEV optimization and arbitrage trade loops. A stored
+// value of 0 means the default; set values must fall within 1..=MAX.
+pub const DEFAULT_OPTIMIZATION_ITERATIONS: u8 = 10;
+pub const MAX_OPTIMIZATION_ITERATIONS: u8 = 32;
+pub const DEFAULT_ARBITRAGE_ITERATIONS: u8 = 5;
+pub const MAX_ARBITRAGE_ITERATIONS: u8 = 16;
+
+fn optimization_iterations(dex_slippage: &DexSlippage) -> u8 {
+    match dex_slippage.optimization_iterations {
+        0 => DEFAULT_OPTIMIZATION_ITERATIONS,
+        iterations => iterations.min(MAX_OPTIMIZATION_ITERATIONS),
+    }
+}
+
+fn arbitrage_iterations(dex_slippage: &DexSlippage) -> u8 {
+    match dex_slippage.arbitrage_iterations {
+        0 => DEFAULT_ARBITRAGE_ITERATIONS,
+        iterations => iterations.min(MAX_ARBITRAGE_ITERATIONS),
+    }
+}
+
+// Log levels: quiet only logs outcomes, verbose also logs intermediate figures
+pub const LOG_LEVEL_QUIET: u8 = 0;
+pub const LOG_LEVEL_VERBOSE: u8 = 1;
+
+fn is_verbose(dex_slippage: &DexSlippage) -> bool {
+    dex_slippage.log_level >= LOG_LEVEL_VERBOSE
+}
+
+// msg! that only logs when the state's log_level is verbose
+macro_rules! debug_msg {
+    ($dex_slippage:expr, $($arg:tt)+) => {
+        if is_verbose(&$dex_slippage) {
+            msg!($($arg)+);
+        }
+    };
+}
+
 entrypoint!(process_instruction);
 
+// The transfer's six accounts plus a signature from every co-owner
+pub const MAX_TRANSFER_ACCOUNTS: usize = 6 + MAX_OWNERS;
+
+// Transfers `amount` (u64 LE) of tokens and credits the trading balance.
+//
+// Accounts:
+//   0. `[signer]` owner
+//   1. `[]` token program
+//   2. `[writable]` source token account
+//   3. `[writable]` destination token account
+//   4. `[signer]` source account authority
+//   5. `[writable]` state account
+//   6.. `[signer]` co-owners, when a multi-sig threshold is set
 pub fn process_instruction(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     instruction_data: &[u8],
 ) -> ProgramResult {
+    if accounts.len() > MAX_TRANSFER_ACCOUNTS {
+        msg!("Too many accounts: {} (max {})", accounts.len(), MAX_TRANSFER_ACCOUNTS);
+        return Err(ProgramError::InvalidArgument);
+    }
+
     let accounts_iter = &mut accounts.iter();
     let owner = next_account_info(accounts_iter)?;
     let token_program = next_account_info(accounts_iter)?;
     let token_account_from = next_account_info(accounts_iter)?;
     let token_account_to = next_account_info(accounts_iter)?;
     let authority = next_account_info(accounts_iter)?;
-    let rent_info = next_account_info(accounts_iter)?;
     let state_account = next_account_info(accounts_iter)?;
 
-    // Deserialize state account data
-    let mut state_data = state_account.try_borrow_mut_data()?;
-    let mut dex_slippage = DexSlippage::try_from_slice(&state_data)?;
+    ensure_distinct_owner(owner, state_account)?;
+
+    // Everything the transfer mutates must be passed writable
+    ensure_writable(state_account)?;
+    ensure_writable(token_account_from)?;
+    ensure_writable(token_account_to)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let mut dex_slippage = load_state(state_account)?;
 
     // The amount to transfer or approve
     let amount = instruction_data
@@ -60,10 +447,20 @@ pub fn process_instruction(
     let amount = u64::from_le_bytes(amount.try_into().unwrap());
 
     // Ensure the owner matches
-    if dex_slippage.owner != *owner.key {
-        return Err(ProgramError::IncorrectProgramId);
+    assert_owner(&dex_slippage, owner, accounts)?;
+    check_token_amount(&dex_slippage, amount)?;
+    validate_token_account(token_account_from, state_account, program_id)?;
+    validate_token_account(token_account_to, state_account, program_id)?;
+    check_position_cap(&dex_slippage, token_account_to, amount)?;
+
+    // A delegation past its TTL is revoked on the next transfer
+    if dex_slippage.approval_ttl_slots > 0 && expire_delegation(&mut dex_slippage, Clock::get()?.slot) {
+        revoke_tokens(token_program, token_account_from, authority)?;
+        msg!("Delegation on {} expired and was revoked", token_account_from.key);
     }
 
+    let balance_before = get_token_balance(token_account_to)?;
+
     // Perform token transfer
     transfer_tokens(
         token_program,
@@ -73,9 +470,210 @@ pub fn process_instruction(
         amount,
     )?;
 
+    // Funds entering the trading account are held to the buy-side tolerance
+    let received = get_token_balance(token_account_to)?.saturating_sub(balance_before);
+    check_fill(
+        &dex_slippage,
+        TradeSide::Buy,
+        &SwapFill {
+            quoted: amount,
+            filled: received,
+        },
+    )?;
+
     // Update state
     dex_slippage.trading_balance_in_tokens += amount;
-    dex_slippage.serialize(&mut *state_data)?;
+    store_state(state_account, &dex_slippage)?;
+
+    Ok(())
+}
+
+// Serializes into a scratch buffer first, so state that no longer fits the
+// account errors out before anything is written instead of being half-written.
+// State that shrank, such as an Option cleared to None, leaves no stale bytes
+// behind: the rest of the account is zeroed.
+fn write_state(dex_slippage: &DexSlippage, state_data: &mut [u8]) -> ProgramResult {
+    let serialized = dex_slippage.try_to_vec()?;
+    if serialized.len() > state_data.len() {
+        msg!("State needs {} bytes, account has {}", serialized.len(), state_data.len());
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    state_data[..serialized.len()].copy_from_slice(&serialized);
+    state_data[serialized.len()..].fill(0);
+    Ok(())
+}
+
+// Skeleton shared by the owner-only setters: takes the owner and state accounts,
+// checks the owner and writes the state back once `update` succeeds
+fn update_state<F>(accounts: &[AccountInfo], update: F) -> ProgramResult
+where
+    F: FnOnce(&mut DexSlippage) -> ProgramResult,
+{
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    update(&mut dex_slippage)?;
+    write_state(&dex_slippage, &mut state_data)?;
+
+    Ok(())
+}
+
+// Reads the state without keeping the account borrowed, so a CPI that re-enters
+// the program can borrow it again. Pair with `store_state` once the CPI returns.
+fn load_state(state_account: &AccountInfo) -> Result<DexSlippage, ProgramError> {
+    let state_data = state_account.try_borrow_data()?;
+    DexSlippage::from_versioned_slice(&state_data)
+}
+
+fn store_state(state_account: &AccountInfo, dex_slippage: &DexSlippage) -> ProgramResult {
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    write_state(dex_slippage, &mut state_data)
+}
+
+// Writing to an account passed read-only only fails once the instruction returns,
+// with a runtime error that doesn't say which account was at fault
+fn ensure_writable(account: &AccountInfo) -> ProgramResult {
+    if !account.is_writable {
+        msg!("Account {} must be writable", account.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+// The state account is program-owned data, never a signer, so passing it in
+// the owner slot is always a malformed call rather than a real owner
+fn ensure_distinct_owner(owner: &AccountInfo, state_account: &AccountInfo) -> ProgramResult {
+    if owner.key == state_account.key {
+        msg!("Owner and state account must be distinct: {}", owner.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+// Zero-amount transfers and approvals succeed but waste compute, so they
+// are rejected unless explicitly allowed
+fn check_token_amount(dex_slippage: &DexSlippage, amount: u64) -> ProgramResult {
+    if amount == 0 && !dex_slippage.allow_zero_amount {
+        msg!("Zero-amount token operations are not allowed");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+// Catches the state or program account being passed where a token account is
+// expected, before TokenAccount::unpack turns it into a confusing error
+fn validate_token_account(
+    token: &AccountInfo,
+    state_account: &AccountInfo,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    if token.key == state_account.key || token.key == program_id {
+        msg!("Account {} is the program or state account, not a token account", token.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+    if *token.owner != spl_token::id() {
+        msg!("Account {} is not owned by the token program", token.key);
+        return Err(ProgramError::IllegalOwner);
+    }
+
+    Ok(())
+}
+
+// Swap fee the router's AMM charges, 0 when none is configured
+fn router_fee_bps(dex_slippage: &DexSlippage, router: &Pubkey) -> u16 {
+    dex_slippage
+        .router_fees
+        .iter()
+        .find(|(fee_router, _)| fee_router == router)
+        .map(|(_, fee_bps)| *fee_bps)
+        .unwrap_or(0)
+}
+
+fn position_cap(dex_slippage: &DexSlippage, mint: &Pubkey) -> Option<u64> {
+    dex_slippage
+        .position_caps
+        .iter()
+        .find(|(capped_mint, cap)| capped_mint == mint && *cap > 0)
+        .map(|(_, cap)| *cap)
+}
+
+// Rejects an operation that would push the token account's balance above its mint's cap
+fn check_position_cap(dex_slippage: &DexSlippage, token: &AccountInfo, incoming: u64) -> ProgramResult {
+    let account = TokenAccount::unpack(&token.data.borrow())?;
+    let cap = match position_cap(dex_slippage, &account.mint) {
+        Some(cap) => cap,
+        None => return Ok(()),
+    };
+
+    let resulting_balance = account
+        .amount
+        .checked_add(incoming)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    if resulting_balance > cap {
+        msg!("Position in {} would be {}, above its cap of {}", account.mint, resulting_balance, cap);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+// Privileged handlers require the owner, plus `threshold` co-owner signatures
+// when multi-sig is configured. A threshold of 0 or 1 is the single-owner case.
+fn assert_owner(
+    dex_slippage: &DexSlippage,
+    owner: &AccountInfo,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    if dex_slippage.owner != *owner.key {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Even the single-owner case, threshold 1, needs the owner's signature
+    if !owner.is_signer {
+        msg!("Owner {} must sign", owner.key);
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if dex_slippage.threshold <= 1 {
+        return Ok(());
+    }
+
+    // Each listed key counts once, however often it appears in the list
+    let mut co_owners: Vec<&Pubkey> = dex_slippage
+        .owners
+        .iter()
+        .filter(|co_owner| **co_owner != Pubkey::default())
+        .collect();
+    co_owners.sort();
+    co_owners.dedup();
+    let signatures = co_owners
+        .into_iter()
+        .filter(|co_owner| {
+            accounts
+                .iter()
+                .any(|account| account.key == *co_owner && account.is_signer)
+        })
+        .count();
+
+    if signatures < dex_slippage.threshold as usize {
+        msg!("Multi-sig threshold not met: {} of {}", signatures, dex_slippage.threshold);
+        return Err(ProgramError::MissingRequiredSignature);
+    }
 
     Ok(())
 }
@@ -104,6 +702,32 @@ fn transfer_tokens(
     )
 }
 
+fn transfer_tokens_signed(
+    token_program: &AccountInfo,
+    source: &AccountInfo,
+    destination: &AccountInfo,
+    authority: &AccountInfo,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let transfer_instruction = transfer(
+        &token_program.key,
+        &source.key,
+        &destination.key,
+        &authority.key,
+        &[],
+        amount,
+    )?;
+
+    let account_infos = &[token_program.clone(), source.clone(), destination.clone(), authority.clone()];
+
+    invoke_signed(
+        &transfer_instruction,
+        account_infos,
+        signer_seeds,
+    )
+}
+
 fn approve_tokens(
     token_program: &AccountInfo,
     source: &AccountInfo,
@@ -128,10 +752,31 @@ fn approve_tokens(
     )
 }
 
-// Function to initialize the contract state
+fn revoke_tokens(
+    token_program: &AccountInfo,
+    source: &AccountInfo,
+    owner: &AccountInfo,
+) -> ProgramResult {
+    let revoke_instruction = revoke(&token_program.key, &source.key, &owner.key, &[])?;
+
+    let account_infos = &[token_program.clone(), source.clone(), owner.clone()];
+
+    invoke(
+        &revoke_instruction,
+        account_infos,
+    )
+}
+
+// Function to initialize the contract state. Each strategy index gets its own
+// state PDA, so one owner can run several independently configured bots. The
+// caller's nonce is part of the seeds: racing initializations of the same
+// (strategy, nonce) resolve to one winner, and every later attempt fails with
+// AccountAlreadyInitialized.
 pub fn initialize(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
+    strategy_index: u8,
+    nonce: u64,
     data: &[u8],
 ) -> ProgramResult {
     let accounts_iter = &mut accounts.iter();
@@ -140,18 +785,36 @@ pub fn initialize(
     let system_program = next_account_info(accounts_iter)?;
     let rent_info = next_account_info(accounts_iter)?;
 
-    let (state_pda, _state_bump) = Pubkey::find_program_address(&[b"state"], program_id);
+    // The payer funds the new state account
+    ensure_writable(payer)?;
+    ensure_writable(state_account)?;
+
+    let nonce_bytes = nonce.to_le_bytes();
+    let (state_pda, state_bump) =
+        Pubkey::find_program_address(&[b"state", &[strategy_index], &nonce_bytes], program_id);
 
     // Check if the state account is already initialized
     if state_account.owner != system_program.key {
+        msg!("State for strategy {} nonce {} is already initialized", strategy_index, nonce);
         return Err(ProgramError::AccountAlreadyInitialized);
     }
 
+    // Ensure the rent account is the real rent sysvar
+    if rent_info.key != &solana_program::sysvar::rent::id() {
+        msg!("Rent account is not the rent sysvar");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if *state_account.key != state_pda {
+        msg!("State account is not the PDA for strategy {} nonce {}", strategy_index, nonce);
+        return Err(ProgramError::InvalidSeeds);
+    }
+
     // Create the state account with space for the DexSlippage struct
-    let rent = &Rent::from_account_info(rent_info)?;
+    let rent = &Rent::get()?;
     let required_lamports = rent.minimum_balance(DexSlippage::LEN);
 
-    solana_program::program::invoke(
+    solana_program::program::invoke_signed(
         &solana_program::system_instruction::create_account(
             &payer.key,
             &state_account.key,
@@ -159,66 +822,98 @@ pub fn initialize(
             DexSlippage::LEN as u64,
             program_id,
         ),
-        &[payer.clone(), state_account.clone()],
+        &[payer.clone(), state_account.clone(), system_program.clone()],
+        &[&[b"state", &[strategy_index], &nonce_bytes, &[state_bump]]],
     )?;
 
-    // Initialize the state
+    // Initialize the state. Whoever pays for the account owns it, regardless
+    // of the owner in the submitted config.
     let mut state_data = state_account.try_borrow_mut_data()?;
     let mut dex_slippage = DexSlippage::try_from_slice(data)?;
-    dex_slippage.serialize(&mut *state_data)?;
+    dex_slippage.owner = *payer.key;
+    write_state(&dex_slippage, &mut state_data)?;
 
     Ok(())
 }
 
-fn set_slippage(
+// Grows a state account created under an older layout to the current LEN, with
+// the owner topping up the rent. Until then the account reads fine but every
+// write fails with AccountDataTooSmall, as the current state doesn't fit it.
+fn migrate_state(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    slippage_percent: u8,
 ) -> ProgramResult {
     let accounts_iter = &mut accounts.iter();
     let owner = next_account_info(accounts_iter)?;
     let state_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
 
-    // Deserialize state account data
-    let mut state_data = state_account.try_borrow_mut_data()?;
-    let mut dex_slippage = DexSlippage::try_from_slice(&state_data)?;
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let dex_slippage = load_state(state_account)?;
 
     // Ensure the owner matches
-    if dex_slippage.owner != *owner.key {
-        return Err(ProgramError::IncorrectProgramId);
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    if state_account.data_len() >= DexSlippage::LEN {
+        msg!("State is already on the current layout");
+        return Ok(());
+    }
+
+    let required_lamports = Rent::get()?.minimum_balance(DexSlippage::LEN);
+    let top_up = required_lamports.saturating_sub(state_account.lamports());
+    if top_up > 0 {
+        invoke(
+            &solana_program::system_instruction::transfer(owner.key, state_account.key, top_up),
+            &[owner.clone(), state_account.clone(), system_program.clone()],
+        )?;
     }
 
-    // Set slippage
-    dex_slippage.slippage_percent = slippage_percent;
-    dex_slippage.is_slippage_set = true;
-    dex_slippage.serialize(&mut *state_data)?;
+    let old_len = state_account.data_len();
+    state_account.realloc(DexSlippage::LEN, true)?;
+    store_state(state_account, &dex_slippage)?;
+
+    msg!("Migrated state from {} to {} bytes", old_len, DexSlippage::LEN);
 
     Ok(())
 }
 
-fn enable_mev(
+fn set_slippage(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    enable: bool,
+    slippage_percent: u8,
 ) -> ProgramResult {
-    let accounts_iter = &mut accounts.iter();
-    let owner = next_account_info(accounts_iter)?;
-    let state_account = next_account_info(accounts_iter)?;
-
-    // Deserialize state account data
-    let mut state_data = state_account.try_borrow_mut_data()?;
-    let mut dex_slippage = DexSlippage::try_from_slice(&state_data)?;
+    update_state(accounts, |dex_slippage| {
+        validate_slippage(slippage_percent)?;
+        if dex_slippage.min_slippage_change_interval_slots > 0 {
+            record_slippage_change(dex_slippage, Clock::get()?.slot)?;
+        }
 
-    // Ensure the owner matches
-    if dex_slippage.owner != *owner.key {
-        return Err(ProgramError::IncorrectProgramId);
-    }
+        // Set slippage
+        dex_slippage.slippage_percent = slippage_percent;
+        dex_slippage.is_slippage_set = true;
+        Ok(())
+    })
+}
 
-    // Enable or disable MEV
-    dex_slippage.mev_enabled = enable;
-    dex_slippage.serialize(&mut *state_data)?;
+fn enable_mev(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    enable: bool,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Re-enabling, typically after the circuit breaker tripped, starts its recovery window
+        if enable && !dex_slippage.mev_enabled {
+            dex_slippage.consecutive_losses = 0;
+            dex_slippage.breaker_recovery_remaining = dex_slippage.breaker_recovery_wins;
+        }
 
-    Ok(())
+        // Enable or disable MEV
+        dex_slippage.mev_enabled = enable;
+        Ok(())
+    })
 }
 
 fn set_liquidity_threshold(
@@ -226,466 +921,9158 @@ fn set_liquidity_threshold(
     accounts: &[AccountInfo],
     threshold: u64,
 ) -> ProgramResult {
-    let accounts_iter = &mut accounts.iter();
-    let owner = next_account_info(accounts_iter)?;
-    let state_account = next_account_info(accounts_iter)?;
+    update_state(accounts, |dex_slippage| {
+        validate_liquidity_threshold(threshold)?;
 
-    // Deserialize state account data
-    let mut state_data = state_account.try_borrow_mut_data()?;
-    let mut dex_slippage = DexSlippage::try_from_slice(&state_data)?;
+        // Set liquidity threshold
+        dex_slippage.liquidity_threshold = threshold;
+        Ok(())
+    })
+}
 
-    // Ensure the owner matches
-    if dex_slippage.owner != *owner.key {
-        return Err(ProgramError::IncorrectProgramId);
-    }
+// Sets slippage and liquidity threshold together so the config is never half-updated
+fn set_guards(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    slippage_percent: u8,
+    liquidity_threshold: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Validate both before applying either
+        validate_slippage(slippage_percent)?;
+        validate_liquidity_threshold(liquidity_threshold)?;
+        if dex_slippage.min_slippage_change_interval_slots > 0 {
+            record_slippage_change(dex_slippage, Clock::get()?.slot)?;
+        }
+
+        // Set slippage and liquidity threshold
+        dex_slippage.slippage_percent = slippage_percent;
+        dex_slippage.is_slippage_set = true;
+        dex_slippage.liquidity_threshold = liquidity_threshold;
+        Ok(())
+    })
+}
 
-    // Set liquidity threshold
-    dex_slippage.liquidity_threshold = threshold;
-    dex_slippage.serialize(&mut *state_data)?;
+// Rapid slippage changes may be a compromised key loosening protections, so
+// changes are rate-limited. A last change slot of 0 means never changed.
+fn record_slippage_change(dex_slippage: &mut DexSlippage, current_slot: u64) -> ProgramResult {
+    let last_change = dex_slippage.last_slippage_change_slot;
+    if last_change != 0
+        && current_slot.saturating_sub(last_change) < dex_slippage.min_slippage_change_interval_slots
+    {
+        msg!("Slippage was changed at slot {}, too recently", last_change);
+        return Err(ProgramError::InvalidArgument);
+    }
 
+    dex_slippage.last_slippage_change_slot = current_slot;
     Ok(())
 }
 
-fn calculate_arbitrage(
-    router1: &AccountInfo,
-    router2: &AccountInfo,
-    router3: &AccountInfo,
-    token1: &AccountInfo,
-    token2: &AccountInfo,
-    token3: &AccountInfo,
-    amount: u64,
-) -> ProgramResult {
-    msg!("Calculating arbitrage...");
+fn validate_slippage(slippage_percent: u8) -> ProgramResult {
+    if slippage_percent > 100 {
+        msg!("Slippage must be at most 100%");
+        return Err(ProgramError::InvalidArgument);
+    }
 
-    let price1 = get_price_from_router(router1, token1, token2, amount)?;
-    msg!("Price from router1: {}", price1);
+    Ok(())
+}
 
-    let price2 = get_price_from_router(router2, token2, token3, price1)?;
-    msg!("Price from router2: {}", price2);
+// The threshold is denominated in liquidity_threshold_mint, so it is compared
+// against the pool reserve holding that mint. Nothing is enforced until a mint is set.
+fn check_liquidity(dex_slippage: &DexSlippage, pool_accounts: &[AccountInfo]) -> ProgramResult {
+    if dex_slippage.liquidity_threshold_mint == Pubkey::default() {
+        return Ok(());
+    }
 
-    let price3 = get_price_from_router(router3, token3, token1, price2)?;
-    msg!("Price from router3: {}", price3);
+    for pool_account in pool_accounts {
+        let reserve = TokenAccount::unpack(&pool_account.data.borrow())?;
+        if reserve.mint != dex_slippage.liquidity_threshold_mint {
+            continue;
+        }
 
-    let potential_profit = price3 as i64 - amount as i64;
-    msg!("Potential profit: {}", potential_profit);
+        if reserve.amount < dex_slippage.liquidity_threshold {
+            msg!(
+                "Pool liquidity {} of mint {} below threshold {}",
+                reserve.amount,
+                reserve.mint,
+                dex_slippage.liquidity_threshold
+            );
+            return Err(ProgramError::InsufficientFunds);
+        }
+        return Ok(());
+    }
 
-    let price_difference = (price3 as i128 - price1 as i128) >> 1;
-    msg!("Price difference after bit shift: {}", price_difference);
-
-    let threshold: i128 = 1000;
-    let is_profitable = (potential_profit as i128 & threshold) == threshold;
-    msg!("Is arbitrage profitable? {}", is_profitable);
-
-    let adjusted_profit = (potential_profit as i128).wrapping_mul(10).wrapping_add(price_difference);
-    msg!("Adjusted profit: {}", adjusted_profit);
-
-    let arbitrage_opportunity = adjusted_profit > threshold;
-    msg!("Arbitrage opportunity detected: {}", arbitrage_opportunity);
-
-    if arbitrage_opportunity {
-        let execution_price1 = price1.wrapping_mul(3) >> 2;
-        let execution_price2 = price2.wrapping_mul(5) >> 3;
-        let execution_price3 = price3.wrapping_mul(7) >> 4;
-        
-        msg!("Execution price1: {}", execution_price1);
-        msg!("Execution price2: {}", execution_price2);
-        msg!("Execution price3: {}", execution_price3);
+    msg!("No pool reserve of mint {}", dex_slippage.liquidity_threshold_mint);
+    Err(ProgramError::InvalidArgument)
+}
 
-        let final_arbitrage_value = (execution_price1 as u64).wrapping_add(execution_price2).wrapping_add(execution_price3);
-        msg!("Final arbitrage value: {}", final_arbitrage_value);
+fn validate_liquidity_threshold(threshold: u64) -> ProgramResult {
+    if threshold == 0 {
+        msg!("Liquidity threshold must be non-zero");
+        return Err(ProgramError::InvalidArgument);
     }
 
     Ok(())
 }
 
-fn perform_mev(
+// Quote returned by a router, with the slippage-protected minimum output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RouterQuote {
+    pub expected: u64,
+    pub min_out: u64,
+}
+
+fn get_price_from_router(
+    dex_slippage: &DexSlippage,
     router: &AccountInfo,
     token_in: &AccountInfo,
     token_out: &AccountInfo,
     amount: u64,
-) -> ProgramResult {
-    msg!("Performing MEV...");
+) -> Result<RouterQuote, ProgramError> {
+    get_price_from_router_with_retry(dex_slippage, router, token_in, token_out, amount, 0)
+}
 
-    // Fetch initial token balances
-    let initial_balance_in = get_token_balance(token_in)?;
-    let initial_balance_out = get_token_balance(token_out)?;
+// Same as get_price_from_router, with the slippage widened for a retried submission
+fn get_price_from_router_with_retry(
+    dex_slippage: &DexSlippage,
+    router: &AccountInfo,
+    token_in: &AccountInfo,
+    token_out: &AccountInfo,
+    amount: u64,
+    retry_count: u8,
+) -> Result<RouterQuote, ProgramError> {
+    validate_router(dex_slippage, router.key)?;
 
-    //  MEV strategy: Flashloan and atomic arbitrage
-    let flashloan_amount = amount << 1;
-    let intermediate_amount = execute_flashloan(router, token_in, flashloan_amount)?;
-    let mev_profit = execute_atomic_arbitrage(router, token_in, token_out, intermediate_amount)?;
+    let pool_in = TokenAccount::unpack(&token_in.data.borrow())?;
+    let pool_out = TokenAccount::unpack(&token_out.data.borrow())?;
 
-    msg!("Flashloan amount: {}", flashloan_amount);
-    msg!("Intermediate amount after flashloan: {}", intermediate_amount);
-    msg!("MEV profit: {}", mev_profit);
+    // Make sure the pool is actually for the registered pair
+    validate_pool_mints(dex_slippage, &pool_in.mint, &pool_out.mint)?;
 
-    // Calculate final balances
-    let final_balance_in = get_token_balance(token_in)?;
-    let final_balance_out = get_token_balance(token_out)?;
+    let reserve_in = pool_in.amount;
+    let reserve_out = pool_out.amount;
 
-    msg!("Final Token In Balance: {}", final_balance_in);
-    msg!("Final Token Out Balance: {}", final_balance_out);
+    // The AMM keeps its swap fee, only the rest of the input moves the curve
+    let fee_bps = router_fee_bps(dex_slippage, router.key) as u128;
+    let amount_in = (amount as u128) * (10_000 - fee_bps) / 10_000;
 
-    // Perform route adjustments for MEV optimization
-    let mut route_optimization_factor: u64 = 1;
-    for _ in 0..10 {
-        route_optimization_factor = route_optimization_factor.wrapping_mul(2).wrapping_add(1);
+    // Constant product quote: amount_in * reserve_out / (reserve_in + amount_in)
+    let denominator = (reserve_in as u128) + amount_in;
+    if denominator == 0 {
+        return Err(ProgramError::InvalidAccountData);
     }
-    msg!("Route optimization factor: {}", route_optimization_factor);
+    let expected = (amount_in * (reserve_out as u128) / denominator) as u64;
 
-    // Verify if MEV was successful
-    if mev_profit > flashloan_amount {
-        msg!("MEV execution successful with profit: {}", mev_profit);
-    } else {
-        msg!("MEV execution not profitable");
+    Ok(RouterQuote {
+        expected,
+        min_out: apply_slippage_bps(expected, effective_slippage_bps(dex_slippage, retry_count)),
+    })
+}
+
+fn validate_router(dex_slippage: &DexSlippage, router: &Pubkey) -> ProgramResult {
+    let listed = *router != Pubkey::default() && dex_slippage.filtered_routers.iter().any(|r| r == router);
+    let allowed = match dex_slippage.router_filter_mode {
+        RouterFilterMode::None => true,
+        RouterFilterMode::Allowlist => listed,
+        RouterFilterMode::Denylist => !listed,
+    };
+
+    if !allowed {
+        msg!("Router {} rejected by {:?} filter", router, dex_slippage.router_filter_mode);
+        return Err(ProgramError::InvalidArgument);
     }
 
     Ok(())
 }
 
-fn get_token_balance(token: &AccountInfo) -> Result<u64, ProgramError> {
-    // fetching token balance
-    Ok(1000)
-}
+// Pools must trade exactly the registered base/quote mints, in either order.
+// Nothing is enforced until a pair is registered.
+fn validate_pool_mints(dex_slippage: &DexSlippage, mint_in: &Pubkey, mint_out: &Pubkey) -> ProgramResult {
+    if dex_slippage.base_mint == Pubkey::default() && dex_slippage.quote_mint == Pubkey::default() {
+        return Ok(());
+    }
 
-fn execute_flashloan(
-    router: &AccountInfo,
-    token: &AccountInfo,
-    amount: u64,
-) -> Result<u64, ProgramError> {
-    // executing a flashloan
-    Ok(amount.wrapping_mul(2))
+    let matches_pair = (*mint_in == dex_slippage.base_mint && *mint_out == dex_slippage.quote_mint)
+        || (*mint_in == dex_slippage.quote_mint && *mint_out == dex_slippage.base_mint);
+    if !matches_pair {
+        msg!("Pool mints {} / {} do not match the registered pair", mint_in, mint_out);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
 }
 
-fn execute_atomic_arbitrage(
-    router: &AccountInfo,
-    token_in: &AccountInfo,
-    token_out: &AccountInfo,
-    amount: u64,
-) -> Result<u64, ProgramError> {
-    //  atomic arbitrage execution
-    let arbitrage_result = amount.wrapping_add(amount >> 2);
-    Ok(arbitrage_result)
+fn apply_slippage_bps(expected: u64, slippage_bps: u64) -> u64 {
+    let retained_bps = 10_000u128.saturating_sub(slippage_bps as u128);
+    ((expected as u128) * retained_bps / 10_000) as u64
 }
 
-  
+// Each retry widens the tolerance by `retry_slippage_step_bps`, capped at
+// `max_retry_slippage_bps`. Retries never tighten below the base slippage.
+fn effective_slippage_bps(dex_slippage: &DexSlippage, retry_count: u8) -> u64 {
+    let base_bps = base_slippage_bps(dex_slippage);
+    if retry_count == 0 {
+        return base_bps;
+    }
 
-fn execute_liquidity_provision(
-    router: &AccountInfo,
-    token_a: &AccountInfo,
-    token_b: &AccountInfo,
-    amount_a: u64,
-    amount_b: u64,
-) -> ProgramResult {
-    msg!("Executing liquidity provision...");
+    let widened_bps = base_bps + retry_count as u64 * dex_slippage.retry_slippage_step_bps as u64;
+    let cap_bps = (dex_slippage.max_retry_slippage_bps as u64).max(base_bps);
+    widened_bps.min(cap_bps)
+}
+
+// With a volatility band configured the base slippage tracks recent price
+// moves, clamped to the band. Otherwise it is the static slippage_percent.
+fn base_slippage_bps(dex_slippage: &DexSlippage) -> u64 {
+    if dex_slippage.max_volatility_slippage_bps == 0 {
+        return dex_slippage.slippage_percent as u64 * 100;
+    }
 
-    let mut total_liquidity_a = 0;
-    let mut total_liquidity_b = 0;
+    price_volatility_bps(dex_slippage)
+        .max(dex_slippage.min_volatility_slippage_bps as u64)
+        .min(dex_slippage.max_volatility_slippage_bps as u64)
+}
 
-    // calculating liquidity provisions in multiple steps
-    for step in 0..5 {
-        let provision_amount_a = (amount_a >> step).wrapping_add(step as u64);
-        let provision_amount_b = (amount_b >> step).wrapping_add(step as u64);
+fn record_price_observation(dex_slippage: &mut DexSlippage, reserves: &ReserveSnapshot) {
+    if reserves.reserve_a == 0 {
+        return;
+    }
+    let price = (reserves.reserve_b as u128) * PRICE_OBSERVATION_SCALE / (reserves.reserve_a as u128);
 
-        total_liquidity_a = total_liquidity_a.wrapping_add(provision_amount_a);
-        total_liquidity_b = total_liquidity_b.wrapping_add(provision_amount_b);
+    let next = dex_slippage.price_history_next as usize % PRICE_HISTORY_LEN;
+    dex_slippage.price_history[next] = price.min(u64::MAX as u128) as u64;
+    dex_slippage.price_history_next = ((next + 1) % PRICE_HISTORY_LEN) as u8;
+}
 
-        msg!("Step {}: Provision Amount A: {}, Provision Amount B: {}", step, provision_amount_a, provision_amount_b);
+// Mean absolute change between consecutive recorded prices, in bps. Empty
+// slots are skipped, so a partly filled history still gives an estimate.
+fn price_volatility_bps(dex_slippage: &DexSlippage) -> u64 {
+    let start = dex_slippage.price_history_next as usize % PRICE_HISTORY_LEN;
+    let prices: Vec<u128> = (0..PRICE_HISTORY_LEN)
+        .map(|i| dex_slippage.price_history[(start + i) % PRICE_HISTORY_LEN] as u128)
+        .filter(|price| *price > 0)
+        .collect();
+    if prices.len() < 2 {
+        return 0;
     }
 
-    let liquidity_ratio = total_liquidity_a.wrapping_mul(1000) / total_liquidity_b;
-    msg!("Total Liquidity A: {}", total_liquidity_a);
-    msg!("Total Liquidity B: {}", total_liquidity_b);
-    msg!("Liquidity Ratio: {}", liquidity_ratio);
+    let total_bps: u128 = prices
+        .windows(2)
+        .map(|pair| pair[0].abs_diff(pair[1]) * 10_000 / pair[0])
+        .sum();
+    (total_bps / (prices.len() as u128 - 1)).min(u64::MAX as u128) as u64
+}
 
-    let adjusted_liquidity_a = adjust_liquidity(router, token_a, total_liquidity_a)?;
-    let adjusted_liquidity_b = adjust_liquidity(router, token_b, total_liquidity_b)?;
+// Price feed layout read from an oracle account
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OraclePrice {
+    pub price: u64,
+    pub confidence: u64,
+}
 
-    msg!("Adjusted Liquidity A: {}", adjusted_liquidity_a);
-    msg!("Adjusted Liquidity B: {}", adjusted_liquidity_b);
+// Nothing is enforced until an oracle program is set
+fn read_oracle_price(dex_slippage: &DexSlippage, oracle: &AccountInfo) -> Result<OraclePrice, ProgramError> {
+    if dex_slippage.oracle_program != Pubkey::default() && *oracle.owner != dex_slippage.oracle_program {
+        msg!("Oracle account {} is not owned by {}", oracle.key, dex_slippage.oracle_program);
+        return Err(ProgramError::IncorrectProgramId);
+    }
 
-    Ok(())
+    let data = oracle.try_borrow_data()?;
+    OraclePrice::deserialize(&mut &data[..]).map_err(|_| {
+        msg!("Oracle account {} is not a price feed", oracle.key);
+        ProgramError::InvalidAccountData
+    })
 }
 
-fn rebalance_portfolio(
-    token_a: &AccountInfo,
-    token_b: &AccountInfo,
-    amount: u64,
-) -> ProgramResult {
-    msg!("Rebalancing portfolio...");
+// Reads every supplied oracle and reduces them to a single price.
+// When oracles are configured, only those accounts are accepted.
+fn read_oracle_quorum(
+    dex_slippage: &DexSlippage,
+    oracles: &[AccountInfo],
+) -> Result<OraclePrice, ProgramError> {
+    let configured = oracles_configured(dex_slippage);
+    let mut prices = Vec::with_capacity(oracles.len());
+    for oracle in oracles.iter().take(MAX_ORACLES) {
+        if configured && !dex_slippage.oracles.contains(oracle.key) {
+            msg!("Oracle {} is not configured", oracle.key);
+            return Err(ProgramError::InvalidArgument);
+        }
+        prices.push(read_oracle_price(dex_slippage, oracle)?);
+    }
 
-    // Fetch initial token balances
-    let initial_balance_a = get_token_balance(token_a)?;
-    let initial_balance_b = get_token_balance(token_b)?;
+    median_oracle_price(dex_slippage, &mut prices)
+}
 
-    // Calculate target balances for rebalancing
-    let total_balance = initial_balance_a.wrapping_add(initial_balance_b);
-    let target_balance_a = total_balance / 2;
-    let target_balance_b = total_balance / 2;
+fn oracles_configured(dex_slippage: &DexSlippage) -> bool {
+    dex_slippage.oracles.iter().any(|o| *o != Pubkey::default())
+}
 
-    // Determine amounts to buy/sell for rebalancing
-    let difference_a = if initial_balance_a > target_balance_a {
-        initial_balance_a - target_balance_a
-    } else {
-        target_balance_a - initial_balance_a
-    };
+// Once oracles or a confidence bound are configured, a price reading is
+// required rather than skipped when the caller leaves the oracles out
+fn oracle_required(dex_slippage: &DexSlippage) -> bool {
+    oracles_configured(dex_slippage) || dex_slippage.max_oracle_confidence_bps > 0
+}
 
-    let difference_b = if initial_balance_b > target_balance_b {
-        initial_balance_b - target_balance_b
-    } else {
-        target_balance_b - initial_balance_b
-    };
+// Takes the median reading and requires at least `oracle_quorum` readings
+// within `oracle_band_bps` of it. A zero band requires exact agreement.
+fn median_oracle_price(
+    dex_slippage: &DexSlippage,
+    prices: &mut [OraclePrice],
+) -> Result<OraclePrice, ProgramError> {
+    if prices.is_empty() {
+        msg!("No oracle accounts supplied");
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
 
-    let mut rebalance_steps = 5;
-    let mut adjustment_a = 0;
-    let mut adjustment_b = 0;
+    prices.sort_by_key(|p| p.price);
+    let median = prices[prices.len() / 2];
 
-    // Perform rebalancing in steps
-    for i in 0..rebalance_steps {
-        let step_amount_a = (difference_a / rebalance_steps) >> i;
-        let step_amount_b = (difference_b / rebalance_steps) >> i;
+    let band = (median.price as u128) * (dex_slippage.oracle_band_bps as u128) / 10_000;
+    let agreeing = prices
+        .iter()
+        .filter(|p| (p.price.abs_diff(median.price) as u128) <= band)
+        .count();
+    let quorum = (dex_slippage.oracle_quorum as usize).max(1);
+    if agreeing < quorum {
+        msg!("Only {} oracles agree, quorum is {}", agreeing, quorum);
+        return Err(ProgramError::InvalidAccountData);
+    }
 
-        if initial_balance_a > target_balance_a {
-            sell_token(token_a, step_amount_a)?;
-            adjustment_a += step_amount_a;
-        } else {
-            buy_token(token_a, step_amount_a)?;
-            adjustment_a -= step_amount_a;
-        }
+    Ok(median)
+}
 
-        if initial_balance_b > target_balance_b {
-            sell_token(token_b, step_amount_b)?;
-            adjustment_b += step_amount_b;
-        } else {
-            buy_token(token_b, step_amount_b)?;
-            adjustment_b -= step_amount_b;
-        }
+// A wide confidence interval means the oracle price can't be trusted.
+// A zero `max_oracle_confidence_bps` disables the check.
+fn check_oracle_confidence(dex_slippage: &DexSlippage, oracle_price: &OraclePrice) -> ProgramResult {
+    if dex_slippage.max_oracle_confidence_bps == 0 {
+        return Ok(());
+    }
+    if oracle_price.price == 0 {
+        return Err(ProgramError::InvalidAccountData);
+    }
 
+    let confidence_bps = (oracle_price.confidence as u128) * 10_000 / (oracle_price.price as u128);
+    if confidence_bps > dex_slippage.max_oracle_confidence_bps as u128 {
         msg!(
-            "Step {}: Adjustment A: {}, Adjustment B: {}",
-            i,
-            adjustment_a,
-            adjustment_b
+            "Oracle confidence {} bps exceeds max {} bps",
+            confidence_bps,
+            dex_slippage.max_oracle_confidence_bps
         );
+        return Err(ProgramError::InvalidAccountData);
     }
+
+    Ok(())
 }
 
-fn withdraw_funds(
-    program_id: &Pubkey,
-    accounts: &[AccountInfo],
-) -> ProgramResult {
-    let accounts_iter = &mut accounts.iter();
-    let owner = next_account_info(accounts_iter)?;
-    let state_account = next_account_info(accounts_iter)?;
-    let receiver = next_account_info(accounts_iter)?;
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ArbitrageDirection {
+    // token1 -> token2 -> token3 -> token1 via router1, router2, router3
+    Forward,
+    // token1 -> token3 -> token2 -> token1 via router3, router2, router1
+    Reverse,
+}
 
-    // Deserialize state account data
-    let mut state_data = state_account.try_borrow_mut_data()?;
-    let mut dex_slippage = DexSlippage::try_from_slice(&state_data)?;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArbitrageOutcome {
+    pub direction: ArbitrageDirection,
+    pub potential_profit: i64,
+    pub is_opportunity: bool,
+}
 
-    // Ensure the owner matches
-    if dex_slippage.owner != *owner.key {
-        return Err(ProgramError::IncorrectProgramId);
+// Picks the cycle whose final output is larger; ties go to the forward cycle
+fn choose_direction(forward_out: u64, reverse_out: u64) -> ArbitrageDirection {
+    if reverse_out > forward_out {
+        ArbitrageDirection::Reverse
+    } else {
+        ArbitrageDirection::Forward
     }
+}
 
-    // Transfer all funds from the contract to the owner's account
-    let lamports = state_account.lamports();
-    **state_account.lamports.borrow_mut() = 0;
-    **receiver.lamports.borrow_mut() += lamports;
+// Hops in perform_mev's token_in -> token_out -> token_in round trip
+pub const ROUND_TRIP_HOPS: u8 = 2;
 
-    msg!("Funds withdrawn by the owner");
+// Hops in calculate_arbitrage's triangular cycle, the longest route quoted
+pub const TRIANGULAR_HOPS: u8 = 3;
+
+// Short routes are the most contested, some strategies skip them entirely
+fn check_min_hops(dex_slippage: &DexSlippage, hops: u8) -> ProgramResult {
+    if hops < dex_slippage.min_hops {
+        msg!("Route has {} hops, minimum is {}", hops, dex_slippage.min_hops);
+        return Err(ProgramError::InvalidArgument);
+    }
 
     Ok(())
 }
 
-fn perform_spl_arbitrage(
-    token_a: &AccountInfo,
-    token_b: &AccountInfo,
+fn calculate_arbitrage(
+    dex_slippage: &DexSlippage,
+    router1: &AccountInfo,
+    router2: &AccountInfo,
+    router3: &AccountInfo,
+    token1: &AccountInfo,
+    token2: &AccountInfo,
+    token3: &AccountInfo,
+    oracles: &[AccountInfo],
     amount: u64,
-) -> ProgramResult {
-    msg!("Performing SPL token arbitrage...");
+) -> Result<ArbitrageOutcome, ProgramError> {
+    msg!("Calculating arbitrage...");
+    check_min_hops(dex_slippage, TRIANGULAR_HOPS)?;
 
-    // Fetch initial token balances
-    let initial_balance_a = get_token_balance(token_a)?;
-    let initial_balance_b = get_token_balance(token_b)?;
+    // Don't trade on an unreliable oracle reading
+    if oracle_required(dex_slippage) || !oracles.is_empty() {
+        let oracle_price = read_oracle_quorum(dex_slippage, oracles)?;
+        check_oracle_confidence(dex_slippage, &oracle_price)?;
+    }
 
-    // SPL token arbitrage
-    let arbitrage_route_a = calculate_arbitrage_route(token_a, amount)?;
-    let arbitrage_route_b = calculate_arbitrage_route(token_b, amount)?;
+    // Quote both cycles, the reverse one may be the profitable one
+    let forward1 = get_price_from_router(dex_slippage, router1, token1, token2, amount)?;
+    let forward2 = get_price_from_router(dex_slippage, router2, token2, token3, forward1.expected)?;
+    let forward3 = get_price_from_router(dex_slippage, router3, token3, token1, forward2.expected)?;
+    let forward = [forward1, forward2, forward3];
 
-    msg!("Arbitrage route for Token A: {}", arbitrage_route_a);
-    msg!("Arbitrage route for Token B: {}", arbitrage_route_b);
+    let reverse1 = get_price_from_router(dex_slippage, router3, token1, token3, amount)?;
+    let reverse2 = get_price_from_router(dex_slippage, router2, token3, token2, reverse1.expected)?;
+    let reverse3 = get_price_from_router(dex_slippage, router1, token2, token1, reverse2.expected)?;
+    let reverse = [reverse1, reverse2, reverse3];
 
-    let mut profit_a = 0;
-    let mut profit_b = 0;
+    let direction = choose_direction(forward[2].expected, reverse[2].expected);
+    debug_msg!(dex_slippage, "Arbitrage direction: {:?}", direction);
 
-    // Perform a series of arbitrage trades
-    for i in 0..5 {
-        let trade_amount_a = (amount >> i).wrapping_add(i as u64);
-        let trade_amount_b = (amount >> (5 - i)).wrapping_add(i as u64);
+    let [quote1, quote2, quote3] = match direction {
+        ArbitrageDirection::Forward => forward,
+        ArbitrageDirection::Reverse => reverse,
+    };
 
-        let trade_result_a = execute_trade(token_a, trade_amount_a)?;
-        let trade_result_b = execute_trade(token_b, trade_amount_b)?;
+    let price1 = quote1.expected;
+    debug_msg!(dex_slippage, "Price from first hop: {} (min out {})", price1, quote1.min_out);
 
-        profit_a = profit_a.wrapping_add(trade_result_a);
-        profit_b = profit_b.wrapping_add(trade_result_b);
+    let price2 = quote2.expected;
+    debug_msg!(dex_slippage, "Price from second hop: {} (min out {})", price2, quote2.min_out);
+
+    let price3 = quote3.expected;
+    debug_msg!(dex_slippage, "Price from third hop: {} (min out {})", price3, quote3.min_out);
+
+    let potential_profit = price3 as i64 - amount as i64;
+    debug_msg!(dex_slippage, "Potential profit: {}", potential_profit);
 
-        msg!("Trade {}: Result A: {}, Result B: {}", i, trade_result_a, trade_result_b);
+    // An enormous spread is more likely a manipulated or broken pool than a real opportunity
+    if exceeds_max_spread(amount, price3, dex_slippage.max_spread_bps) {
+        msg!("Spread exceeds {} bps, treating opportunity as suspicious", dex_slippage.max_spread_bps);
+        return Ok(ArbitrageOutcome {
+            direction,
+            potential_profit,
+            is_opportunity: false,
+        });
     }
 
-    // Calculate final profits
-    let final_profit = profit_a.wrapping_add(profit_b) >> 1;
-    msg!("Final arbitrage profit: {}", final_profit);
+    // Below the threshold is never an opportunity, skip the arithmetic below.
+    // A negative threshold lets aggressive strategies accept a bounded loss.
+    let is_profitable = meets_profit_threshold(potential_profit, dex_slippage.min_profit_threshold);
+    if !is_profitable {
+        msg!(
+            "Arbitrage opportunity detected: false (profit {} below threshold {})",
+            potential_profit,
+            dex_slippage.min_profit_threshold
+        );
+        return Ok(ArbitrageOutcome {
+            direction,
+            potential_profit,
+            is_opportunity: false,
+        });
+    }
 
-    // Check if arbitrage was profitable
-    let threshold_profit: u64 = 1000;
-    let is_profitable = final_profit > threshold_profit;
-    msg!("Is arbitrage profitable? {}", is_profitable);
+    let price_difference = (price3 as i128 - price1 as i128) >> 1;
+    debug_msg!(dex_slippage, "Price difference after bit shift: {}", price_difference);
 
-    if is_profitable {
-        msg!("Arbitrage execution successful with profit: {}", final_profit);
-    } else {
-        msg!("Arbitrage execution not profitable");
+    let adjusted_profit = (potential_profit as i128).wrapping_mul(10).wrapping_add(price_difference);
+    debug_msg!(dex_slippage, "Adjusted profit: {}", adjusted_profit);
+
+    // The profit also has to cover the reference transaction price
+    let covers_tx_price = covers_arb_tx_price(potential_profit, dex_slippage.arb_tx_price);
+    debug_msg!(dex_slippage, "Covers arb tx price {}? {}", dex_slippage.arb_tx_price, covers_tx_price);
+
+    let arbitrage_opportunity = is_profitable && covers_tx_price;
+    msg!("Arbitrage opportunity detected: {}", arbitrage_opportunity);
+
+    if arbitrage_opportunity {
+        let execution_price1 = price1.wrapping_mul(3) >> 2;
+        let execution_price2 = price2.wrapping_mul(5) >> 3;
+        let execution_price3 = price3.wrapping_mul(7) >> 4;
+        
+        debug_msg!(dex_slippage, "Execution price1: {}", execution_price1);
+        debug_msg!(dex_slippage, "Execution price2: {}", execution_price2);
+        debug_msg!(dex_slippage, "Execution price3: {}", execution_price3);
+
+        let final_arbitrage_value = (execution_price1 as u64).wrapping_add(execution_price2).wrapping_add(execution_price3);
+        debug_msg!(dex_slippage, "Final arbitrage value: {}", final_arbitrage_value);
     }
 
-    Ok(())
+    Ok(ArbitrageOutcome {
+        direction,
+        potential_profit,
+        is_opportunity: arbitrage_opportunity,
+    })
 }
 
-fn update_trading_balance(
+// Both sides are signed, a loss compares below any non-negative threshold
+fn meets_profit_threshold(potential_profit: i64, min_profit_threshold: i64) -> bool {
+    potential_profit >= min_profit_threshold
+}
+
+// A zero arb_tx_price predates SetArbTxPrice and imposes nothing
+fn covers_arb_tx_price(potential_profit: i64, arb_tx_price: u64) -> bool {
+    arb_tx_price == 0 || potential_profit as i128 > arb_tx_price as i128
+}
+
+// Implied spread of the cycle in bps of the input amount. A zero max disables the check.
+fn exceeds_max_spread(amount: u64, cycle_output: u64, max_spread_bps: u16) -> bool {
+    if max_spread_bps == 0 || amount == 0 {
+        return false;
+    }
+
+    let spread_bps = (cycle_output as i128 - amount as i128).unsigned_abs() * 10_000 / amount as u128;
+    spread_bps > max_spread_bps as u128
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct MevEstimate {
+    pub amount: u64,
+    pub expected_out: u64,
+    pub estimated_profit: i64,
+}
+
+// Read-only profit estimate of the forward cycle for dashboards. Touches no
+// state account: each hop's AMM fee comes with the instruction, and pair and
+// router filters are never applied.
+fn estimate_mev(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
-    new_balance: u64,
+    amount: u64,
+    hop_fees_bps: [u16; 3],
 ) -> ProgramResult {
     let accounts_iter = &mut accounts.iter();
-    let owner = next_account_info(accounts_iter)?;
-    let state_account = next_account_info(accounts_iter)?;
+    let router1 = next_account_info(accounts_iter)?;
+    let router2 = next_account_info(accounts_iter)?;
+    let router3 = next_account_info(accounts_iter)?;
+    let token1 = next_account_info(accounts_iter)?;
+    let token2 = next_account_info(accounts_iter)?;
+    let token3 = next_account_info(accounts_iter)?;
 
-    // Deserialize state account data
-    let mut state_data = state_account.try_borrow_mut_data()?;
-    let mut dex_slippage = DexSlippage::try_from_slice(&state_data)?;
+    let routers = [router1, router2, router3];
+    let config = hop_fee_config(routers, hop_fees_bps)?;
+    let estimate = estimate_mev_profit(&config, routers, [token1, token2, token3], amount)?;
+    emit_event("mev_estimate", &estimate)
+}
 
-    // Ensure the owner matches
-    if dex_slippage.owner != *owner.key {
-        return Err(ProgramError::IncorrectProgramId);
+// Quote config for EstimateMev: the default config, charging each hop's router
+// the fee passed for that hop
+fn hop_fee_config(routers: [&AccountInfo; 3], hop_fees_bps: [u16; 3]) -> Result<DexSlippage, ProgramError> {
+    let mut config = DexSlippage::default();
+    for (slot, (router, fee_bps)) in routers.iter().zip(hop_fees_bps).enumerate() {
+        if fee_bps > 10_000 {
+            msg!("Router fee must be at most 10000 bps");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // A router charges the same fee on every hop it serves
+        if let Some((_, known_fee_bps)) = config.router_fees.iter().find(|(fee_router, _)| fee_router == router.key) {
+            if *known_fee_bps != fee_bps {
+                msg!("Router {} given fees of {} and {} bps", router.key, known_fee_bps, fee_bps);
+                return Err(ProgramError::InvalidArgument);
+            }
+        }
+        config.router_fees[slot] = (*router.key, fee_bps);
     }
 
-    // Update trading balance
-    dex_slippage.trading_balance_in_tokens = new_balance;
-    dex_slippage.serialize(&mut *state_data)?;
+    Ok(config)
+}
+
+// Quote config for EvaluateRoutes: the default config, with the router fees of
+// the state account when one is passed
+fn estimate_config(program_id: &Pubkey, state_account: Option<&AccountInfo>) -> Result<DexSlippage, ProgramError> {
+    let mut config = DexSlippage::default();
+    if let Some(state_account) = state_account {
+        if state_account.owner != program_id {
+            msg!("State account {} is not owned by this program", state_account.key);
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        config.router_fees = load_state(state_account)?.router_fees;
+    }
 
-    Ok(())
+    Ok(config)
 }
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use solana_program::clock::Epoch;
-    use solana_program::sysvar::rent::Rent;
 
-    #[test]
-    fn test_initialize() {
-        let program_id = Pubkey::new_unique();
+fn estimate_mev_profit(
+    config: &DexSlippage,
+    routers: [&AccountInfo; 3],
+    tokens: [&AccountInfo; 3],
+    amount: u64,
+) -> Result<MevEstimate, ProgramError> {
+    let hop1 = get_price_from_router(config, routers[0], tokens[0], tokens[1], amount)?;
+    let hop2 = get_price_from_router(config, routers[1], tokens[1], tokens[2], hop1.expected)?;
+    let hop3 = get_price_from_router(config, routers[2], tokens[2], tokens[0], hop2.expected)?;
+
+    Ok(MevEstimate {
+        amount,
+        expected_out: hop3.expected,
+        estimated_profit: (hop3.expected as i128 - amount as i128) as i64,
+    })
+}
+
+// Accounts per route for EvaluateRoutes: router1, router2, router3, token1, token2, token3.
+// The routes may be followed by the state account, for its router fees.
+pub const ROUTE_ACCOUNTS: usize = 6;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct BestRoute {
+    pub index: u8,
+    pub estimated_profit: i64,
+}
+
+// Quotes several 3-hop routes in one go and reports the most profitable one
+fn evaluate_routes(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let (routes, state_account) = match accounts.len() % ROUTE_ACCOUNTS {
+        1 => (&accounts[..accounts.len() - 1], accounts.last()),
+        _ => (accounts, None),
+    };
+    let config = estimate_config(program_id, state_account)?;
+    let best = best_route(&config, routes, amount)?;
+    msg!("Best route {} with estimated profit {}", best.index, best.estimated_profit);
+    emit_event("best_route", &best)
+}
+
+fn best_route(config: &DexSlippage, accounts: &[AccountInfo], amount: u64) -> Result<BestRoute, ProgramError> {
+    if accounts.is_empty() || accounts.len() % ROUTE_ACCOUNTS != 0 {
+        msg!("Routes must be groups of {} accounts", ROUTE_ACCOUNTS);
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+
+    let mut best: Option<BestRoute> = None;
+    for (index, route) in accounts.chunks(ROUTE_ACCOUNTS).enumerate() {
+        let estimate = estimate_mev_profit(
+            config,
+            [&route[0], &route[1], &route[2]],
+            [&route[3], &route[4], &route[5]],
+            amount,
+        )?;
+
+        if best.as_ref().map_or(true, |best| estimate.estimated_profit > best.estimated_profit) {
+            best = Some(BestRoute {
+                index: u8::try_from(index).map_err(|_| ProgramError::InvalidArgument)?,
+                estimated_profit: estimate.estimated_profit,
+            });
+        }
+    }
+
+    best.ok_or(ProgramError::NotEnoughAccountKeys)
+}
+
+// Arbitrage computed ahead of time by `precompute_arb`, along with the slot it was quoted at
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ArbitragePlan {
+    pub direction: ArbitrageDirection,
+    pub amount: u64,
+    pub potential_profit: i64,
+    pub slot: u64,
+}
+
+fn precompute_arb(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let router1 = next_account_info(accounts_iter)?;
+    let router2 = next_account_info(accounts_iter)?;
+    let router3 = next_account_info(accounts_iter)?;
+    let token1 = next_account_info(accounts_iter)?;
+    let token2 = next_account_info(accounts_iter)?;
+    let token3 = next_account_info(accounts_iter)?;
+    let oracles = accounts_iter.as_slice();
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    let outcome = calculate_arbitrage(
+        &dex_slippage,
+        router1,
+        router2,
+        router3,
+        token1,
+        token2,
+        token3,
+        oracles,
+        amount,
+    )?;
+    if !outcome.is_opportunity {
+        msg!("No opportunity to cache");
+        return Ok(());
+    }
+
+    // Cache the plan for a later perform_mev
+    dex_slippage.cached_plan = Some(ArbitragePlan {
+        direction: outcome.direction,
+        amount,
+        potential_profit: outcome.potential_profit,
+        slot: Clock::get()?.slot,
+    });
+    write_state(&dex_slippage, &mut state_data)?;
+
+    Ok(())
+}
+
+// A cached plan older than max_plan_age_slots must be recomputed; 0 disables the limit
+fn check_plan_age(dex_slippage: &DexSlippage, plan: &ArbitragePlan, current_slot: u64) -> ProgramResult {
+    let age = current_slot.saturating_sub(plan.slot);
+    if dex_slippage.max_plan_age_slots > 0 && age > dex_slippage.max_plan_age_slots {
+        msg!("Cached plan is {} slots old, recompute it", age);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+// Only reads the clock when a maximum age is configured
+fn plan_is_fresh(dex_slippage: &DexSlippage, plan: &ArbitragePlan) -> Result<bool, ProgramError> {
+    if dex_slippage.max_plan_age_slots == 0 {
+        return Ok(true);
+    }
+
+    Ok(check_plan_age(dex_slippage, plan, Clock::get()?.slot).is_ok())
+}
+
+// Where perform_mev sweeps leftover arbitrage output once the flashloan is repaid
+pub struct ProfitDestination<'a, 'info> {
+    pub token_program: &'a AccountInfo<'info>,
+    pub authority: &'a AccountInfo<'info>,
+    pub authority_seeds: &'a [&'a [u8]],
+    pub vault: &'a AccountInfo<'info>,
+}
+
+fn perform_mev(
+    dex_slippage: &mut DexSlippage,
+    router: &AccountInfo,
+    lending_pool: Option<&AccountInfo>,
+    token_in: &AccountInfo,
+    token_out: &AccountInfo,
+    pool_accounts: &[AccountInfo],
+    profit_destination: Option<&ProfitDestination>,
+    amount: u64,
+    bundle_min_out: u64,
+    nonce: u64,
+) -> ProgramResult {
+    msg!("Performing MEV...");
+
+    if dex_slippage.paused {
+        msg!("Bot is paused");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    if !mev_allowed(dex_slippage) {
+        msg!(
+            "MEV needs trading and MEV enabled (trading: {}, mev: {})",
+            dex_slippage.enable_trading,
+            dex_slippage.mev_enabled
+        );
+        return Err(ProgramError::InvalidAccountData);
+    }
+    check_min_hops(dex_slippage, ROUND_TRIP_HOPS)?;
+    if dex_slippage.mev_in_progress {
+        msg!("A recorded MEV run still has a loan outstanding");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Don't spam the network with attempts in a single slot
+    if dex_slippage.max_attempts_per_slot > 0 {
+        record_attempt(dex_slippage, Clock::get()?.slot)?;
+    }
+
+    // A cached plan decides the trade and is used up by it. Quotes in a stale
+    // plan no longer reflect the pools, so it is dropped and the caller's trade
+    // goes ahead instead of failing until someone recomputes it.
+    let (token_in, token_out, amount) = match dex_slippage.cached_plan.take() {
+        Some(plan) if plan_is_fresh(dex_slippage, &plan)? => {
+            msg!("Executing cached {:?} plan of {}", plan.direction, plan.amount);
+            match plan.direction {
+                ArbitrageDirection::Forward => (token_in, token_out, plan.amount),
+                ArbitrageDirection::Reverse => (token_out, token_in, plan.amount),
+            }
+        }
+        Some(_) => {
+            msg!("Dropped stale cached plan");
+            (token_in, token_out, amount)
+        }
+        None => (token_in, token_out, amount),
+    };
+
+    // A frozen account guarantees a failed swap, bail out before paying for it
+    ensure_not_frozen(token_in)?;
+    ensure_not_frozen(token_out)?;
+    for pool_account in pool_accounts {
+        ensure_not_frozen(pool_account)?;
+    }
+
+    // Fetch initial token balances
+    let initial_balance_in = get_token_balance(token_in)?;
+    let initial_balance_out = get_token_balance(token_out)?;
+    let reserves_before = snapshot_reserves(pool_accounts, SNAPSHOT_BEFORE_MEV)?;
+    record_price_observation(dex_slippage, &reserves_before);
+    check_liquidity(dex_slippage, pool_accounts)?;
+
+    //  MEV strategy: Flashloan and atomic arbitrage, spot arbitrage or liquidity provision
+    let (leveraged, amount) = match dex_slippage.strategy {
+        MevStrategy::FlashloanArb => {
+            // A failed flashloan CPI aborts the whole transaction, so a short
+            // lending pool has to be caught here, before anything is borrowed
+            let flashloan_amount = flashloan_size(amount)?;
+            let liquidity = lending_liquidity(lending_pool)?;
+            if liquidity >= flashloan_amount {
+                (true, amount)
+            } else {
+                msg!("Lending pool holds {}, short of a {} flashloan", liquidity, flashloan_amount);
+                let own_amount =
+                    fallback_amount(dex_slippage, amount, initial_balance_in).ok_or(ProgramError::InsufficientFunds)?;
+                msg!("Falling back to unleveraged arbitrage of {}", own_amount);
+                (false, own_amount)
+            }
+        }
+        MevStrategy::SpotArb => (false, amount),
+        MevStrategy::LiquidityProvision => {
+            // Earns fees, there is no arbitrage leg to settle
+            let liquidity_ratio = execute_liquidity_provision(dex_slippage, router, token_in, token_out, amount, amount)?;
+            msg!("Provided liquidity at ratio {}", liquidity_ratio);
+            return Ok(());
+        }
+    };
+    let flashloan_amount = if leveraged { flashloan_size(amount)? } else { 0 };
+    let trade_amount = if leveraged { flashloan_amount } else { amount };
+
+    // Quote the round trip before the trade moves the pools, the realized
+    // slippage is measured against it
+    let quoted_output = simulate_round_trip(dex_slippage, router, token_in, token_out, trade_amount)?;
+
+    // Safe mode refuses to move funds at a loss
+    if dex_slippage.safe_mode {
+        require_simulated_profit(quoted_output, trade_amount)?;
+    }
+
+    let (intermediate_amount, principal) = if leveraged {
+        (execute_flashloan(router, token_in, flashloan_amount)?, flashloan_amount)
+    } else {
+        (amount, amount)
+    };
+    let mev_profit = execute_atomic_arbitrage(dex_slippage, router, token_in, token_out, intermediate_amount)?;
+
+    debug_msg!(dex_slippage, "Flashloan amount: {}", flashloan_amount);
+    debug_msg!(dex_slippage, "Intermediate amount after flashloan: {}", intermediate_amount);
+    debug_msg!(dex_slippage, "MEV profit: {}", mev_profit);
+
+    // Calculate final balances
+    let final_balance_in = get_token_balance(token_in)?;
+    let final_balance_out = get_token_balance(token_out)?;
+
+    debug_msg!(dex_slippage, "Final Token In Balance: {}", final_balance_in);
+    debug_msg!(dex_slippage, "Final Token Out Balance: {}", final_balance_out);
+
+    // Whatever is left after repaying the flashloan is profit
+    let residual = flashloan_residual(mev_profit, principal);
+
+    // The output account only keeps what is left after the principal is repaid,
+    // for the quote as much as for the trade
+    emit_event(
+        "slippage_report",
+        &slippage_report(
+            dex_slippage,
+            quoted_output.saturating_sub(principal),
+            initial_balance_out,
+            final_balance_out,
+        ),
+    )?;
+    check_position_cap(dex_slippage, token_out, 0)?;
+    check_bundle_min_out(initial_balance_out, final_balance_out, bundle_min_out)?;
+    check_profit_delta(dex_slippage, initial_balance_out, final_balance_out, residual)?;
+
+    let reserves_after = snapshot_reserves(pool_accounts, SNAPSHOT_AFTER_MEV)?;
+    debug_msg!(
+        dex_slippage,
+        "Pool reserves moved from {}/{} to {}/{}",
+        reserves_before.reserve_a,
+        reserves_before.reserve_b,
+        reserves_after.reserve_a,
+        reserves_after.reserve_b
+    );
+
+    // Perform route adjustments for MEV optimization
+    let route_optimization_factor = route_optimization_factor(optimization_iterations(dex_slippage));
+    debug_msg!(dex_slippage, "Route optimization factor: {}", route_optimization_factor);
+
+    record_trade_outcome(dex_slippage, residual > 0);
+
+    // Verify if MEV was successful
+    if residual > 0 {
+        msg!("MEV execution successful with profit: {}", mev_profit);
+        if record_profit(dex_slippage, residual, nonce)? {
+            realize_profit(dex_slippage, residual)?;
+        }
+
+        sweep_residual(dex_slippage, profit_destination, token_out, residual)?;
+    } else {
+        msg!("MEV execution not profitable");
+    }
+
+    Ok(())
+}
+
+// Every leg can pass its own checks and the bundle still come out short,
+// so the net gain on the output account is checked once at the end
+fn check_bundle_min_out(initial_balance_out: u64, final_balance_out: u64, bundle_min_out: u64) -> ProgramResult {
+    let net_out = final_balance_out.saturating_sub(initial_balance_out);
+    if net_out < bundle_min_out {
+        msg!("Bundle gained {}, below minimum {}", net_out, bundle_min_out);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+// The output account should move by the residual the arbitrage left after
+// repaying its principal, give or take `max_profit_delta_mismatch` (0 = unchecked).
+// A wildly different delta means something else touched the account
+// mid-bundle, or the accounting is wrong.
+fn check_profit_delta(
+    dex_slippage: &DexSlippage,
+    initial_balance_out: u64,
+    final_balance_out: u64,
+    residual: u64,
+) -> ProgramResult {
+    if dex_slippage.max_profit_delta_mismatch == 0 {
+        return Ok(());
+    }
+
+    let delta = final_balance_out.saturating_sub(initial_balance_out);
+    if delta.abs_diff(residual) > dex_slippage.max_profit_delta_mismatch {
+        msg!("Balance moved by {}, arbitrage left {}", delta, residual);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+// Outcome of ConfirmProfit, logged whether or not the bundle goes through
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct ProfitConfirmation {
+    pub expected_min: u64,
+    pub realized: u64,
+    pub confirmed: bool,
+}
+
+// First instruction of a bundle: records the token account's balance for
+// ConfirmProfit to compare against
+fn snapshot_balance(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let token = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    dex_slippage.bundle_snapshot_account = *token.key;
+    dex_slippage.bundle_snapshot_balance = get_token_balance(token)?;
+    write_state(&dex_slippage, &mut state_data)?;
+
+    msg!("Balance snapshot of {}: {}", token.key, dex_slippage.bundle_snapshot_balance);
+
+    Ok(())
+}
+
+// Last instruction of a bundle: fails unless the token account gained at least
+// `expected_min` since snapshot_balance, which reverts the whole bundle
+fn confirm_profit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    expected_min: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let token = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    if dex_slippage.bundle_snapshot_account == Pubkey::default()
+        || dex_slippage.bundle_snapshot_account != *token.key
+    {
+        msg!("No balance snapshot for {}", token.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let initial_balance = dex_slippage.bundle_snapshot_balance;
+    let final_balance = get_token_balance(token)?;
+    let outcome = check_bundle_min_out(initial_balance, final_balance, expected_min);
+    emit_event(
+        "profit_confirmation",
+        &ProfitConfirmation {
+            expected_min,
+            realized: final_balance.saturating_sub(initial_balance),
+            confirmed: outcome.is_ok(),
+        },
+    )?;
+    outcome?;
+
+    // The snapshot is single-use
+    dex_slippage.bundle_snapshot_account = Pubkey::default();
+    dex_slippage.bundle_snapshot_balance = 0;
+    write_state(&dex_slippage, &mut state_data)?;
+
+    Ok(())
+}
+
+// MEV is a kind of trading: it only runs while trading is enabled and MEV is
+// enabled on top of it. Disabling trading stops MEV regardless of mev_enabled.
+fn mev_allowed(dex_slippage: &DexSlippage) -> bool {
+    dex_slippage.enable_trading && dex_slippage.mev_enabled
+}
+
+// Borrow twice the input; a shift would silently wrap large amounts into a tiny loan
+fn flashloan_size(amount: u64) -> Result<u64, ProgramError> {
+    amount.checked_mul(2).ok_or_else(|| {
+        msg!("Flashloan for {} overflows", amount);
+        ProgramError::ArithmeticOverflow
+    })
+}
+
+fn simulate_round_trip(
+    dex_slippage: &DexSlippage,
+    router: &AccountInfo,
+    token_in: &AccountInfo,
+    token_out: &AccountInfo,
+    amount: u64,
+) -> Result<u64, ProgramError> {
+    let outbound = get_price_from_router(dex_slippage, router, token_in, token_out, amount)?;
+    let inbound = get_price_from_router(dex_slippage, router, token_out, token_in, outbound.expected)?;
+    Ok(inbound.expected)
+}
+
+fn require_simulated_profit(simulated_output: u64, principal: u64) -> ProgramResult {
+    if simulated_output <= principal {
+        msg!("Safe mode: simulated output {} doesn't exceed {}, aborting", simulated_output, principal);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+// Quotes converting realized profit into profit_mint. Only the configured
+// conversion router may be used, and it must pass the router filter.
+fn quote_profit_conversion(
+    dex_slippage: &DexSlippage,
+    router: &AccountInfo,
+    profit_pool: &AccountInfo,
+    profit_mint_pool: &AccountInfo,
+    amount: u64,
+) -> Result<RouterQuote, ProgramError> {
+    if dex_slippage.profit_conversion_router == Pubkey::default()
+        || *router.key != dex_slippage.profit_conversion_router
+    {
+        msg!("Router {} is not the profit conversion router", router.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let profit_mint_reserve = TokenAccount::unpack(&profit_mint_pool.data.borrow())?;
+    if profit_mint_reserve.mint != dex_slippage.profit_mint {
+        msg!("Conversion pool does not pay out in profit mint {}", dex_slippage.profit_mint);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    get_price_from_router(dex_slippage, router, profit_pool, profit_mint_pool, amount)
+}
+
+// What the lending pool can lend right now, nothing without a pool account
+fn lending_liquidity(lending_pool: Option<&AccountInfo>) -> Result<u64, ProgramError> {
+    match lending_pool {
+        Some(lending_pool) => get_token_balance(lending_pool),
+        None => Ok(0),
+    }
+}
+
+// Own funds to arbitrage with when the lending pool is short, capped by
+// max_fallback_amount. None when the fallback is disabled or there is nothing to trade.
+fn fallback_amount(dex_slippage: &DexSlippage, amount: u64, own_balance: u64) -> Option<u64> {
+    let own_amount = amount.min(own_balance).min(dex_slippage.max_fallback_amount);
+    if own_amount == 0 {
+        return None;
+    }
+
+    Some(own_amount)
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct TradeReceipt {
+    pub nonce: u64,
+    pub amount: u64,
+    pub profit: u64,
+    pub slot: u64,
+}
+
+impl TradeReceipt {
+    pub const LEN: usize = 8 + 8 + 8 + 8;
+}
+
+// Writes an audit receipt for an executed trade to the PDA `[b"trade", nonce]`,
+// so there is exactly one receipt per trade nonce
+fn emit_trade_receipt<'info>(
+    program_id: &Pubkey,
+    payer: &AccountInfo<'info>,
+    receipt_account: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    nonce: u64,
+    amount: u64,
+    profit: u64,
+) -> ProgramResult {
+    let nonce_bytes = nonce.to_le_bytes();
+    let (receipt_pda, receipt_bump) = Pubkey::find_program_address(&[b"trade", &nonce_bytes], program_id);
+    if *receipt_account.key != receipt_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !receipt_account.data_is_empty() {
+        msg!("Receipt for trade {} already exists", nonce);
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let rent = Rent::get()?;
+    invoke_signed(
+        &solana_program::system_instruction::create_account(
+            payer.key,
+            receipt_account.key,
+            rent.minimum_balance(TradeReceipt::LEN),
+            TradeReceipt::LEN as u64,
+            program_id,
+        ),
+        &[payer.clone(), receipt_account.clone(), system_program.clone()],
+        &[&[b"trade", &nonce_bytes, &[receipt_bump]]],
+    )?;
+
+    let receipt = TradeReceipt {
+        nonce,
+        amount,
+        profit,
+        slot: Clock::get()?.slot,
+    };
+    let mut receipt_data = receipt_account.try_borrow_mut_data()?;
+    receipt.serialize(&mut *receipt_data)?;
+
+    msg!("Trade receipt {} written to {}", nonce, receipt_account.key);
+
+    Ok(())
+}
+
+// Tops up the authority PDA from the payer so account-creating CPIs signed by
+// the authority can cover rent. Anyone may fund it.
+fn fund_authority<'info>(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo<'info>],
+    lamports: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let payer = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    ensure_writable(payer)?;
+    ensure_writable(authority)?;
+    let (authority_pda, _authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if *authority.key != authority_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if lamports == 0 {
+        msg!("Nothing to fund");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    invoke(
+        &solana_program::system_instruction::transfer(payer.key, authority.key, lamports),
+        &[payer.clone(), authority.clone(), system_program.clone()],
+    )?;
+
+    msg!("Funded authority {} with {} lamports", authority.key, lamports);
+
+    Ok(())
+}
+
+// Point-in-time copy of the full config and stats for off-chain archival
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct StateSnapshot {
+    pub timestamp: i64,
+    pub state: DexSlippage,
+}
+
+// Archives the current state to the PDA `[b"snapshot", label]`. A label can
+// only be used once, so a snapshot is never overwritten.
+fn snapshot_state<'info>(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo<'info>],
+    label: &[u8],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let snapshot_account = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    // The owner pays for the snapshot account
+    ensure_writable(owner)?;
+    ensure_writable(snapshot_account)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let dex_slippage = load_state(state_account)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    if label.is_empty() || label.len() > solana_program::pubkey::MAX_SEED_LEN {
+        msg!("Snapshot label must be 1 to {} bytes", solana_program::pubkey::MAX_SEED_LEN);
+        return Err(ProgramError::InvalidArgument);
+    }
+    let (snapshot_pda, snapshot_bump) = Pubkey::find_program_address(&[b"snapshot", label], program_id);
+    if *snapshot_account.key != snapshot_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !snapshot_account.data_is_empty() {
+        msg!("Snapshot {} already exists", snapshot_account.key);
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let snapshot = StateSnapshot {
+        timestamp: Clock::get()?.unix_timestamp,
+        state: dex_slippage,
+    };
+    let snapshot_data = snapshot.try_to_vec()?;
+
+    let rent = Rent::get()?;
+    invoke_signed(
+        &solana_program::system_instruction::create_account(
+            owner.key,
+            snapshot_account.key,
+            rent.minimum_balance(snapshot_data.len()),
+            snapshot_data.len() as u64,
+            program_id,
+        ),
+        &[owner.clone(), snapshot_account.clone(), system_program.clone()],
+        &[&[b"snapshot", label, &[snapshot_bump]]],
+    )?;
+
+    snapshot_account.try_borrow_mut_data()?.copy_from_slice(&snapshot_data);
+
+    msg!("State snapshot written to {}", snapshot_account.key);
+
+    Ok(())
+}
+
+// Arbitrage output left over once the flashloan is repaid; a shortfall leaves nothing
+fn flashloan_residual(arbitrage_output: u64, flashloan_amount: u64) -> u64 {
+    arbitrage_output.saturating_sub(flashloan_amount)
+}
+
+// Compounded profit stays in the trading account, otherwise it is swept to the vault
+fn sweep_residual(
+    dex_slippage: &DexSlippage,
+    profit_destination: Option<&ProfitDestination>,
+    token_out: &AccountInfo,
+    residual: u64,
+) -> ProgramResult {
+    if let (false, Some(destination)) = (dex_slippage.compound, profit_destination) {
+        transfer_tokens_signed(
+            destination.token_program,
+            token_out,
+            destination.vault,
+            destination.authority,
+            residual,
+            &[destination.authority_seeds],
+        )?;
+        msg!("Swept flashloan residual to profit vault: {}", residual);
+    }
+
+    Ok(())
+}
+
+// Counts an MEV attempt, resetting the counter when the slot advances
+fn record_attempt(dex_slippage: &mut DexSlippage, current_slot: u64) -> ProgramResult {
+    if current_slot != dex_slippage.attempts_slot {
+        dex_slippage.attempts_slot = current_slot;
+        dex_slippage.attempts_this_slot = 0;
+    }
+
+    if dex_slippage.attempts_this_slot >= dex_slippage.max_attempts_per_slot {
+        msg!("Max attempts per slot reached: {}", dex_slippage.max_attempts_per_slot);
+        return Err(ProgramError::InvalidArgument);
+    }
+    dex_slippage.attempts_this_slot += 1;
+
+    Ok(())
+}
+
+fn route_optimization_factor(iterations: u8) -> u64 {
+    let mut route_optimization_factor: u64 = 1;
+    for _ in 0..iterations {
+        route_optimization_factor = route_optimization_factor.wrapping_mul(2).wrapping_add(1);
+    }
+    route_optimization_factor
+}
+
+// Adds to `total_profit` at most once per nonce. Returns false for a replayed nonce.
+fn record_profit(dex_slippage: &mut DexSlippage, net_profit: u64, nonce: u64) -> Result<bool, ProgramError> {
+    if nonce <= dex_slippage.last_profit_nonce {
+        msg!("Profit for nonce {} already counted", nonce);
+        return Ok(false);
+    }
+
+    dex_slippage.total_profit = dex_slippage
+        .total_profit
+        .checked_add(net_profit)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    dex_slippage.last_profit_nonce = nonce;
+
+    // Statistics, cleared by reset_stats
+    dex_slippage.trade_count = dex_slippage.trade_count.saturating_add(1);
+    let next = dex_slippage.recent_profits_next as usize % PROFIT_HISTORY_LEN;
+    dex_slippage.recent_profits[next] = net_profit;
+    dex_slippage.recent_profits_next = ((next + 1) % PROFIT_HISTORY_LEN) as u8;
+
+    Ok(true)
+}
+
+// Consecutive-loss circuit breaker: `max_consecutive_losses` losses in a row
+// (0 = never) disable MEV until the owner re-enables it. After re-enabling the
+// breaker stays disarmed for `breaker_recovery_wins` consecutive wins, so a
+// single further loss doesn't immediately trip it again.
+fn record_trade_outcome(dex_slippage: &mut DexSlippage, profitable: bool) {
+    if profitable {
+        dex_slippage.consecutive_losses = 0;
+        dex_slippage.breaker_recovery_remaining = dex_slippage.breaker_recovery_remaining.saturating_sub(1);
+        return;
+    }
+
+    if dex_slippage.breaker_recovery_remaining > 0 {
+        // Still recovering, a loss restarts the win streak instead of counting
+        dex_slippage.breaker_recovery_remaining = dex_slippage.breaker_recovery_wins;
+        return;
+    }
+
+    dex_slippage.consecutive_losses = dex_slippage.consecutive_losses.saturating_add(1);
+    if dex_slippage.max_consecutive_losses > 0
+        && dex_slippage.consecutive_losses >= dex_slippage.max_consecutive_losses
+    {
+        msg!("Circuit breaker tripped after {} consecutive losses", dex_slippage.consecutive_losses);
+        dex_slippage.mev_enabled = false;
+    }
+}
+
+// Sets aside the `fee_bps` cut of a realized profit in `accrued_fees`, which
+// stays in the profit vault until WithdrawFees. Returns the profit left over.
+fn accrue_fee(dex_slippage: &mut DexSlippage, profit: u64) -> Result<u64, ProgramError> {
+    let fee = ((profit as u128) * (dex_slippage.fee_bps as u128) / 10_000) as u64;
+    dex_slippage.accrued_fees = dex_slippage
+        .accrued_fees
+        .checked_add(fee)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    Ok(profit - fee)
+}
+
+fn realize_profit(dex_slippage: &mut DexSlippage, net_profit: u64) -> ProgramResult {
+    let net_profit = accrue_fee(dex_slippage, net_profit)?;
+    if dex_slippage.compound {
+        // Compounding: park small profits until they exceed compound_threshold,
+        // then roll them back into the trading balance in one go
+        dex_slippage.pending_compound = dex_slippage
+            .pending_compound
+            .checked_add(net_profit)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        if dex_slippage.pending_compound <= dex_slippage.compound_threshold {
+            msg!("Profit pending compounding: {}", dex_slippage.pending_compound);
+            return Ok(());
+        }
+
+        let compounded = dex_slippage.pending_compound;
+        dex_slippage.trading_balance_in_tokens = dex_slippage
+            .trading_balance_in_tokens
+            .checked_add(compounded)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        dex_slippage.pending_compound = 0;
+        msg!("Compounded profit into trading balance: {}", compounded);
+    } else {
+        // Leave the profit for the owner to sweep
+        dex_slippage.unswept_profit = dex_slippage
+            .unswept_profit
+            .checked_add(net_profit)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        msg!("Profit available to sweep: {}", net_profit);
+    }
+
+    Ok(())
+}
+
+fn get_token_balance(token: &AccountInfo) -> Result<u64, ProgramError> {
+    // fetching token balance
+    let token_account = TokenAccount::unpack(&token.data.borrow())?;
+    Ok(token_account.amount)
+}
+
+fn ensure_not_frozen(token: &AccountInfo) -> ProgramResult {
+    let token_account = TokenAccount::unpack(&token.data.borrow())?;
+    if token_account.state == AccountState::Frozen {
+        msg!("Token account {} is frozen", token.key);
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+// Largest event payload logged in full, well inside the per-transaction log limit
+pub const MAX_EVENT_PAYLOAD_LEN: usize = 512;
+
+// Emits a structured event as `[name, borsh(event)]` via sol_log_data.
+// Oversized payloads are logged as `[name, "truncated", compact_payload]` instead.
+fn emit_event<T: BorshSerialize>(name: &str, event: &T) -> ProgramResult {
+    let payload = event.try_to_vec()?;
+    if payload.len() > MAX_EVENT_PAYLOAD_LEN {
+        msg!("Event {} payload of {} bytes is truncated", name, payload.len());
+        sol_log_data(&[name.as_bytes(), b"truncated", &compact_payload(&payload)]);
+    } else {
+        sol_log_data(&[name.as_bytes(), &payload]);
+    }
+    Ok(())
+}
+
+// Stands in for a payload too large to log: its length followed by its hash,
+// enough for an indexer to match it against the account data it came from
+fn compact_payload(payload: &[u8]) -> Vec<u8> {
+    let mut compact = (payload.len() as u32).to_le_bytes().to_vec();
+    compact.extend_from_slice(&hash(payload).to_bytes());
+    compact
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct BalanceDiscrepancy {
+    pub accounted: u64,
+    pub actual: u64,
+    pub paused: bool,
+}
+
+// Realized slippage of a swap next to the budgeted tolerance, both in bps
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct SlippageReport {
+    pub expected_out: u64,
+    pub realized_out: u64,
+    pub realized_slippage_bps: u64,
+    pub budgeted_slippage_bps: u64,
+}
+
+fn slippage_report(
+    dex_slippage: &DexSlippage,
+    expected_out: u64,
+    balance_before: u64,
+    balance_after: u64,
+) -> SlippageReport {
+    let realized_out = balance_after.saturating_sub(balance_before);
+    let shortfall = expected_out.saturating_sub(realized_out);
+    let realized_slippage_bps = if expected_out == 0 {
+        0
+    } else {
+        (shortfall as u128 * 10_000 / expected_out as u128) as u64
+    };
+
+    SlippageReport {
+        expected_out,
+        realized_out,
+        realized_slippage_bps,
+        budgeted_slippage_bps: effective_slippage_bps(dex_slippage, 0),
+    }
+}
+
+pub const SNAPSHOT_BEFORE_MEV: u8 = 0;
+pub const SNAPSHOT_AFTER_MEV: u8 = 1;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct ReserveSnapshot {
+    pub stage: u8,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+fn snapshot_reserves(pool_accounts: &[AccountInfo], stage: u8) -> Result<ReserveSnapshot, ProgramError> {
+    let (pool_reserve_a, pool_reserve_b) = match pool_accounts {
+        [reserve_a, reserve_b, ..] => (reserve_a, reserve_b),
+        _ => {
+            msg!("Missing pool reserve accounts for snapshot");
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+    };
+
+    let snapshot = ReserveSnapshot {
+        stage,
+        reserve_a: get_token_balance(pool_reserve_a)?,
+        reserve_b: get_token_balance(pool_reserve_b)?,
+    };
+    emit_event("reserve_snapshot", &snapshot)?;
+
+    Ok(snapshot)
+}
+
+fn execute_flashloan(
+    router: &AccountInfo,
+    token: &AccountInfo,
+    amount: u64,
+) -> Result<u64, ProgramError> {
+    // executing a flashloan
+    Ok(amount.wrapping_mul(2))
+}
+
+fn execute_atomic_arbitrage(
+    dex_slippage: &DexSlippage,
+    router: &AccountInfo,
+    token_in: &AccountInfo,
+    token_out: &AccountInfo,
+    amount: u64,
+) -> Result<u64, ProgramError> {
+    let buy_leg = get_price_from_router(dex_slippage, router, token_in, token_out, amount)?;
+
+    // The sell leg is quoted at the buy leg's output, so a price move between
+    // the two legs shows up as the round trip returning less than it put in
+    if dex_slippage.max_interleg_deviation_bps > 0 {
+        let sell_leg = get_price_from_router(dex_slippage, router, token_out, token_in, buy_leg.expected)?;
+        check_interleg_deviation(dex_slippage, amount, sell_leg.expected)?;
+    }
+
+    //  atomic arbitrage execution
+    let arbitrage_result = amount.wrapping_add(amount >> 2);
+
+    // Each leg is held to its own side's tolerance: the sell leg has to return
+    // its quote for the least the buy leg was allowed to fill
+    let buy_min_out = apply_slippage_bps(buy_leg.expected, side_slippage_bps(dex_slippage, TradeSide::Buy));
+    let sell_leg = get_price_from_router(dex_slippage, router, token_out, token_in, buy_min_out)?;
+    check_fill(
+        dex_slippage,
+        TradeSide::Sell,
+        &SwapFill {
+            quoted: sell_leg.expected,
+            filled: arbitrage_result,
+        },
+    )?;
+
+    Ok(arbitrage_result)
+}
+
+  
+
+// Only an adverse move is bounded: the sell leg returning more than the buy
+// leg put in is the arbitrage working, not a deviation to abort on
+fn check_interleg_deviation(dex_slippage: &DexSlippage, buy_leg_in: u64, sell_leg_out: u64) -> ProgramResult {
+    if sell_leg_out >= buy_leg_in || buy_leg_in == 0 {
+        return Ok(());
+    }
+
+    let deviation_bps = ((buy_leg_in - sell_leg_out) as u128) * 10_000 / (buy_leg_in as u128);
+    if deviation_bps > dex_slippage.max_interleg_deviation_bps as u128 {
+        msg!(
+            "Sell leg deviates {} bps from the buy leg, max {} bps",
+            deviation_bps,
+            dex_slippage.max_interleg_deviation_bps
+        );
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+fn execute_liquidity_provision(
+    dex_slippage: &DexSlippage,
+    router: &AccountInfo,
+    token_a: &AccountInfo,
+    token_b: &AccountInfo,
+    amount_a: u64,
+    amount_b: u64,
+) -> Result<u64, ProgramError> {
+    msg!("Executing liquidity provision...");
+
+    let mut total_liquidity_a: u64 = 0;
+    let mut total_liquidity_b: u64 = 0;
+
+    // calculating liquidity provisions in multiple steps
+    for step in 0..5 {
+        let provision_amount_a = (amount_a >> step).checked_add(step as u64).ok_or(ProgramError::ArithmeticOverflow)?;
+        let provision_amount_b = (amount_b >> step).checked_add(step as u64).ok_or(ProgramError::ArithmeticOverflow)?;
+
+        total_liquidity_a = total_liquidity_a
+            .checked_add(provision_amount_a)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        total_liquidity_b = total_liquidity_b
+            .checked_add(provision_amount_b)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        msg!("Step {}: Provision Amount A: {}, Provision Amount B: {}", step, provision_amount_a, provision_amount_b);
+    }
+
+    let liquidity_ratio = scaled_liquidity_ratio(
+        total_liquidity_a,
+        total_liquidity_b,
+        dex_slippage.liquidity_ratio_scale,
+    )?;
+    msg!("Total Liquidity A: {}", total_liquidity_a);
+    msg!("Total Liquidity B: {}", total_liquidity_b);
+    msg!("Liquidity Ratio: {}", liquidity_ratio);
+
+    let adjusted_liquidity_a = adjust_liquidity(router, token_a, total_liquidity_a)?;
+    let adjusted_liquidity_b = adjust_liquidity(router, token_b, total_liquidity_b)?;
+
+    msg!("Adjusted Liquidity A: {}", adjusted_liquidity_a);
+    msg!("Adjusted Liquidity B: {}", adjusted_liquidity_b);
+
+    Ok(liquidity_ratio)
+}
+
+// liquidity_a / liquidity_b scaled by `scale`, computed in u128 so neither
+// the multiplication overflows nor the division truncates early
+fn scaled_liquidity_ratio(liquidity_a: u64, liquidity_b: u64, scale: u64) -> Result<u64, ProgramError> {
+    if liquidity_b == 0 {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let scale = if scale == 0 { DEFAULT_LIQUIDITY_RATIO_SCALE } else { scale };
+    let ratio = (liquidity_a as u128) * (scale as u128) / (liquidity_b as u128);
+
+    u64::try_from(ratio).map_err(|_| ProgramError::ArithmeticOverflow)
+}
+
+fn rebalance_portfolio(
+    dex_slippage: &DexSlippage,
+    router: &AccountInfo,
+    token_a: &AccountInfo,
+    token_b: &AccountInfo,
+    amount: u64,
+) -> ProgramResult {
+    msg!("Rebalancing portfolio...");
+
+    // A 50/50 split of a mint against itself is meaningless
+    let mint_a = TokenAccount::unpack(&token_a.data.borrow())?.mint;
+    let mint_b = TokenAccount::unpack(&token_b.data.borrow())?.mint;
+    if mint_a == mint_b {
+        msg!("Cannot rebalance mint {} against itself", mint_a);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Fetch initial token balances
+    let initial_balance_a = get_token_balance(token_a)?;
+    let initial_balance_b = get_token_balance(token_b)?;
+
+    // Calculate target balances for rebalancing
+    let total_balance = initial_balance_a
+        .checked_add(initial_balance_b)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    let (target_balance_a, target_balance_b) =
+        rebalance_targets(total_balance, dex_slippage.rebalance_remainder_to_a)?;
+
+    // Determine amounts to buy/sell for rebalancing
+    let difference_a = if initial_balance_a > target_balance_a {
+        initial_balance_a - target_balance_a
+    } else {
+        target_balance_a - initial_balance_a
+    };
+
+    let difference_b = if initial_balance_b > target_balance_b {
+        initial_balance_b - target_balance_b
+    } else {
+        target_balance_b - initial_balance_b
+    };
+
+    let mut rebalance_steps = 5;
+    let mut adjustment_a: i64 = 0;
+    let mut adjustment_b: i64 = 0;
+
+    // Perform rebalancing in steps
+    for i in 0..rebalance_steps {
+        let step_amount_a = (difference_a / rebalance_steps) >> i;
+        let step_amount_b = (difference_b / rebalance_steps) >> i;
+
+        // The legs only quote for now, see swap_leg
+        if initial_balance_a > target_balance_a {
+            sell_token(dex_slippage, router, token_a, token_b, step_amount_a)?;
+            adjustment_a = adjust_balance(adjustment_a, step_amount_a, TradeSide::Sell)?;
+        } else {
+            buy_token(dex_slippage, router, token_a, token_b, step_amount_a)?;
+            adjustment_a = adjust_balance(adjustment_a, step_amount_a, TradeSide::Buy)?;
+        }
+
+        if initial_balance_b > target_balance_b {
+            sell_token(dex_slippage, router, token_b, token_a, step_amount_b)?;
+            adjustment_b = adjust_balance(adjustment_b, step_amount_b, TradeSide::Sell)?;
+        } else {
+            buy_token(dex_slippage, router, token_b, token_a, step_amount_b)?;
+            adjustment_b = adjust_balance(adjustment_b, step_amount_b, TradeSide::Buy)?;
+        }
+
+        msg!(
+            "Step {}: Adjustment A: {}, Adjustment B: {}",
+            i,
+            adjustment_a,
+            adjustment_b
+        );
+    }
+
+    Ok(())
+}
+
+// Quoted and actually filled output of a swap leg
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SwapFill {
+    pub quoted: u64,
+    pub filled: u64,
+}
+
+// Sells `amount` of token for counter_token
+fn sell_token(
+    dex_slippage: &DexSlippage,
+    router: &AccountInfo,
+    token: &AccountInfo,
+    counter_token: &AccountInfo,
+    amount: u64,
+) -> Result<RouterQuote, ProgramError> {
+    swap_leg(dex_slippage, TradeSide::Sell, router, token, counter_token, amount)
+}
+
+// Buys token with `amount` of counter_token
+fn buy_token(
+    dex_slippage: &DexSlippage,
+    router: &AccountInfo,
+    token: &AccountInfo,
+    counter_token: &AccountInfo,
+    amount: u64,
+) -> Result<RouterQuote, ProgramError> {
+    swap_leg(dex_slippage, TradeSide::Buy, router, counter_token, token, amount)
+}
+
+// Stub until a router swap is wired in: the leg is quoted and bounded by its
+// side's slippage, but nothing is swapped, so there is no fill to check yet
+fn swap_leg(
+    dex_slippage: &DexSlippage,
+    side: TradeSide,
+    router: &AccountInfo,
+    token_in: &AccountInfo,
+    token_out: &AccountInfo,
+    amount: u64,
+) -> Result<RouterQuote, ProgramError> {
+    let quote = get_price_from_router(dex_slippage, router, token_in, token_out, amount)?;
+    let min_out = apply_slippage_bps(quote.expected, side_slippage_bps(dex_slippage, side));
+
+    // executing the swap through the router
+
+    Ok(RouterQuote {
+        expected: quote.expected,
+        min_out,
+    })
+}
+
+// Whether a swap leg enters (buys) or exits (sells) the traded token
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TradeSide {
+    Buy,
+    Sell,
+}
+
+// Per-side tolerance in bps. A side left at zero uses the global slippage_percent.
+fn side_slippage_bps(dex_slippage: &DexSlippage, side: TradeSide) -> u64 {
+    let side_bps = match side {
+        TradeSide::Buy => dex_slippage.buy_slippage_bps,
+        TradeSide::Sell => dex_slippage.sell_slippage_bps,
+    };
+    if side_bps == 0 {
+        return dex_slippage.slippage_percent as u64 * 100;
+    }
+    side_bps as u64
+}
+
+fn check_fill(dex_slippage: &DexSlippage, side: TradeSide, fill: &SwapFill) -> ProgramResult {
+    let min_out = apply_slippage_bps(fill.quoted, side_slippage_bps(dex_slippage, side));
+    if fill.filled < min_out {
+        msg!("{:?} fill {} below minimum {} (quoted {})", side, fill.filled, min_out, fill.quoted);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+// Running net adjustment of a rebalanced token: sells add, buys subtract
+fn adjust_balance(adjustment: i64, step_amount: u64, side: TradeSide) -> Result<i64, ProgramError> {
+    let step_amount = i64::try_from(step_amount).map_err(|_| ProgramError::ArithmeticOverflow)?;
+    let adjusted = match side {
+        TradeSide::Sell => adjustment.checked_add(step_amount),
+        TradeSide::Buy => adjustment.checked_sub(step_amount),
+    };
+    adjusted.ok_or(ProgramError::ArithmeticOverflow)
+}
+
+// Splits the total in half, giving an odd unit to the configured side so the
+// targets always add back up to the total
+fn rebalance_targets(total_balance: u64, remainder_to_a: bool) -> Result<(u64, u64), ProgramError> {
+    let half = total_balance / 2;
+    let remainder = total_balance % 2;
+    let targets = if remainder_to_a {
+        (half + remainder, half)
+    } else {
+        (half, half + remainder)
+    };
+
+    if targets.0.checked_add(targets.1) != Some(total_balance) {
+        msg!("Rebalance targets {} / {} don't add up to {}", targets.0, targets.1, total_balance);
+        return Err(ProgramError::ArithmeticOverflow);
+    }
+    Ok(targets)
+}
+
+fn withdraw_funds(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let receiver = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+    ensure_writable(receiver)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches and actually signed
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    // Large balances must go through the timelocked withdrawal
+    let lamports = withdrawable_lamports(&dex_slippage, state_account.lamports());
+    if lamports == 0 && dex_slippage.min_operating_lamports > 0 {
+        msg!("Nothing to withdraw above the {} lamport operating reserve", dex_slippage.min_operating_lamports);
+        return Err(ProgramError::InsufficientFunds);
+    }
+    if dex_slippage.withdraw_delay_slots > 0 && lamports > dex_slippage.instant_withdraw_limit {
+        msg!("Withdrawal exceeds instant limit, use a delayed withdrawal");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if dex_slippage.daily_withdraw_limit > 0 {
+        record_daily_withdrawal(&mut dex_slippage, lamports, Clock::get()?.slot)?;
+        write_state(&dex_slippage, &mut state_data)?;
+    }
+
+    // Transfer everything above the operating reserve to the owner's account
+    move_lamports(state_account, receiver, lamports)?;
+
+    msg!("Funds withdrawn by the owner");
+
+    Ok(())
+}
+
+// Lamports that can leave the bot's account while keeping `min_operating_lamports`
+// behind, so it can still pay for the transactions and CPIs it runs later
+fn withdrawable_lamports(dex_slippage: &DexSlippage, balance: u64) -> u64 {
+    balance.saturating_sub(dex_slippage.min_operating_lamports)
+}
+
+fn check_operating_reserve(dex_slippage: &DexSlippage, balance: u64, amount: u64) -> ProgramResult {
+    if amount > withdrawable_lamports(dex_slippage, balance) {
+        msg!(
+            "Withdrawal of {} would breach the {} lamport operating reserve",
+            amount,
+            dex_slippage.min_operating_lamports
+        );
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    Ok(())
+}
+
+fn withdraw_request(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let receiver = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+    ensure_writable(receiver)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    // Small withdrawals are paid out immediately, large ones are queued
+    let current_slot = Clock::get()?.slot;
+    let instant = queue_withdrawal(&mut dex_slippage, amount, current_slot)?;
+    if instant {
+        check_operating_reserve(&dex_slippage, state_account.lamports(), amount)?;
+        record_daily_withdrawal(&mut dex_slippage, amount, current_slot)?;
+        move_lamports(state_account, receiver, amount)?;
+        msg!("Instant withdrawal of {} lamports", amount);
+    } else {
+        msg!("Withdrawal of {} lamports queued", amount);
+    }
+    write_state(&dex_slippage, &mut state_data)?;
+
+    Ok(())
+}
+
+fn withdraw_execute(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let receiver = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+    ensure_writable(receiver)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    let current_slot = Clock::get()?.slot;
+    let amount = release_withdrawal(&mut dex_slippage, current_slot)?;
+    check_operating_reserve(&dex_slippage, state_account.lamports(), amount)?;
+    record_daily_withdrawal(&mut dex_slippage, amount, current_slot)?;
+    move_lamports(state_account, receiver, amount)?;
+    write_state(&dex_slippage, &mut state_data)?;
+
+    msg!("Delayed withdrawal of {} lamports executed", amount);
+
+    Ok(())
+}
+
+fn approve_delegate(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let source = next_account_info(accounts_iter)?;
+    let delegate = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+    ensure_writable(source)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let mut dex_slippage = load_state(state_account)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+    check_token_amount(&dex_slippage, amount)?;
+    validate_token_account(source, state_account, program_id)?;
+    check_delegate(&dex_slippage, delegate.key)?;
+
+    approve_tokens(token_program, source, delegate, authority, amount)?;
+
+    // Start the TTL clock for the new approval
+    if dex_slippage.approval_ttl_slots > 0 {
+        dex_slippage.approval_slot = Clock::get()?.slot;
+        store_state(state_account, &dex_slippage)?;
+    }
+
+    msg!("Approved {} tokens for delegate {}", amount, delegate.key);
+
+    Ok(())
+}
+
+// Only the delegate sanctioned by the owner may be approved
+fn check_delegate(dex_slippage: &DexSlippage, delegate: &Pubkey) -> ProgramResult {
+    match dex_slippage.approved_delegate {
+        Some(approved_delegate) if approved_delegate == *delegate => Ok(()),
+        _ => {
+            msg!("Delegate {} is not the approved delegate", delegate);
+            Err(ProgramError::InvalidArgument)
+        }
+    }
+}
+
+// Marks an approval older than approval_ttl_slots as revoked. Returns true when
+// it just expired, so the caller revokes the SPL delegation. Slot 0 means no live approval.
+fn expire_delegation(dex_slippage: &mut DexSlippage, current_slot: u64) -> bool {
+    if dex_slippage.approval_slot == 0 || dex_slippage.approval_ttl_slots == 0 {
+        return false;
+    }
+
+    if current_slot.saturating_sub(dex_slippage.approval_slot) <= dex_slippage.approval_ttl_slots {
+        return false;
+    }
+
+    dex_slippage.approval_slot = 0;
+    true
+}
+
+fn set_approval_ttl(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    approval_ttl_slots: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set approval TTL (0 lets approvals live until revoked)
+        dex_slippage.approval_ttl_slots = approval_ttl_slots;
+        Ok(())
+    })
+}
+
+fn set_approved_delegate(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    approved_delegate: Option<Pubkey>,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set or clear the sanctioned delegate
+        dex_slippage.approved_delegate = approved_delegate;
+        Ok(())
+    })
+}
+
+fn set_allow_zero_amount(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    allow: bool,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Allow or reject zero-amount transfers and approvals
+        dex_slippage.allow_zero_amount = allow;
+        Ok(())
+    })
+}
+
+fn set_pair_mints(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    base_mint: Pubkey,
+    quote_mint: Pubkey,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if base_mint == quote_mint {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Register the base/quote mints
+        dex_slippage.base_mint = base_mint;
+        dex_slippage.quote_mint = quote_mint;
+        Ok(())
+    })
+}
+
+fn set_retry_slippage(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    retry_slippage_step_bps: u16,
+    max_retry_slippage_bps: u16,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if max_retry_slippage_bps > 10_000 {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set retry slippage escalation
+        dex_slippage.retry_slippage_step_bps = retry_slippage_step_bps;
+        dex_slippage.max_retry_slippage_bps = max_retry_slippage_bps;
+        Ok(())
+    })
+}
+
+fn set_max_oracle_confidence(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_oracle_confidence_bps: u16,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if max_oracle_confidence_bps > 10_000 {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set max oracle confidence
+        dex_slippage.max_oracle_confidence_bps = max_oracle_confidence_bps;
+        Ok(())
+    })
+}
+
+fn set_iteration_limits(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    optimization_iterations: u8,
+    arbitrage_iterations: u8,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        validate_iteration_limits(optimization_iterations, arbitrage_iterations)?;
+
+        // Set loop bounds
+        dex_slippage.optimization_iterations = optimization_iterations;
+        dex_slippage.arbitrage_iterations = arbitrage_iterations;
+        Ok(())
+    })
+}
+
+fn validate_iteration_limits(optimization_iterations: u8, arbitrage_iterations: u8) -> ProgramResult {
+    if !(1..=MAX_OPTIMIZATION_ITERATIONS).contains(&optimization_iterations)
+        || !(1..=MAX_ARBITRAGE_ITERATIONS).contains(&arbitrage_iterations)
+    {
+        msg!("Iteration limits out of range");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+fn set_max_attempts_per_slot(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_attempts_per_slot: u8,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set max attempts per slot (0 disables the cap)
+        dex_slippage.max_attempts_per_slot = max_attempts_per_slot;
+        Ok(())
+    })
+}
+
+fn set_max_spread(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_spread_bps: u16,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if max_spread_bps > 10_000 {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set max spread (0 disables the check)
+        dex_slippage.max_spread_bps = max_spread_bps;
+        Ok(())
+    })
+}
+
+fn set_router_filter(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    mode: RouterFilterMode,
+    routers: [Pubkey; MAX_FILTERED_ROUTERS],
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set router filter
+        dex_slippage.router_filter_mode = mode;
+        dex_slippage.filtered_routers = routers;
+        Ok(())
+    })
+}
+
+fn set_arb_tx_price(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    price: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if price == 0 {
+            msg!("Arb tx price must be non-zero");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set reference transaction price
+        dex_slippage.arb_tx_price = price;
+        Ok(())
+    })
+}
+
+fn set_max_plan_age(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_plan_age_slots: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set max cached plan age (0 disables the limit)
+        dex_slippage.max_plan_age_slots = max_plan_age_slots;
+        Ok(())
+    })
+}
+
+fn set_slippage_change_interval(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    min_slippage_change_interval_slots: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set slippage change cooldown (0 disables it)
+        change_slippage_interval(dex_slippage, min_slippage_change_interval_slots, Clock::get()?.slot)?;
+        Ok(())
+    })
+}
+
+// Raising the cooldown only tightens it and applies at once. Lowering it is
+// what a compromised key would do first, so it is time-locked: the first call
+// requests the new value, a repeat call once the current cooldown has passed
+// applies it.
+fn change_slippage_interval(dex_slippage: &mut DexSlippage, interval_slots: u64, current_slot: u64) -> ProgramResult {
+    let current_interval = dex_slippage.min_slippage_change_interval_slots;
+    if interval_slots >= current_interval {
+        dex_slippage.min_slippage_change_interval_slots = interval_slots;
+        dex_slippage.pending_slippage_change_interval = None;
+        return Ok(());
+    }
+
+    if dex_slippage.pending_slippage_change_interval != Some(interval_slots) {
+        dex_slippage.pending_slippage_change_interval = Some(interval_slots);
+        dex_slippage.pending_slippage_change_interval_slot = current_slot;
+        msg!("Slippage cooldown of {} slots requested, applies in {} slots", interval_slots, current_interval);
+        return Ok(());
+    }
+
+    let waited = current_slot.saturating_sub(dex_slippage.pending_slippage_change_interval_slot);
+    if waited < current_interval {
+        msg!("Slippage cooldown of {} slots applies in {} slots", interval_slots, current_interval - waited);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    dex_slippage.min_slippage_change_interval_slots = interval_slots;
+    dex_slippage.pending_slippage_change_interval = None;
+    Ok(())
+}
+
+fn set_max_fallback_amount(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_fallback_amount: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set unleveraged fallback cap (0 disables the fallback)
+        dex_slippage.max_fallback_amount = max_fallback_amount;
+        Ok(())
+    })
+}
+
+// Sets, updates or (with a cap of 0) removes the position cap for a mint
+fn set_position_cap(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    mint: Pubkey,
+    cap: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Reuse the mint's slot, or take a free one
+        let slot = dex_slippage
+            .position_caps
+            .iter()
+            .position(|(capped_mint, _)| *capped_mint == mint)
+            .or_else(|| {
+                dex_slippage
+                    .position_caps
+                    .iter()
+                    .position(|(capped_mint, cap)| *capped_mint == Pubkey::default() || *cap == 0)
+            })
+            .ok_or_else(|| {
+                msg!("No room for another position cap");
+                ProgramError::InvalidArgument
+            })?;
+
+        // Set position cap
+        dex_slippage.position_caps[slot] = if cap == 0 { (Pubkey::default(), 0) } else { (mint, cap) };
+        Ok(())
+    })
+}
+
+fn set_router_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    router: Pubkey,
+    fee_bps: u16,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if fee_bps > 10_000 {
+            msg!("Router fee must be at most 10000 bps");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Reuse the router's slot, or take a free one
+        let slot = dex_slippage
+            .router_fees
+            .iter()
+            .position(|(fee_router, _)| *fee_router == router)
+            .or_else(|| {
+                dex_slippage
+                    .router_fees
+                    .iter()
+                    .position(|(fee_router, _)| *fee_router == Pubkey::default())
+            })
+            .ok_or_else(|| {
+                msg!("No room for another router fee");
+                ProgramError::InvalidArgument
+            })?;
+
+        // Set router fee
+        dex_slippage.router_fees[slot] = if fee_bps == 0 { (Pubkey::default(), 0) } else { (router, fee_bps) };
+        Ok(())
+    })
+}
+
+fn set_liquidity_threshold_mint(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    mint: Pubkey,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set the mint the liquidity threshold is denominated in
+        dex_slippage.liquidity_threshold_mint = mint;
+        Ok(())
+    })
+}
+
+fn set_safe_mode(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    safe_mode: bool,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Enable or disable simulate-before-execute
+        dex_slippage.safe_mode = safe_mode;
+        Ok(())
+    })
+}
+
+fn set_profit_conversion(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    profit_mint: Pubkey,
+    profit_conversion_router: Pubkey,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        validate_router(dex_slippage, &profit_conversion_router)?;
+
+        // Set profit denomination and its conversion route
+        dex_slippage.profit_mint = profit_mint;
+        dex_slippage.profit_conversion_router = profit_conversion_router;
+        Ok(())
+    })
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct StatsReset {
+    pub trade_count: u64,
+    pub total_profit: u64,
+}
+
+// Starts a new accounting period. Only statistics are cleared: config, balances
+// and the profit nonce (replay protection) are left as they are.
+fn reset_stats(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        let reset = StatsReset {
+            trade_count: dex_slippage.trade_count,
+            total_profit: dex_slippage.total_profit,
+        };
+
+        // Clear statistics
+        dex_slippage.trade_count = 0;
+        dex_slippage.total_profit = 0;
+        dex_slippage.recent_profits = [0; PROFIT_HISTORY_LEN];
+        dex_slippage.recent_profits_next = 0;
+
+        emit_event("stats_reset", &reset)
+    })
+}
+
+fn set_strategy(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    strategy: MevStrategy,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Select the MEV strategy
+        dex_slippage.strategy = strategy;
+        Ok(())
+    })
+}
+
+fn set_oracles(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    oracles: [Pubkey; MAX_ORACLES],
+    oracle_quorum: u8,
+    oracle_band_bps: u16,
+    oracle_program: Pubkey,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // The quorum must be reachable with the configured oracles
+        let oracle_count = oracles.iter().filter(|o| **o != Pubkey::default()).count();
+        if oracle_quorum as usize > oracle_count.max(1) || oracle_band_bps > 10_000 {
+            msg!("Invalid oracle quorum {} of {}", oracle_quorum, oracle_count);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set oracles
+        dex_slippage.oracles = oracles;
+        dex_slippage.oracle_quorum = oracle_quorum;
+        dex_slippage.oracle_band_bps = oracle_band_bps;
+        dex_slippage.oracle_program = oracle_program;
+        Ok(())
+    })
+}
+
+fn set_side_slippage(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    buy_slippage_bps: u16,
+    sell_slippage_bps: u16,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if buy_slippage_bps > 10_000 || sell_slippage_bps > 10_000 {
+            msg!("Invalid side slippage: buy {} bps, sell {} bps", buy_slippage_bps, sell_slippage_bps);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set buy and sell slippage
+        dex_slippage.buy_slippage_bps = buy_slippage_bps;
+        dex_slippage.sell_slippage_bps = sell_slippage_bps;
+        Ok(())
+    })
+}
+
+fn set_volatility_slippage(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    min_volatility_slippage_bps: u16,
+    max_volatility_slippage_bps: u16,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if max_volatility_slippage_bps > 10_000 || min_volatility_slippage_bps > max_volatility_slippage_bps {
+            msg!(
+                "Invalid volatility slippage band: {} to {} bps",
+                min_volatility_slippage_bps,
+                max_volatility_slippage_bps
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set the volatility slippage band (a max of 0 keeps the static slippage)
+        dex_slippage.min_volatility_slippage_bps = min_volatility_slippage_bps;
+        dex_slippage.max_volatility_slippage_bps = max_volatility_slippage_bps;
+        Ok(())
+    })
+}
+
+fn set_min_profit_threshold(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    min_profit_threshold: i64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set the minimum profit, negative to accept a bounded loss
+        dex_slippage.min_profit_threshold = min_profit_threshold;
+        Ok(())
+    })
+}
+
+fn set_compound_threshold(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    compound_threshold: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set the compounding threshold
+        dex_slippage.compound_threshold = compound_threshold;
+        Ok(())
+    })
+}
+
+fn set_min_hops(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    min_hops: u8,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // No route longer than the triangular cycle is ever quoted
+        if min_hops > TRIANGULAR_HOPS {
+            msg!("Minimum hops {} exceeds the longest route {}", min_hops, TRIANGULAR_HOPS);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set minimum route length
+        dex_slippage.min_hops = min_hops;
+        Ok(())
+    })
+}
+
+fn set_max_interleg_deviation(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_interleg_deviation_bps: u16,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if max_interleg_deviation_bps > 10_000 {
+            msg!("Invalid inter-leg deviation: {} bps", max_interleg_deviation_bps);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set max inter-leg deviation
+        dex_slippage.max_interleg_deviation_bps = max_interleg_deviation_bps;
+        Ok(())
+    })
+}
+
+fn set_min_operating_lamports(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    min_operating_lamports: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set the lamports withdrawals must leave behind
+        dex_slippage.min_operating_lamports = min_operating_lamports;
+        Ok(())
+    })
+}
+
+fn set_circuit_breaker(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_consecutive_losses: u8,
+    breaker_recovery_wins: u8,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set the losses that trip the breaker and the wins needed to re-arm it
+        dex_slippage.max_consecutive_losses = max_consecutive_losses;
+        dex_slippage.breaker_recovery_wins = breaker_recovery_wins;
+        Ok(())
+    })
+}
+
+fn set_log_level(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    log_level: u8,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if log_level > LOG_LEVEL_VERBOSE {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set log level
+        dex_slippage.log_level = log_level;
+        Ok(())
+    })
+}
+
+fn set_withdraw_delay(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    withdraw_delay_slots: u64,
+    instant_withdraw_limit: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set withdrawal timelock
+        dex_slippage.withdraw_delay_slots = withdraw_delay_slots;
+        dex_slippage.instant_withdraw_limit = instant_withdraw_limit;
+        Ok(())
+    })
+}
+
+fn set_daily_withdraw_limit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    daily_withdraw_limit: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set daily withdrawal cap (0 disables it)
+        dex_slippage.daily_withdraw_limit = daily_withdraw_limit;
+        Ok(())
+    })
+}
+
+// Returns true when the withdrawal can be paid out immediately
+fn queue_withdrawal(
+    dex_slippage: &mut DexSlippage,
+    amount: u64,
+    current_slot: u64,
+) -> Result<bool, ProgramError> {
+    if dex_slippage.withdraw_delay_slots == 0 || amount <= dex_slippage.instant_withdraw_limit {
+        return Ok(true);
+    }
+
+    if dex_slippage.pending_withdraw_amount != 0 {
+        msg!("A delayed withdrawal is already pending");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    dex_slippage.pending_withdraw_amount = amount;
+    dex_slippage.pending_withdraw_slot = current_slot;
+
+    Ok(false)
+}
+
+fn release_withdrawal(dex_slippage: &mut DexSlippage, current_slot: u64) -> Result<u64, ProgramError> {
+    if dex_slippage.pending_withdraw_amount == 0 {
+        msg!("No delayed withdrawal pending");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let unlock_slot = dex_slippage
+        .pending_withdraw_slot
+        .saturating_add(dex_slippage.withdraw_delay_slots);
+    if current_slot < unlock_slot {
+        msg!("Withdrawal is timelocked until slot {}", unlock_slot);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let amount = dex_slippage.pending_withdraw_amount;
+    dex_slippage.pending_withdraw_amount = 0;
+    dex_slippage.pending_withdraw_slot = 0;
+
+    Ok(amount)
+}
+
+// Counts a payout against the daily limit, starting a new window once a day
+// has passed. A limit of 0 disables the cap.
+fn record_daily_withdrawal(dex_slippage: &mut DexSlippage, amount: u64, current_slot: u64) -> ProgramResult {
+    if dex_slippage.daily_withdraw_limit == 0 {
+        return Ok(());
+    }
+
+    if current_slot.saturating_sub(dex_slippage.withdraw_window_start_slot) >= SLOTS_PER_DAY {
+        dex_slippage.withdraw_window_start_slot = current_slot;
+        dex_slippage.withdrawn_today = 0;
+    }
+
+    let withdrawn_today = dex_slippage
+        .withdrawn_today
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    if withdrawn_today > dex_slippage.daily_withdraw_limit {
+        msg!(
+            "Withdrawal of {} exceeds daily limit {} ({} already withdrawn)",
+            amount,
+            dex_slippage.daily_withdraw_limit,
+            dex_slippage.withdrawn_today
+        );
+        return Err(ProgramError::InsufficientFunds);
+    }
+    dex_slippage.withdrawn_today = withdrawn_today;
+
+    Ok(())
+}
+
+fn move_lamports(from: &AccountInfo, to: &AccountInfo, amount: u64) -> ProgramResult {
+    let from_balance = from
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    let to_balance = to
+        .lamports()
+        .checked_add(amount)
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+
+    **from.lamports.borrow_mut() = from_balance;
+    **to.lamports.borrow_mut() = to_balance;
+
+    Ok(())
+}
+
+fn perform_spl_arbitrage(
+    dex_slippage: &DexSlippage,
+    token_a: &AccountInfo,
+    token_b: &AccountInfo,
+    amount: u64,
+) -> ProgramResult {
+    msg!("Performing SPL token arbitrage...");
+
+    // Fetch initial token balances
+    let initial_balance_a = get_token_balance(token_a)?;
+    let initial_balance_b = get_token_balance(token_b)?;
+
+    // SPL token arbitrage
+    let arbitrage_route_a = calculate_arbitrage_route(token_a, amount)?;
+    let arbitrage_route_b = calculate_arbitrage_route(token_b, amount)?;
+
+    debug_msg!(dex_slippage, "Arbitrage route for Token A: {}", arbitrage_route_a);
+    debug_msg!(dex_slippage, "Arbitrage route for Token B: {}", arbitrage_route_b);
+
+    let mut profit_a = 0;
+    let mut profit_b = 0;
+
+    // Perform a series of arbitrage trades
+    let trades = spl_arbitrage_trades(amount, arbitrage_iterations(dex_slippage));
+    for (i, (trade_amount_a, trade_amount_b)) in trades.into_iter().enumerate() {
+        let trade_result_a = match trade_amount_a {
+            Some(trade_amount_a) => execute_trade(token_a, trade_amount_a)?,
+            None => 0,
+        };
+        let trade_result_b = match trade_amount_b {
+            Some(trade_amount_b) => execute_trade(token_b, trade_amount_b)?,
+            None => 0,
+        };
+
+        profit_a = profit_a.wrapping_add(trade_result_a);
+        profit_b = profit_b.wrapping_add(trade_result_b);
+
+        debug_msg!(dex_slippage, "Trade {}: Result A: {}, Result B: {}", i, trade_result_a, trade_result_b);
+    }
+
+    // Calculate final profits
+    let final_profit = profit_a.wrapping_add(profit_b) >> 1;
+    debug_msg!(dex_slippage, "Final arbitrage profit: {}", final_profit);
+
+    // Check if arbitrage was profitable
+    let threshold_profit: u64 = 1000;
+    let is_profitable = final_profit > threshold_profit;
+    debug_msg!(dex_slippage, "Is arbitrage profitable? {}", is_profitable);
+
+    if is_profitable {
+        msg!("Arbitrage execution successful with profit: {}", final_profit);
+    } else {
+        msg!("Arbitrage execution not profitable");
+    }
+
+    Ok(())
+}
+
+// Amounts for each of perform_spl_arbitrage's trades. Leg A halves the amount
+// every step and leg B grows toward it; a leg whose share of a small balance
+// rounds down to zero has nothing to trade and is skipped (None).
+fn spl_arbitrage_trades(amount: u64, iterations: u8) -> Vec<(Option<u64>, Option<u64>)> {
+    (0..iterations)
+        .map(|i| {
+            let step = |shift: u8| match amount >> shift {
+                0 => None,
+                share => Some(share.wrapping_add(i as u64)),
+            };
+            (step(i), step(iterations - i))
+        })
+        .collect()
+}
+
+fn update_trading_balance(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    new_balance: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Update trading balance
+        dex_slippage.trading_balance_in_tokens = new_balance;
+        Ok(())
+    })
+}
+
+fn propose_owner(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    new_owner: Pubkey,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    // Record the proposal, replacing any earlier one
+    dex_slippage.pending_owner = Some(new_owner);
+    dex_slippage.pending_owner_slot = Clock::get()?.slot;
+    write_state(&dex_slippage, &mut state_data)?;
+
+    msg!("Ownership transfer proposed to {}", new_owner);
+
+    Ok(())
+}
+
+fn accept_ownership(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let new_owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+
+    if !new_owner.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    ensure_distinct_owner(new_owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    accept_pending_owner(&mut dex_slippage, new_owner.key, Clock::get()?.slot)?;
+    write_state(&dex_slippage, &mut state_data)?;
+
+    msg!("Ownership accepted by {}", new_owner.key);
+
+    Ok(())
+}
+
+fn set_pending_owner_window(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    window_slots: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set pending owner window (0 keeps proposals open until accepted)
+        dex_slippage.pending_owner_window_slots = window_slots;
+        Ok(())
+    })
+}
+
+// Clears a pending owner whose acceptance window has elapsed
+fn expire_pending_owner(dex_slippage: &mut DexSlippage, current_slot: u64) {
+    if dex_slippage.pending_owner.is_none() || dex_slippage.pending_owner_window_slots == 0 {
+        return;
+    }
+
+    let expiry_slot = dex_slippage
+        .pending_owner_slot
+        .saturating_add(dex_slippage.pending_owner_window_slots);
+    if current_slot > expiry_slot {
+        msg!("Pending owner proposal expired at slot {}", expiry_slot);
+        dex_slippage.pending_owner = None;
+        dex_slippage.pending_owner_slot = 0;
+    }
+}
+
+fn accept_pending_owner(
+    dex_slippage: &mut DexSlippage,
+    new_owner: &Pubkey,
+    current_slot: u64,
+) -> ProgramResult {
+    expire_pending_owner(dex_slippage, current_slot);
+
+    match dex_slippage.pending_owner {
+        Some(pending_owner) if pending_owner == *new_owner => {
+            // The new owner takes the old owner's place in the multi-sig set
+            let old_owner = dex_slippage.owner;
+            for co_owner in dex_slippage.owners.iter_mut().filter(|co_owner| **co_owner == old_owner) {
+                *co_owner = pending_owner;
+            }
+            dex_slippage.owner = pending_owner;
+            dex_slippage.pending_owner = None;
+            dex_slippage.pending_owner_slot = 0;
+            Ok(())
+        }
+        Some(_) => Err(ProgramError::IncorrectProgramId),
+        None => {
+            msg!("No pending ownership transfer");
+            Err(ProgramError::InvalidAccountData)
+        }
+    }
+}
+
+// Listed owners fill the front of the set, unused slots at the end are left
+// as the default key. No key may appear twice, as it would count twice toward
+// the threshold, and the owner itself must be listed.
+fn validate_owners(owner: &Pubkey, owners: &[Pubkey; MAX_OWNERS], threshold: u8) -> ProgramResult {
+    let owner_count = owners.iter().take_while(|o| **o != Pubkey::default()).count();
+    let listed = &owners[..owner_count];
+    if owners[owner_count..].iter().any(|o| *o != Pubkey::default()) {
+        msg!("Owners must be listed without gaps");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if listed.iter().enumerate().any(|(i, o)| listed[..i].contains(o)) {
+        msg!("Owners must be distinct");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if owner_count > 0 && !listed.contains(owner) {
+        msg!("Owner {} must be one of the listed owners", owner);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The threshold must be reachable by the listed owners
+    if threshold as usize > owner_count.max(1) {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    Ok(())
+}
+
+fn set_owners(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    owners: [Pubkey; MAX_OWNERS],
+    threshold: u8,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        validate_owners(&dex_slippage.owner, &owners, threshold)?;
+
+        // Set multi-sig owners
+        dex_slippage.owners = owners;
+        dex_slippage.threshold = threshold;
+        Ok(())
+    })
+}
+
+fn init_vault(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    mint: Pubkey,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let mint_info = next_account_info(accounts_iter)?;
+    let system_program = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let associated_token_program = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    // The owner pays for the vault
+    ensure_writable(owner)?;
+    ensure_writable(vault)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let dex_slippage = load_state(state_account)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    // Vaults are owned by the authority PDA
+    let (authority_pda, _authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if *authority.key != authority_pda || *mint_info.key != mint {
+        return Err(ProgramError::InvalidArgument);
+    }
+    if *vault.key != get_associated_token_address(&authority_pda, &mint) {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if vault_exists(vault) {
+        msg!("Vault for mint {} already exists", mint);
+        return Ok(());
+    }
+
+    invoke(
+        &create_associated_token_account(owner.key, &authority_pda, &mint, token_program.key),
+        &[
+            owner.clone(),
+            vault.clone(),
+            authority.clone(),
+            mint_info.clone(),
+            system_program.clone(),
+            token_program.clone(),
+            associated_token_program.clone(),
+        ],
+    )?;
+
+    msg!("Created vault {} for mint {}", vault.key, mint);
+
+    Ok(())
+}
+
+fn vault_exists(vault: &AccountInfo) -> bool {
+    *vault.owner == spl_token::id() && !vault.data_is_empty()
+}
+
+fn set_migration_destinations(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    destinations: [Pubkey; MAX_MIGRATION_DESTINATIONS],
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set migration allowlist
+        dex_slippage.migration_destinations = destinations;
+        Ok(())
+    })
+}
+
+fn is_migration_destination(dex_slippage: &DexSlippage, destination_state: &Pubkey) -> bool {
+    *destination_state != Pubkey::default()
+        && dex_slippage
+            .migration_destinations
+            .iter()
+            .any(|destination| destination == destination_state)
+}
+
+// Moves every lamport and token balance to a new program version's state and vaults.
+// Remaining accounts are (source vault, destination vault) pairs.
+fn migrate_funds(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    destination_state: Pubkey,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let destination_state_info = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    // Both states' lamports move
+    ensure_writable(state_account)?;
+    ensure_writable(destination_state_info)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let dex_slippage = load_state(state_account)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    if !is_migration_destination(&dex_slippage, &destination_state)
+        || *destination_state_info.key != destination_state
+    {
+        msg!("Migration destination {} is not allowlisted", destination_state);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (authority_pda, authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if *authority.key != authority_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Sweep every vault to its counterpart owned by the new program state
+    let vaults = accounts_iter.as_slice();
+    if vaults.len() % 2 != 0 {
+        return Err(ProgramError::NotEnoughAccountKeys);
+    }
+    // The new program's vaults are held by its own authority PDA, not by its state
+    let (destination_authority, _) = Pubkey::find_program_address(&[b"authority"], destination_state_info.owner);
+    for pair in vaults.chunks(2) {
+        let (source_vault, destination_vault) = (&pair[0], &pair[1]);
+        ensure_writable(source_vault)?;
+        ensure_writable(destination_vault)?;
+        let source = TokenAccount::unpack(&source_vault.data.borrow())?;
+        let destination = TokenAccount::unpack(&destination_vault.data.borrow())?;
+        if source.mint != destination.mint || destination.owner != destination_authority {
+            msg!("Destination vault {} does not belong to the new state", destination_vault.key);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        transfer_tokens_signed(
+            token_program,
+            source_vault,
+            destination_vault,
+            authority,
+            source.amount,
+            &[&[b"authority", &[authority_bump]]],
+        )?;
+        msg!("Migrated {} tokens of mint {}", source.amount, source.mint);
+    }
+
+    // Finally move the lamports held by the state account
+    let lamports = state_account.lamports();
+    move_lamports(state_account, destination_state_info, lamports)?;
+    msg!("Migrated {} lamports to {}", lamports, destination_state);
+
+    Ok(())
+}
+
+fn set_paused(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    paused: bool,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // The guardian may pause, but only the owner can unpause
+    if !(paused && is_guardian(&dex_slippage, owner)) {
+        assert_owner(&dex_slippage, owner, accounts)?;
+    }
+
+    // Pause or unpause the bot
+    dex_slippage.paused = paused;
+    write_state(&dex_slippage, &mut state_data)?;
+
+    Ok(())
+}
+
+fn is_guardian(dex_slippage: &DexSlippage, signer: &AccountInfo) -> bool {
+    dex_slippage.guardian != Pubkey::default() && *signer.key == dex_slippage.guardian && signer.is_signer
+}
+
+fn set_guardian(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    guardian: Pubkey,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // A zero key would silently leave the bot without a guardian
+        if guardian == Pubkey::default() {
+            msg!("Guardian cannot be the default pubkey");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Rotate the pause-only guardian
+        dex_slippage.guardian = guardian;
+        Ok(())
+    })
+}
+
+fn set_max_discrepancy_tolerance(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_discrepancy_tolerance: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set the drift tolerated before auto-pausing
+        dex_slippage.max_discrepancy_tolerance = max_discrepancy_tolerance;
+        Ok(())
+    })
+}
+
+fn set_max_profit_delta_mismatch(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_profit_delta_mismatch: u64,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Set how far the balance delta may stray from the reported profit (0 disables the check)
+        dex_slippage.max_profit_delta_mismatch = max_profit_delta_mismatch;
+        Ok(())
+    })
+}
+
+fn set_auto_pause_on_discrepancy(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    enable: bool,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Enable or disable auto-pausing on accounting drift
+        dex_slippage.auto_pause_on_discrepancy = enable;
+        Ok(())
+    })
+}
+
+// Self-check that the accounted trading balance matches the vault's real balance
+fn verify_invariants(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let state_account = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+
+    ensure_writable(state_account)?;
+
+    // Anyone can run the check, so only the authority's own vault counts. A
+    // look-alike account with a lower balance would otherwise pause the bot.
+    validate_token_account(vault, state_account, program_id)?;
+    let vault_account = TokenAccount::unpack(&vault.data.borrow())?;
+    let (authority_pda, _authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+    let vault_ata = get_associated_token_address(&authority_pda, &vault_account.mint);
+    if vault_account.owner != authority_pda || *vault.key != vault_ata {
+        msg!("Vault {} is not the authority's associated token account", vault.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    let vault_balance = vault_account.amount;
+    if let Some(discrepancy) = check_balance_invariant(&mut dex_slippage, vault_balance) {
+        emit_event("balance_discrepancy", &discrepancy)?;
+        write_state(&dex_slippage, &mut state_data)?;
+    } else {
+        msg!("Balance invariant holds: {}", vault_balance);
+    }
+
+    Ok(())
+}
+
+fn check_balance_invariant(dex_slippage: &mut DexSlippage, vault_balance: u64) -> Option<BalanceDiscrepancy> {
+    if dex_slippage.trading_balance_in_tokens == vault_balance {
+        return None;
+    }
+
+    msg!(
+        "Balance discrepancy: accounted {}, actual {}",
+        dex_slippage.trading_balance_in_tokens,
+        vault_balance
+    );
+    // Small rounding drift is reported but doesn't stop the bot
+    let drift = dex_slippage.trading_balance_in_tokens.abs_diff(vault_balance);
+    if dex_slippage.auto_pause_on_discrepancy && drift > dex_slippage.max_discrepancy_tolerance {
+        dex_slippage.paused = true;
+    }
+
+    Some(BalanceDiscrepancy {
+        accounted: dex_slippage.trading_balance_in_tokens,
+        actual: vault_balance,
+        paused: dex_slippage.paused,
+    })
+}
+
+// Sweeps unswept profit from the profit vault to the owner. With `profit_in_sol`
+// the vault holds wrapped SOL: the profit is moved into a temporary wSOL account
+// which is then closed, unwrapping it to native lamports in the owner's wallet.
+fn sweep_profit(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let profit_vault = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+    ensure_writable(profit_vault)?;
+    ensure_writable(destination)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let mut dex_slippage = load_state(state_account)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    let (authority_pda, authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if *authority.key != authority_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let authority_seeds: &[&[u8]] = &[b"authority", &[authority_bump]];
+
+    let amount = dex_slippage.unswept_profit;
+    if amount == 0 {
+        msg!("No profit to sweep");
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    transfer_tokens_signed(
+        token_program,
+        profit_vault,
+        destination,
+        authority,
+        amount,
+        &[authority_seeds],
+    )?;
+
+    if dex_slippage.profit_in_sol {
+        unwrap_sol_to_owner(token_program, destination, owner, authority, authority_seeds)?;
+        msg!("Swept {} profit to the owner as native SOL", amount);
+    } else {
+        msg!("Swept {} profit tokens to the owner", amount);
+    }
+
+    dex_slippage.unswept_profit = 0;
+    store_state(state_account, &dex_slippage)?;
+
+    Ok(())
+}
+
+// Pays accrued fees out of the fee vault to the configured fee recipient.
+// Trading balance and unswept profit are left alone.
+fn withdraw_fees(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let fee_vault = next_account_info(accounts_iter)?;
+    let recipient = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+    ensure_writable(fee_vault)?;
+    ensure_writable(recipient)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let mut dex_slippage = load_state(state_account)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    let (authority_pda, authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if *authority.key != authority_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Fees only ever go to the configured recipient's token account
+    let recipient_account = TokenAccount::unpack(&recipient.data.borrow())?;
+    if dex_slippage.fee_recipient == Pubkey::default() || recipient_account.owner != dex_slippage.fee_recipient {
+        msg!("Account {} does not belong to the fee recipient", recipient.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let amount = dex_slippage.accrued_fees;
+    if amount == 0 {
+        msg!("No fees to withdraw");
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    transfer_tokens_signed(
+        token_program,
+        fee_vault,
+        recipient,
+        authority,
+        amount,
+        &[&[b"authority", &[authority_bump]]],
+    )?;
+
+    dex_slippage.accrued_fees = 0;
+    store_state(state_account, &dex_slippage)?;
+
+    msg!("Withdrew {} in fees to {}", amount, recipient.key);
+
+    Ok(())
+}
+
+fn set_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    fee_bps: u16,
+    fee_recipient: Pubkey,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // A fee with nowhere to go could never be withdrawn
+        if fee_bps > 10_000 || (fee_bps > 0 && fee_recipient == Pubkey::default()) {
+            msg!("Invalid fee {} bps to {}", fee_bps, fee_recipient);
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Set the fee and its recipient
+        dex_slippage.fee_bps = fee_bps;
+        dex_slippage.fee_recipient = fee_recipient;
+        Ok(())
+    })
+}
+
+// Closes a wrapped-SOL account held by the authority into the owner's wallet,
+// unwrapping its balance, along with its rent, to native lamports
+fn unwrap_sol_to_owner(
+    token_program: &AccountInfo,
+    wsol_account: &AccountInfo,
+    owner: &AccountInfo,
+    authority: &AccountInfo,
+    authority_seeds: &[&[u8]],
+) -> ProgramResult {
+    let temporary_account = TokenAccount::unpack(&wsol_account.data.borrow())?;
+    if temporary_account.mint != spl_token::native_mint::id() {
+        msg!("Unwrapping to SOL requires a wrapped SOL account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    invoke_signed(
+        &close_account(token_program.key, wsol_account.key, owner.key, authority.key, &[])?,
+        &[token_program.clone(), wsol_account.clone(), owner.clone(), authority.clone()],
+        &[authority_seeds],
+    )
+}
+
+// Withdraws `amount` tokens from a vault held by the authority PDA. With
+// `unwrap_sol` the destination is a temporary wrapped-SOL account owned by the
+// authority, which is closed afterwards so the owner receives native lamports.
+fn withdraw_tokens(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    unwrap_sol: bool,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let destination = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+    ensure_writable(vault)?;
+    ensure_writable(destination)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let mut dex_slippage = load_state(state_account)?;
+
+    // Ensure the owner matches and actually signed
+    assert_owner(&dex_slippage, owner, accounts)?;
+    check_token_amount(&dex_slippage, amount)?;
+
+    let (authority_pda, authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if *authority.key != authority_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let authority_seeds: &[&[u8]] = &[b"authority", &[authority_bump]];
+
+    // Without unwrapping, tokens may only go to an account the owner controls
+    if !unwrap_sol && TokenAccount::unpack(&destination.data.borrow())?.owner != *owner.key {
+        msg!("Destination {} is not owned by the owner", destination.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    dex_slippage.trading_balance_in_tokens = dex_slippage
+        .trading_balance_in_tokens
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+
+    transfer_tokens_signed(
+        token_program,
+        vault,
+        destination,
+        authority,
+        amount,
+        &[authority_seeds],
+    )?;
+
+    if unwrap_sol {
+        unwrap_sol_to_owner(token_program, destination, owner, authority, authority_seeds)?;
+        msg!("Withdrew {} to the owner as native SOL", amount);
+    } else {
+        msg!("Withdrew {} tokens to {}", amount, destination.key);
+    }
+
+    store_state(state_account, &dex_slippage)?;
+
+    Ok(())
+}
+
+fn set_profit_in_sol(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    profit_in_sol: bool,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // Take profit as native SOL or as tokens
+        dex_slippage.profit_in_sol = profit_in_sol;
+        Ok(())
+    })
+}
+
+fn set_lending_pool(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    lending_pool: Pubkey,
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        // The only account cleanup_failed_mev repays a loan to
+        dex_slippage.lending_pool = lending_pool;
+        Ok(())
+    })
+}
+
+// Records a flashloan taken by an earlier transaction of a multi-transaction
+// bundle. A run recorded here, in a transaction of its own, stays in progress
+// until settle_mev closes it once the bundle went through, or
+// cleanup_failed_mev repays the loan if it didn't.
+fn begin_mev(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    loan_amount: u64,
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let mut dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    if dex_slippage.mev_in_progress {
+        msg!("MEV execution already in progress");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Mark the run as started until its loan is repaid
+    dex_slippage.mev_in_progress = true;
+    dex_slippage.outstanding_loan = loan_amount;
+    write_state(&dex_slippage, &mut state_data)?;
+
+    msg!("Started MEV run with loan: {}", loan_amount);
+
+    Ok(())
+}
+
+// Closes a run recorded by begin_mev whose bundle repaid its own loan, so
+// nothing is transferred
+fn settle_mev(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    update_state(accounts, |dex_slippage| {
+        if !dex_slippage.mev_in_progress {
+            msg!("No MEV execution in progress");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        msg!("Settled MEV run with loan: {}", dex_slippage.outstanding_loan);
+        dex_slippage.outstanding_loan = 0;
+        dex_slippage.mev_in_progress = false;
+        Ok(())
+    })
+}
+
+// Repays the loan of a run recorded by begin_mev from the authority's vault
+// to the configured lending pool, and clears the in-progress flag
+fn cleanup_failed_mev(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+    let authority = next_account_info(accounts_iter)?;
+    let token_program = next_account_info(accounts_iter)?;
+    let vault = next_account_info(accounts_iter)?;
+    let lender = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+    ensure_writable(vault)?;
+    ensure_writable(lender)?;
+
+    // Deserialize state account data, without holding the borrow across CPIs
+    let mut dex_slippage = load_state(state_account)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    // Only a stuck MEV run needs cleaning up
+    if !dex_slippage.mev_in_progress {
+        msg!("No MEV execution in progress");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (authority_pda, authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if *authority.key != authority_pda {
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The vault only ever pays back the pool the loan came from
+    if dex_slippage.lending_pool == Pubkey::default() || *lender.key != dex_slippage.lending_pool {
+        msg!("Lender {} is not the configured lending pool", lender.key);
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // Repay the outstanding flashloan from reserves
+    let outstanding_loan = dex_slippage.outstanding_loan;
+    dex_slippage.trading_balance_in_tokens = dex_slippage
+        .trading_balance_in_tokens
+        .checked_sub(outstanding_loan)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    if outstanding_loan > 0 {
+        transfer_tokens_signed(
+            token_program,
+            vault,
+            lender,
+            authority,
+            outstanding_loan,
+            &[&[b"authority", &[authority_bump]]],
+        )?;
+    }
+    dex_slippage.outstanding_loan = 0;
+    dex_slippage.mev_in_progress = false;
+    store_state(state_account, &dex_slippage)?;
+
+    msg!("Cleaned up failed MEV, repaid loan: {}", outstanding_loan);
+
+    Ok(())
+}
+
+// Read-only dump of the full Borsh-serialized state for backup tooling
+fn export_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let state_account = next_account_info(accounts_iter)?;
+
+    // Deserialize state account data
+    let state_data = state_account.try_borrow_data()?;
+    let dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // A backup has to be restorable, so unlike other events the state is never
+    // compacted. At LEN bytes it stays well inside the per-transaction log limit.
+    let payload = dex_slippage.try_to_vec()?;
+    sol_log_data(&[b"config", &payload]);
+
+    Ok(())
+}
+
+// Restores state from bytes previously emitted by `export_config`
+fn import_config(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    config: &[u8],
+) -> ProgramResult {
+    let accounts_iter = &mut accounts.iter();
+    let owner = next_account_info(accounts_iter)?;
+    let state_account = next_account_info(accounts_iter)?;
+
+    ensure_distinct_owner(owner, state_account)?;
+    ensure_writable(state_account)?;
+
+    // Deserialize state account data
+    let mut state_data = state_account.try_borrow_mut_data()?;
+    let dex_slippage = DexSlippage::from_versioned_slice(&state_data)?;
+
+    // Ensure the owner matches
+    assert_owner(&dex_slippage, owner, accounts)?;
+
+    let mut imported = DexSlippage::try_from_slice(config).map_err(|_| ProgramError::InvalidInstructionData)?;
+    keep_account_state(&mut imported, &dex_slippage);
+    validate_config(&imported)?;
+
+    // An import is held to the same slippage cooldown as the setters
+    if imported.min_slippage_change_interval_slots < dex_slippage.min_slippage_change_interval_slots {
+        msg!("The slippage cooldown can only be lowered through set_slippage_change_interval");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let slippage_changed = imported.slippage_percent != dex_slippage.slippage_percent;
+    if slippage_changed && dex_slippage.min_slippage_change_interval_slots > 0 {
+        record_slippage_change(&mut imported, Clock::get()?.slot)?;
+    }
+
+    // Overwrite the settings
+    write_state(&imported, &mut state_data)?;
+
+    Ok(())
+}
+
+// An import restores settings only. Who controls the account, replay nonces and
+// running stats belong to the account itself, never to the backup it came from.
+fn keep_account_state(imported: &mut DexSlippage, current: &DexSlippage) {
+    imported.owner = current.owner;
+    imported.pending_owner = current.pending_owner;
+    imported.pending_owner_slot = current.pending_owner_slot;
+    imported.guardian = current.guardian;
+    imported.approved_delegate = current.approved_delegate;
+    imported.approval_slot = current.approval_slot;
+    imported.paused = current.paused;
+    imported.trading_balance_in_tokens = current.trading_balance_in_tokens;
+    imported.mev_in_progress = current.mev_in_progress;
+    imported.outstanding_loan = current.outstanding_loan;
+    imported.pending_withdraw_amount = current.pending_withdraw_amount;
+    imported.pending_withdraw_slot = current.pending_withdraw_slot;
+    imported.withdrawn_today = current.withdrawn_today;
+    imported.withdraw_window_start_slot = current.withdraw_window_start_slot;
+    imported.total_profit = current.total_profit;
+    imported.last_profit_nonce = current.last_profit_nonce;
+    imported.unswept_profit = current.unswept_profit;
+    imported.pending_compound = current.pending_compound;
+    imported.accrued_fees = current.accrued_fees;
+    imported.trade_count = current.trade_count;
+    imported.recent_profits = current.recent_profits;
+    imported.recent_profits_next = current.recent_profits_next;
+    imported.attempts_this_slot = current.attempts_this_slot;
+    imported.attempts_slot = current.attempts_slot;
+    imported.cached_plan = current.cached_plan;
+    imported.last_slippage_change_slot = current.last_slippage_change_slot;
+    imported.pending_slippage_change_interval = current.pending_slippage_change_interval;
+    imported.pending_slippage_change_interval_slot = current.pending_slippage_change_interval_slot;
+    imported.consecutive_losses = current.consecutive_losses;
+    imported.breaker_recovery_remaining = current.breaker_recovery_remaining;
+    imported.bundle_snapshot_account = current.bundle_snapshot_account;
+    imported.bundle_snapshot_balance = current.bundle_snapshot_balance;
+    imported.price_history = current.price_history;
+    imported.price_history_next = current.price_history_next;
+}
+
+// Applies the same checks as the individual setters to a full state.
+// Balances, flags and mints carry no constraints of their own.
+fn validate_config(dex_slippage: &DexSlippage) -> ProgramResult {
+    validate_slippage(dex_slippage.slippage_percent)?;
+    validate_liquidity_threshold(dex_slippage.liquidity_threshold)?;
+    validate_owners(&dex_slippage.owner, &dex_slippage.owners, dex_slippage.threshold)?;
+
+    if dex_slippage.log_level > LOG_LEVEL_VERBOSE
+        || dex_slippage.max_oracle_confidence_bps > 10_000
+        || dex_slippage.oracle_band_bps > 10_000
+        || dex_slippage.max_retry_slippage_bps > 10_000
+        || dex_slippage.max_spread_bps > 10_000
+        || dex_slippage.buy_slippage_bps > 10_000
+        || dex_slippage.sell_slippage_bps > 10_000
+        || dex_slippage.fee_bps > 10_000
+        || dex_slippage.max_interleg_deviation_bps > 10_000
+        || dex_slippage.router_fees.iter().any(|(_, fee_bps)| *fee_bps > 10_000)
+        || dex_slippage.max_volatility_slippage_bps > 10_000
+        || dex_slippage.min_volatility_slippage_bps > dex_slippage.max_volatility_slippage_bps
+        || dex_slippage.optimization_iterations > MAX_OPTIMIZATION_ITERATIONS
+        || dex_slippage.arbitrage_iterations > MAX_ARBITRAGE_ITERATIONS
+        || dex_slippage.min_hops > TRIANGULAR_HOPS
+    {
+        msg!("Imported config has out-of-range settings");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // A snapshot taken mid-flashloan must not be restored
+    if dex_slippage.mev_in_progress || dex_slippage.outstanding_loan != 0 {
+        msg!("Imported config has a flashloan in progress");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use solana_program::clock::Epoch;
+    use solana_program::instruction::{AccountMeta, Instruction, InstructionError};
+    use solana_program::program_option::COption;
+    use solana_program::sysvar::rent::Rent;
+    use solana_program_test::{processor, ProgramTest};
+    use solana_sdk::{
+        account::Account,
+        signature::{Keypair, Signer},
+        transaction::{Transaction, TransactionError},
+    };
+    use spl_token::state::Mint;
+
+    // Payload of the first `emit_event`-style log with the given name. Each
+    // sol_log_data field is logged base64-encoded after "Program data: ".
+    fn logged_event(log_messages: &[String], name: &str) -> Option<Vec<u8>> {
+        log_messages.iter().find_map(|log| {
+            let fields = log
+                .strip_prefix("Program data: ")?
+                .split(' ')
+                .map(|field| STANDARD.decode(field).unwrap())
+                .collect::<Vec<_>>();
+            match fields.as_slice() {
+                [event_name, payload] if event_name == name.as_bytes() => Some(payload.clone()),
+                _ => None,
+            }
+        })
+    }
+
+    // State as a real account holds it, at the start of a LEN-sized buffer
+    fn state_account_data(dex_slippage: &DexSlippage) -> Vec<u8> {
+        let mut data = dex_slippage.try_to_vec().unwrap();
+        data.resize(DexSlippage::LEN, 0);
+        data
+    }
+
+    // The owner signer and writable state account the owner-only handlers take
+    struct SetterFixture {
+        program_id: Pubkey,
+        owner: Pubkey,
+        state_account: Pubkey,
+        owner_lamports: u64,
+        state_lamports: u64,
+        owner_data: Vec<u8>,
+        state_data: Vec<u8>,
+    }
+
+    impl SetterFixture {
+        fn new(program_id: Pubkey, owner: Pubkey, state_data: Vec<u8>) -> Self {
+            SetterFixture {
+                program_id,
+                owner,
+                state_account: Pubkey::new_unique(),
+                owner_lamports: 0,
+                state_lamports: 0,
+                owner_data: vec![],
+                state_data,
+            }
+        }
+
+        fn accounts(&mut self) -> Vec<AccountInfo<'_>> {
+            vec![
+                AccountInfo::new(
+                    &self.owner,
+                    true,
+                    false,
+                    &mut self.owner_lamports,
+                    &mut self.owner_data,
+                    &self.program_id,
+                    false,
+                    Epoch::default(),
+                ),
+                AccountInfo::new(
+                    &self.state_account,
+                    false,
+                    true,
+                    &mut self.state_lamports,
+                    &mut self.state_data,
+                    &self.program_id,
+                    false,
+                    Epoch::default(),
+                ),
+            ]
+        }
+    }
+
+    // A router and the writable token accounts a trade moves between
+    struct SwapFixture {
+        program_id: Pubkey,
+        token_program: Pubkey,
+        router: Pubkey,
+        token_in: Pubkey,
+        token_out: Pubkey,
+        router_lamports: u64,
+        token_in_lamports: u64,
+        token_out_lamports: u64,
+        router_data: Vec<u8>,
+        token_in_data: Vec<u8>,
+        token_out_data: Vec<u8>,
+    }
+
+    impl SwapFixture {
+        fn new(program_id: Pubkey, router: Pubkey, token_in_data: Vec<u8>, token_out_data: Vec<u8>) -> Self {
+            SwapFixture {
+                program_id,
+                token_program: spl_token::id(),
+                router,
+                token_in: Pubkey::new_unique(),
+                token_out: Pubkey::new_unique(),
+                router_lamports: 0,
+                token_in_lamports: 0,
+                token_out_lamports: 0,
+                router_data: vec![],
+                token_in_data,
+                token_out_data,
+            }
+        }
+
+        fn accounts(&mut self) -> Vec<AccountInfo<'_>> {
+            vec![
+                AccountInfo::new(
+                    &self.router,
+                    false,
+                    false,
+                    &mut self.router_lamports,
+                    &mut self.router_data,
+                    &self.program_id,
+                    false,
+                    Epoch::default(),
+                ),
+                AccountInfo::new(
+                    &self.token_in,
+                    false,
+                    true,
+                    &mut self.token_in_lamports,
+                    &mut self.token_in_data,
+                    &self.token_program,
+                    false,
+                    Epoch::default(),
+                ),
+                AccountInfo::new(
+                    &self.token_out,
+                    false,
+                    true,
+                    &mut self.token_out_lamports,
+                    &mut self.token_out_data,
+                    &self.token_program,
+                    false,
+                    Epoch::default(),
+                ),
+            ]
+        }
+    }
+
+    fn token_account_data(mint: Pubkey, owner: Pubkey, amount: u64) -> Vec<u8> {
+        let mut data = vec![0u8; TokenAccount::LEN];
+        TokenAccount::pack(
+            TokenAccount {
+                mint,
+                owner,
+                amount,
+                state: AccountState::Initialized,
+                ..TokenAccount::default()
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_initialize() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let token_program = Pubkey::new_unique();
+        let rent_sysvar = Rent::default();
+
+        let mut state_data = vec![0u8; DexSlippage::LEN];
+        let mut rent_data = rent_sysvar.try_to_vec().unwrap();
+        rent_data.resize(Rent::size_of(), 0);
+
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                true,
+                &mut [],
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_program,
+                false,
+                false,
+                &mut [],
+                &mut [],
+                &spl_token::id(),
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let instruction_data = DexSlippage {
+            owner,
+            arb_tx_price: 0,
+            enable_trading: false,
+            token_pair: 0,
+            trading_balance_in_tokens: 0,
+            is_slippage_set: false,
+            slippage_percent: 0,
+            mev_enabled: false,
+            liquidity_threshold: 0,
+            ..DexSlippage::default()
+        }
+        .try_to_vec()
+        .unwrap();
+
+        assert_eq!(
+            initialize(&program_id, &accounts, 0, 0, &instruction_data).is_ok(),
+            true
+        );
+    }
+
+    #[test]
+    fn test_set_slippage() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+
+        let mut state_data = vec![0u8; DexSlippage::LEN];
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                true,
+                &mut [],
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut [],
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(
+            set_slippage(&program_id, &accounts, 5).is_ok(),
+            true
+        );
+    }
+
+    #[test]
+    fn test_enable_mev() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let mut fixture = SetterFixture::new(program_id, owner, state_account_data(&DexSlippage {
+            owner,
+            mev_enabled: false,
+            ..DexSlippage::default()
+        }));
+        let accounts = fixture.accounts();
+
+        assert_eq!(
+            enable_mev(&program_id, &accounts, true).is_ok(),
+            true
+        );
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.mev_enabled, true);
+    }
+
+    #[test]
+    fn test_disable_mev() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let mut fixture = SetterFixture::new(program_id, owner, state_account_data(&DexSlippage {
+            owner,
+            mev_enabled: true,
+            ..DexSlippage::default()
+        }));
+        let accounts = fixture.accounts();
+
+        assert_eq!(
+            enable_mev(&program_id, &accounts, false).is_ok(),
+            true
+        );
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.mev_enabled, false);
+    }
+
+    #[test]
+    fn test_set_liquidity_threshold() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+
+        let mut state_data = vec![0u8; DexSlippage::LEN];
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                true,
+                &mut [],
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut [],
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(
+            set_liquidity_threshold(&program_id, &accounts, 1000).is_ok(),
+            true
+        );
+    }
+
+    #[test]
+    fn test_withdraw_funds() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let receiver = Pubkey::new_unique();
+
+        let mut state_data = vec![0u8; DexSlippage::LEN];
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                true,
+                &mut [],
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut [],
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &receiver,
+                false,
+                true,
+                &mut [],
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(
+            withdraw_funds(&program_id, &accounts).is_ok(),
+            true
+        );
+    }
+
+    #[test]
+    fn test_perform_mev_compounds_profit() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let token_in = Pubkey::new_unique();
+        let token_out = Pubkey::new_unique();
+
+        let pool_a = Pubkey::new_unique();
+        let pool_b = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let mut router_lamports = 0;
+        let mut token_in_lamports = 0;
+        let mut token_out_lamports = 0;
+        let mut pool_a_lamports = 0;
+        let mut pool_b_lamports = 0;
+        let mut router_data = vec![];
+        let mut token_in_data = token_account_data(mint, program_id, 1_000);
+        let mut token_out_data = token_account_data(mint, program_id, 1_000);
+        let mut pool_a_data = token_account_data(mint, router, 50_000);
+        let mut pool_b_data = token_account_data(mint, router, 50_000);
+        let router_info = AccountInfo::new(
+            &router,
+            false,
+            false,
+            &mut router_lamports,
+            &mut router_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let token_in_info = AccountInfo::new(
+            &token_in,
+            false,
+            true,
+            &mut token_in_lamports,
+            &mut token_in_data,
+            &spl_token::id(),
+            false,
+            Epoch::default(),
+        );
+        let token_out_info = AccountInfo::new(
+            &token_out,
+            false,
+            true,
+            &mut token_out_lamports,
+            &mut token_out_data,
+            &spl_token::id(),
+            false,
+            Epoch::default(),
+        );
+        let pool_accounts = vec![
+            AccountInfo::new(
+                &pool_a,
+                false,
+                false,
+                &mut pool_a_lamports,
+                &mut pool_a_data,
+                &spl_token::id(),
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &pool_b,
+                false,
+                false,
+                &mut pool_b_lamports,
+                &mut pool_b_data,
+                &spl_token::id(),
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            trading_balance_in_tokens: 1_000,
+            compound: true,
+            ..DexSlippage::default()
+        };
+
+        // 100 -> 200 flashloan -> 400 intermediate -> 500 out, net profit 300
+        assert_eq!(
+            perform_mev(
+                &mut dex_slippage,
+                &router_info,
+                Some(&pool_accounts[0]),
+                &token_in_info,
+                &token_out_info,
+                &pool_accounts,
+                None,
+                100,
+                0,
+                1
+            )
+            .is_ok(),
+            true
+        );
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 1_300);
+    }
+
+    #[test]
+    fn test_perform_mev_without_compounding() {
+        let mut dex_slippage = DexSlippage {
+            trading_balance_in_tokens: 1_000,
+            compound: false,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(realize_profit(&mut dex_slippage, 300).is_ok(), true);
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 1_000);
+        assert_eq!(dex_slippage.unswept_profit, 300);
+    }
+
+    #[test]
+    fn test_compound_profit_overflow() {
+        let mut dex_slippage = DexSlippage {
+            trading_balance_in_tokens: u64::MAX,
+            compound: true,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(
+            realize_profit(&mut dex_slippage, 1),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+    }
+
+    #[test]
+    fn test_compound_threshold() {
+        let mut dex_slippage = DexSlippage {
+            trading_balance_in_tokens: 1_000,
+            compound: true,
+            compound_threshold: 500,
+            ..DexSlippage::default()
+        };
+
+        // Small profits are parked while they stay at or under the threshold
+        assert_eq!(realize_profit(&mut dex_slippage, 200).is_ok(), true);
+        assert_eq!(realize_profit(&mut dex_slippage, 300).is_ok(), true);
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 1_000);
+        assert_eq!(dex_slippage.pending_compound, 500);
+
+        // Crossing it compounds everything accumulated so far
+        assert_eq!(realize_profit(&mut dex_slippage, 1).is_ok(), true);
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 1_501);
+        assert_eq!(dex_slippage.pending_compound, 0);
+        assert_eq!(dex_slippage.unswept_profit, 0);
+    }
+
+    fn failed_mev_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        match instruction_data.split_first() {
+            Some((0, loan_amount)) => {
+                let loan_amount = u64::try_from_slice(loan_amount).map_err(|_| ProgramError::InvalidInstructionData)?;
+                begin_mev(program_id, accounts, loan_amount)
+            }
+            Some((1, [])) => cleanup_failed_mev(program_id, accounts),
+            Some((2, [])) => settle_mev(program_id, accounts),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_failed_mev() {
+        let program_id = Pubkey::new_unique();
+        let owner = Keypair::new();
+        let state_account = Pubkey::new_unique();
+        let vault = Pubkey::new_unique();
+        let lender = Pubkey::new_unique();
+        let stranger = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let rent = Rent::default();
+
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(failed_mev_processor),
+        );
+        let state_data = state_account_data(&DexSlippage {
+            owner: owner.pubkey(),
+            trading_balance_in_tokens: 5_000,
+            lending_pool: lender,
+            ..DexSlippage::default()
+        });
+        program_test.add_account(
+            state_account,
+            Account {
+                lamports: rent.minimum_balance(state_data.len()),
+                data: state_data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        for (key, token_owner, amount) in [
+            (vault, authority, 5_000),
+            (lender, Pubkey::new_unique(), 0),
+            (stranger, Pubkey::new_unique(), 0),
+        ] {
+            program_test.add_account(
+                key,
+                Account {
+                    lamports: rent.minimum_balance(TokenAccount::LEN),
+                    data: token_account_data(mint, token_owner, amount),
+                    owner: spl_token::id(),
+                    ..Account::default()
+                },
+            );
+        }
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let begin = |loan_amount: u64| [&[0][..], &loan_amount.to_le_bytes()].concat();
+        let steps = [
+            (begin(2_000), None),
+            (vec![1], Some(stranger)),
+            (vec![1], Some(lender)),
+            (vec![1], Some(lender)),
+            (begin(1_000), None),
+            (vec![2], None),
+        ];
+        let mut blockhash = recent_blockhash;
+        let mut results = vec![];
+        for (instruction_data, repay_to) in steps {
+            let mut accounts = vec![
+                AccountMeta::new_readonly(owner.pubkey(), true),
+                AccountMeta::new(state_account, false),
+            ];
+            if let Some(repay_to) = repay_to {
+                accounts.extend([
+                    AccountMeta::new_readonly(authority, false),
+                    AccountMeta::new_readonly(spl_token::id(), false),
+                    AccountMeta::new(vault, false),
+                    AccountMeta::new(repay_to, false),
+                ]);
+            }
+            // A fresh blockhash for each step so the repeated cleanup isn't deduplicated
+            blockhash = banks_client.get_new_latest_blockhash(&blockhash).await.unwrap();
+            let transaction = Transaction::new_signed_with_payer(
+                &[Instruction::new_with_bytes(program_id, &instruction_data, accounts)],
+                Some(&payer.pubkey()),
+                &[&payer, &owner],
+                blockhash,
+            );
+            results.push(banks_client.process_transaction(transaction).await);
+        }
+
+        // The recorded run survives its own transaction, a lender other than the
+        // configured pool isn't paid, and the pool is repaid for real
+        assert_eq!(results[0].is_ok(), true);
+        assert_eq!(
+            results[1].as_ref().unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::InvalidArgument)
+        );
+        assert_eq!(results[2].is_ok(), true);
+
+        // A second cleanup finds nothing to do
+        assert_eq!(
+            results[3].as_ref().unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::InvalidAccountData)
+        );
+
+        // A run whose bundle went through is settled without paying anything
+        assert_eq!(results[4].is_ok(), true);
+        assert_eq!(results[5].is_ok(), true);
+
+        let state = banks_client.get_account(state_account).await.unwrap().unwrap();
+        let dex_slippage = DexSlippage::from_versioned_slice(&state.data).unwrap();
+        assert_eq!(dex_slippage.mev_in_progress, false);
+        assert_eq!(dex_slippage.outstanding_loan, 0);
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 3_000);
+
+        let vault_account = banks_client.get_account(vault).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&vault_account.data).unwrap().amount, 3_000);
+        let lender_account = banks_client.get_account(lender).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&lender_account.data).unwrap().amount, 2_000);
+        let stranger_account = banks_client.get_account(stranger).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&stranger_account.data).unwrap().amount, 0);
+    }
+
+    #[test]
+    fn test_initialize_rejects_fake_rent_sysvar() {
+        let program_id = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let system_program = solana_program::system_program::id();
+        let fake_rent = Pubkey::new_unique();
+
+        let mut payer_lamports = 1_000_000_000;
+        let mut state_lamports = 0;
+        let mut system_lamports = 0;
+        let mut rent_lamports = 0;
+        let mut payer_data = vec![];
+        let mut state_data = vec![0u8; DexSlippage::LEN];
+        let mut system_data = vec![];
+        // A zero-filled "sysvar" would report a zero minimum balance
+        let mut rent_data = vec![0u8; Rent::size_of()];
+        let accounts = vec![
+            AccountInfo::new(
+                &payer,
+                true,
+                true,
+                &mut payer_lamports,
+                &mut payer_data,
+                &system_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                true,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &system_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &system_program,
+                false,
+                false,
+                &mut system_lamports,
+                &mut system_data,
+                &system_program,
+                true,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &fake_rent,
+                false,
+                false,
+                &mut rent_lamports,
+                &mut rent_data,
+                &system_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let instruction_data = DexSlippage {
+            owner: payer,
+            ..DexSlippage::default()
+        }
+        .try_to_vec()
+        .unwrap();
+
+        assert_eq!(
+            initialize(&program_id, &accounts, 0, 0, &instruction_data),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_accept_ownership_within_window() {
+        let owner = Pubkey::new_unique();
+        let new_owner = Pubkey::new_unique();
+        let mut dex_slippage = DexSlippage {
+            owner,
+            pending_owner: Some(new_owner),
+            pending_owner_slot: 100,
+            pending_owner_window_slots: 50,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(accept_pending_owner(&mut dex_slippage, &new_owner, 150).is_ok(), true);
+        assert_eq!(dex_slippage.owner, new_owner);
+        assert_eq!(dex_slippage.pending_owner, None);
+    }
+
+    #[test]
+    fn test_accept_ownership_after_expiry() {
+        let owner = Pubkey::new_unique();
+        let new_owner = Pubkey::new_unique();
+        let mut dex_slippage = DexSlippage {
+            owner,
+            pending_owner: Some(new_owner),
+            pending_owner_slot: 100,
+            pending_owner_window_slots: 50,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(
+            accept_pending_owner(&mut dex_slippage, &new_owner, 151),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(dex_slippage.owner, owner);
+        // The expired proposal clears itself
+        assert_eq!(dex_slippage.pending_owner, None);
+    }
+
+    #[test]
+    fn test_get_price_from_router_applies_slippage() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let token_in = Pubkey::new_unique();
+        let token_out = Pubkey::new_unique();
+
+        let mut router_lamports = 0;
+        let mut token_in_lamports = 0;
+        let mut token_out_lamports = 0;
+        let mut router_data = vec![];
+        let mut token_in_data = token_account_data(Pubkey::new_unique(), router, 1_000);
+        let mut token_out_data = token_account_data(Pubkey::new_unique(), router, 1_000);
+        let router_info = AccountInfo::new(
+            &router,
+            false,
+            false,
+            &mut router_lamports,
+            &mut router_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let token_in_info = AccountInfo::new(
+            &token_in,
+            false,
+            false,
+            &mut token_in_lamports,
+            &mut token_in_data,
+            &spl_token::id(),
+            false,
+            Epoch::default(),
+        );
+        let token_out_info = AccountInfo::new(
+            &token_out,
+            false,
+            false,
+            &mut token_out_lamports,
+            &mut token_out_data,
+            &spl_token::id(),
+            false,
+            Epoch::default(),
+        );
+
+        let dex_slippage = DexSlippage {
+            slippage_percent: 5,
+            ..DexSlippage::default()
+        };
+
+        let quote = get_price_from_router(&dex_slippage, &router_info, &token_in_info, &token_out_info, 100).unwrap();
+        assert_eq!(quote.expected > 0, true);
+        assert_eq!(quote.min_out, quote.expected * 95 / 100);
+        assert_eq!(apply_slippage_bps(10_000, 0), 10_000);
+        assert_eq!(apply_slippage_bps(10_000, 10_000), 0);
+    }
+
+    #[test]
+    fn test_multisig_threshold() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let co_owner = Pubkey::new_unique();
+        let outsider = Pubkey::new_unique();
+
+        let mut owners = [Pubkey::default(); MAX_OWNERS];
+        owners[0] = owner;
+        owners[1] = co_owner;
+        let dex_slippage = DexSlippage {
+            owner,
+            owners,
+            threshold: 2,
+            ..DexSlippage::default()
+        };
+
+        let mut owner_lamports = 0;
+        let mut co_owner_lamports = 0;
+        let mut outsider_lamports = 0;
+        let mut owner_data = vec![];
+        let mut co_owner_data = vec![];
+        let mut outsider_data = vec![];
+        let owner_info = AccountInfo::new(
+            &owner,
+            true,
+            false,
+            &mut owner_lamports,
+            &mut owner_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let co_owner_info = AccountInfo::new(
+            &co_owner,
+            true,
+            false,
+            &mut co_owner_lamports,
+            &mut co_owner_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let outsider_info = AccountInfo::new(
+            &outsider,
+            true,
+            false,
+            &mut outsider_lamports,
+            &mut outsider_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+
+        // Both listed owners signed
+        let accounts = vec![owner_info.clone(), co_owner_info];
+        assert_eq!(assert_owner(&dex_slippage, &accounts[0], &accounts).is_ok(), true);
+
+        // A signature from an unlisted key doesn't count
+        let accounts = vec![owner_info, outsider_info];
+        assert_eq!(
+            assert_owner(&dex_slippage, &accounts[0], &accounts),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    #[test]
+    fn test_single_owner_is_threshold_one() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let dex_slippage = DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        };
+
+        let mut owner_lamports = 0;
+        let mut owner_data = vec![];
+        let accounts = vec![AccountInfo::new(
+            &owner,
+            true,
+            false,
+            &mut owner_lamports,
+            &mut owner_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        )];
+
+        assert_eq!(assert_owner(&dex_slippage, &accounts[0], &accounts).is_ok(), true);
+    }
+
+    #[test]
+    fn test_profit_threshold_comparison() {
+        // Triggers exactly at the configured threshold
+        assert_eq!(meets_profit_threshold(1000, 1000), true);
+        assert_eq!(meets_profit_threshold(999, 1000), false);
+        assert_eq!(meets_profit_threshold(5000, 2500), true);
+
+        // 1001 & 1000 == 1000 under the old bitwise check, but 1001 < 2000
+        assert_eq!(meets_profit_threshold(1001, 2000), false);
+        // 1024 & 1000 != 1000 under the old check, yet clears a 1000 threshold
+        assert_eq!(meets_profit_threshold(1024, 1000), true);
+
+        assert_eq!(meets_profit_threshold(-1, 0), false);
+    }
+
+    #[test]
+    fn test_signed_profit_threshold() {
+        // Negative threshold tolerates losses down to it
+        assert_eq!(meets_profit_threshold(-50, -100), true);
+        assert_eq!(meets_profit_threshold(-100, -100), true);
+        assert_eq!(meets_profit_threshold(-101, -100), false);
+
+        // Zero threshold accepts break-even but no loss
+        assert_eq!(meets_profit_threshold(0, 0), true);
+        assert_eq!(meets_profit_threshold(-1, 0), false);
+
+        // Positive threshold rejects losses and small profits
+        assert_eq!(meets_profit_threshold(-1_000, 500), false);
+        assert_eq!(meets_profit_threshold(499, 500), false);
+        assert_eq!(meets_profit_threshold(500, 500), true);
+    }
+
+    #[test]
+    fn test_reserve_snapshots_before_and_after() {
+        let pool_a = Pubkey::new_unique();
+        let pool_b = Pubkey::new_unique();
+        let mint_a = Pubkey::new_unique();
+        let mint_b = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+
+        let mut pool_a_lamports = 0;
+        let mut pool_b_lamports = 0;
+        let mut pool_a_data = token_account_data(mint_a, router, 10_000);
+        let mut pool_b_data = token_account_data(mint_b, router, 20_000);
+        let pool_accounts = vec![
+            AccountInfo::new(
+                &pool_a,
+                false,
+                false,
+                &mut pool_a_lamports,
+                &mut pool_a_data,
+                &spl_token::id(),
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &pool_b,
+                false,
+                false,
+                &mut pool_b_lamports,
+                &mut pool_b_data,
+                &spl_token::id(),
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let before = snapshot_reserves(&pool_accounts, SNAPSHOT_BEFORE_MEV).unwrap();
+
+        // Simulate the swaps moving the pool
+        TokenAccount::pack(
+            TokenAccount {
+                mint: mint_a,
+                owner: router,
+                amount: 11_000,
+                state: AccountState::Initialized,
+                ..TokenAccount::default()
+            },
+            &mut pool_accounts[0].data.borrow_mut(),
+        )
+        .unwrap();
+        TokenAccount::pack(
+            TokenAccount {
+                mint: mint_b,
+                owner: router,
+                amount: 18_200,
+                state: AccountState::Initialized,
+                ..TokenAccount::default()
+            },
+            &mut pool_accounts[1].data.borrow_mut(),
+        )
+        .unwrap();
+
+        let after = snapshot_reserves(&pool_accounts, SNAPSHOT_AFTER_MEV).unwrap();
+
+        assert_eq!(before, ReserveSnapshot { stage: SNAPSHOT_BEFORE_MEV, reserve_a: 10_000, reserve_b: 20_000 });
+        assert_eq!(after, ReserveSnapshot { stage: SNAPSHOT_AFTER_MEV, reserve_a: 11_000, reserve_b: 18_200 });
+        assert_ne!(before, after);
+
+        // A missing pool account is a clear error rather than a panic
+        assert_eq!(
+            snapshot_reserves(&pool_accounts[..1], SNAPSHOT_BEFORE_MEV),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+    }
+
+    #[test]
+    fn test_instant_small_withdrawal() {
+        let mut dex_slippage = DexSlippage {
+            withdraw_delay_slots: 100,
+            instant_withdraw_limit: 1_000,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(queue_withdrawal(&mut dex_slippage, 500, 10), Ok(true));
+        assert_eq!(dex_slippage.pending_withdraw_amount, 0);
+    }
+
+    #[test]
+    fn test_delayed_large_withdrawal() {
+        let mut dex_slippage = DexSlippage {
+            withdraw_delay_slots: 100,
+            instant_withdraw_limit: 1_000,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(queue_withdrawal(&mut dex_slippage, 5_000, 10), Ok(false));
+        assert_eq!(dex_slippage.pending_withdraw_amount, 5_000);
+
+        // Still timelocked
+        assert_eq!(
+            release_withdrawal(&mut dex_slippage, 109),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        assert_eq!(release_withdrawal(&mut dex_slippage, 110), Ok(5_000));
+        assert_eq!(dex_slippage.pending_withdraw_amount, 0);
+    }
+
+    fn init_vault_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let mint = Pubkey::try_from_slice(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+        init_vault(program_id, accounts, mint)
+    }
+
+    #[tokio::test]
+    async fn test_init_vault_is_idempotent() {
+        let program_id = Pubkey::new_unique();
+        let owner = Keypair::new();
+        let state_account = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let vault = get_associated_token_address(&authority, &mint);
+        let rent = Rent::default();
+
+        // ProgramTest loads the SPL token and associated token account programs by default
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(init_vault_processor),
+        );
+        let state_data = state_account_data(&DexSlippage {
+            owner: owner.pubkey(),
+            ..DexSlippage::default()
+        });
+        program_test.add_account(
+            state_account,
+            Account {
+                lamports: rent.minimum_balance(state_data.len()),
+                data: state_data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            owner.pubkey(),
+            Account {
+                lamports: 1_000_000_000,
+                ..Account::default()
+            },
+        );
+        let mut mint_data = vec![0u8; Mint::LEN];
+        Mint::pack(
+            Mint {
+                decimals: 6,
+                is_initialized: true,
+                ..Mint::default()
+            },
+            &mut mint_data,
+        )
+        .unwrap();
+        program_test.add_account(
+            mint,
+            Account {
+                lamports: rent.minimum_balance(Mint::LEN),
+                data: mint_data,
+                owner: spl_token::id(),
+                ..Account::default()
+            },
+        );
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let init_vault_transaction = |blockhash| {
+            Transaction::new_signed_with_payer(
+                &[Instruction::new_with_bytes(
+                    program_id,
+                    &mint.to_bytes(),
+                    vec![
+                        AccountMeta::new(owner.pubkey(), true),
+                        AccountMeta::new_readonly(state_account, false),
+                        AccountMeta::new_readonly(authority, false),
+                        AccountMeta::new(vault, false),
+                        AccountMeta::new_readonly(mint, false),
+                        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+                        AccountMeta::new_readonly(spl_token::id(), false),
+                        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+                    ],
+                )],
+                Some(&payer.pubkey()),
+                &[&payer, &owner],
+                blockhash,
+            )
+        };
+
+        // First call creates the vault
+        assert_eq!(banks_client.get_account(vault).await.unwrap().is_none(), true);
+        assert_eq!(banks_client.process_transaction(init_vault_transaction(recent_blockhash)).await.is_ok(), true);
+        let created = banks_client.get_account(vault).await.unwrap().unwrap();
+        assert_eq!(created.owner, spl_token::id());
+        let vault_account = TokenAccount::unpack(&created.data).unwrap();
+        assert_eq!(vault_account.owner, authority);
+        assert_eq!(vault_account.mint, mint);
+
+        // Once the vault exists, a second call is a no-op. A fresh blockhash keeps
+        // the identical transaction from being deduplicated.
+        let blockhash = banks_client.get_new_latest_blockhash(&recent_blockhash).await.unwrap();
+        assert_eq!(banks_client.process_transaction(init_vault_transaction(blockhash)).await.is_ok(), true);
+        assert_eq!(banks_client.get_account(vault).await.unwrap().unwrap(), created);
+    }
+
+    #[test]
+    fn test_arbitrage_prefers_profitable_reverse_direction() {
+        let amount = 10_000;
+
+        // Forward cycle loses money, only the reverse cycle returns more than it started with
+        let forward_out = 9_800;
+        let reverse_out = 10_250;
+
+        assert_eq!(forward_out < amount && reverse_out > amount, true);
+        assert_eq!(choose_direction(forward_out, reverse_out), ArbitrageDirection::Reverse);
+        assert_eq!(choose_direction(reverse_out, forward_out), ArbitrageDirection::Forward);
+        assert_eq!(choose_direction(amount, amount), ArbitrageDirection::Forward);
+    }
+
+    #[test]
+    fn test_replayed_nonce_does_not_double_count_profit() {
+        let mut dex_slippage = DexSlippage::default();
+
+        assert_eq!(record_profit(&mut dex_slippage, 300, 7), Ok(true));
+        assert_eq!(dex_slippage.total_profit, 300);
+
+        // Retrying the same execution doesn't count it again
+        assert_eq!(record_profit(&mut dex_slippage, 300, 7), Ok(false));
+        assert_eq!(dex_slippage.total_profit, 300);
+
+        // Nor does an older nonce
+        assert_eq!(record_profit(&mut dex_slippage, 300, 6), Ok(false));
+        assert_eq!(dex_slippage.total_profit, 300);
+
+        assert_eq!(record_profit(&mut dex_slippage, 200, 8), Ok(true));
+        assert_eq!(dex_slippage.total_profit, 500);
+        assert_eq!(dex_slippage.last_profit_nonce, 8);
+    }
+
+    #[test]
+    fn test_verbose_logs_suppressed_when_quiet() {
+        let quiet = DexSlippage {
+            log_level: LOG_LEVEL_QUIET,
+            ..DexSlippage::default()
+        };
+        let verbose = DexSlippage {
+            log_level: LOG_LEVEL_VERBOSE,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(is_verbose(&quiet), false);
+        assert_eq!(is_verbose(&verbose), true);
+    }
+
+    fn debug_log_processor(
+        _program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        _instruction_data: &[u8],
+    ) -> ProgramResult {
+        let dex_slippage = load_state(&accounts[0])?;
+        debug_msg!(dex_slippage, "Intermediate figure: {}", 42);
+        msg!("Outcome: done");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_debug_logs_follow_log_level() {
+        let program_id = Pubkey::new_unique();
+        let quiet_state = Pubkey::new_unique();
+        let verbose_state = Pubkey::new_unique();
+        let rent = Rent::default();
+
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(debug_log_processor),
+        );
+        for (key, log_level) in [(quiet_state, LOG_LEVEL_QUIET), (verbose_state, LOG_LEVEL_VERBOSE)] {
+            let state_data = state_account_data(&DexSlippage {
+                log_level,
+                ..DexSlippage::default()
+            });
+            program_test.add_account(
+                key,
+                Account {
+                    lamports: rent.minimum_balance(state_data.len()),
+                    data: state_data,
+                    owner: program_id,
+                    ..Account::default()
+                },
+            );
+        }
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let mut logs = vec![];
+        for state_account in [quiet_state, verbose_state] {
+            let transaction = Transaction::new_signed_with_payer(
+                &[Instruction::new_with_bytes(
+                    program_id,
+                    &[],
+                    vec![AccountMeta::new_readonly(state_account, false)],
+                )],
+                Some(&payer.pubkey()),
+                &[&payer],
+                recent_blockhash,
+            );
+            let result = banks_client.process_transaction_with_metadata(transaction).await.unwrap();
+            assert_eq!(result.result.is_ok(), true);
+            logs.push(result.metadata.unwrap().log_messages);
+        }
+
+        // Outcomes are always logged, intermediate figures only when verbose
+        let logged = |log_messages: &Vec<String>, message: &str| {
+            log_messages.iter().any(|log| log == &format!("Program log: {}", message))
+        };
+        assert_eq!(logged(&logs[0], "Outcome: done"), true);
+        assert_eq!(logged(&logs[0], "Intermediate figure: 42"), false);
+        assert_eq!(logged(&logs[1], "Outcome: done"), true);
+        assert_eq!(logged(&logs[1], "Intermediate figure: 42"), true);
+    }
+
+    #[test]
+    fn test_migrate_funds_to_allowlisted_destination() {
+        let program_id = Pubkey::new_unique();
+        let new_program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let token_program = spl_token::id();
+        let system_program = solana_program::system_program::id();
+        let destination_state = Pubkey::new_unique();
+        let unlisted_state = Pubkey::new_unique();
+        let (new_authority, _) = Pubkey::find_program_address(&[b"authority"], &new_program_id);
+        let mint = Pubkey::new_unique();
+        let source_vault = Pubkey::new_unique();
+        let state_held_vault = Pubkey::new_unique();
+        let destination_vault = Pubkey::new_unique();
+
+        let mut destinations = [Pubkey::default(); MAX_MIGRATION_DESTINATIONS];
+        destinations[0] = destination_state;
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            migration_destinations: destinations,
+            ..DexSlippage::default()
+        });
+        let mut owner_lamports = 0;
+        let mut state_lamports = 5_000_000;
+        let mut authority_lamports = 0;
+        let mut token_lamports = 0;
+        let mut destination_lamports = 0;
+        let mut owner_data = vec![];
+        let mut authority_data = vec![];
+        let mut token_data = vec![];
+        let mut destination_data = vec![];
+        let mut source_vault_lamports = 0;
+        let mut state_held_vault_lamports = 0;
+        let mut destination_vault_lamports = 0;
+        let mut source_vault_data = token_account_data(mint, authority, 700);
+        // Owned by the new state itself rather than the new program's authority
+        let mut state_held_vault_data = token_account_data(mint, destination_state, 0);
+        let mut destination_vault_data = token_account_data(mint, new_authority, 0);
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &system_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &authority,
+                false,
+                false,
+                &mut authority_lamports,
+                &mut authority_data,
+                &system_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_program,
+                false,
+                false,
+                &mut token_lamports,
+                &mut token_data,
+                &system_program,
+                true,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &destination_state,
+                false,
+                true,
+                &mut destination_lamports,
+                &mut destination_data,
+                &new_program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &source_vault,
+                false,
+                true,
+                &mut source_vault_lamports,
+                &mut source_vault_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_held_vault,
+                false,
+                true,
+                &mut state_held_vault_lamports,
+                &mut state_held_vault_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &destination_vault,
+                false,
+                true,
+                &mut destination_vault_lamports,
+                &mut destination_vault_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+        let with_destination_vault = |destination_vault: usize| {
+            let mut migration_accounts = accounts[..6].to_vec();
+            migration_accounts.push(accounts[destination_vault].clone());
+            migration_accounts
+        };
+
+        // An unlisted destination is rejected before anything moves
+        assert_eq!(
+            migrate_funds(&program_id, &with_destination_vault(7), unlisted_state),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(accounts[1].lamports(), 5_000_000);
+
+        // So is a vault the new program's authority can't move
+        assert_eq!(
+            migrate_funds(&program_id, &with_destination_vault(6), destination_state),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(accounts[1].lamports(), 5_000_000);
+
+        // Both states and both vaults have to be passed writable
+        for read_only in [1, 4, 5, 6] {
+            let mut migration_accounts = with_destination_vault(7);
+            migration_accounts[read_only].is_writable = false;
+            assert_eq!(
+                migrate_funds(&program_id, &migration_accounts, destination_state),
+                Err(ProgramError::InvalidArgument)
+            );
+        }
+        assert_eq!(accounts[1].lamports(), 5_000_000);
+
+        assert_eq!(
+            migrate_funds(&program_id, &with_destination_vault(7), destination_state).is_ok(),
+            true
+        );
+        assert_eq!(accounts[1].lamports(), 0);
+        assert_eq!(accounts[4].lamports(), 5_000_000);
+    }
+
+    #[test]
+    fn test_zero_amount_rejected_unless_allowed() {
+        let strict = DexSlippage::default();
+        let permissive = DexSlippage {
+            allow_zero_amount: true,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(check_token_amount(&strict, 0), Err(ProgramError::InvalidArgument));
+        assert_eq!(check_token_amount(&strict, 1).is_ok(), true);
+        assert_eq!(check_token_amount(&permissive, 0).is_ok(), true);
+    }
+
+    #[test]
+    fn test_get_price_rejects_pool_for_other_pair() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+        let pool_in = Pubkey::new_unique();
+        let pool_out = Pubkey::new_unique();
+        let base_mint = Pubkey::new_unique();
+        let quote_mint = Pubkey::new_unique();
+        let other_mint = Pubkey::new_unique();
+
+        let dex_slippage = DexSlippage {
+            base_mint,
+            quote_mint,
+            ..DexSlippage::default()
+        };
+
+        let mut router_lamports = 0;
+        let mut pool_in_lamports = 0;
+        let mut pool_out_lamports = 0;
+        let mut router_data = vec![];
+        let mut pool_in_data = token_account_data(base_mint, router, 10_000);
+        let mut pool_out_data = token_account_data(other_mint, router, 10_000);
+        let accounts = vec![
+            AccountInfo::new(
+                &router,
+                false,
+                false,
+                &mut router_lamports,
+                &mut router_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &pool_in,
+                false,
+                false,
+                &mut pool_in_lamports,
+                &mut pool_in_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &pool_out,
+                false,
+                false,
+                &mut pool_out_lamports,
+                &mut pool_out_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(
+            get_price_from_router(&dex_slippage, &accounts[0], &accounts[1], &accounts[2], 100),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        // The registered pair quotes in either direction
+        assert_eq!(validate_pool_mints(&dex_slippage, &base_mint, &quote_mint).is_ok(), true);
+        assert_eq!(validate_pool_mints(&dex_slippage, &quote_mint, &base_mint).is_ok(), true);
+        assert_eq!(
+            validate_pool_mints(&dex_slippage, &base_mint, &other_mint),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_retry_slippage_widening_schedule() {
+        let dex_slippage = DexSlippage {
+            slippage_percent: 1,
+            retry_slippage_step_bps: 25,
+            max_retry_slippage_bps: 300,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(effective_slippage_bps(&dex_slippage, 0), 100);
+        assert_eq!(effective_slippage_bps(&dex_slippage, 1), 125);
+        assert_eq!(effective_slippage_bps(&dex_slippage, 2), 150);
+        assert_eq!(effective_slippage_bps(&dex_slippage, 4), 200);
+        assert_eq!(apply_slippage_bps(10_000, 125), 9_875);
+    }
+
+    #[test]
+    fn test_retry_slippage_cap() {
+        let dex_slippage = DexSlippage {
+            slippage_percent: 1,
+            retry_slippage_step_bps: 100,
+            max_retry_slippage_bps: 300,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(effective_slippage_bps(&dex_slippage, 2), 300);
+        assert_eq!(effective_slippage_bps(&dex_slippage, 50), 300);
+        assert_eq!(effective_slippage_bps(&dex_slippage, u8::MAX), 300);
+    }
+
+    #[test]
+    fn test_verify_invariants_detects_discrepancy() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let state_account = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let vault = get_associated_token_address(&authority, &mint);
+        let fake_vault = Pubkey::new_unique();
+
+        // Accounting says 5000 but the vault only holds 4000
+        let mut state_data = state_account_data(&DexSlippage {
+            trading_balance_in_tokens: 5_000,
+            auto_pause_on_discrepancy: true,
+            ..DexSlippage::default()
+        });
+        let mut state_lamports = 0;
+        let mut vault_lamports = 0;
+        let mut vault_data = token_account_data(mint, authority, 4_000);
+        let mut fake_vault_lamports = 0;
+        let mut fake_vault_data = token_account_data(mint, Pubkey::new_unique(), 0);
+        let accounts = vec![
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &vault,
+                false,
+                false,
+                &mut vault_lamports,
+                &mut vault_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &fake_vault,
+                false,
+                false,
+                &mut fake_vault_lamports,
+                &mut fake_vault_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        // An empty look-alike vault can't be used to pause the bot
+        assert_eq!(
+            verify_invariants(&program_id, &[accounts[0].clone(), accounts[2].clone()]),
+            Err(ProgramError::InvalidArgument)
+        );
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.paused, false);
+
+        assert_eq!(verify_invariants(&program_id, &accounts[..2]).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.paused, true);
+
+        let mut dex_slippage = DexSlippage {
+            trading_balance_in_tokens: 5_000,
+            ..DexSlippage::default()
+        };
+        assert_eq!(
+            check_balance_invariant(&mut dex_slippage, 4_000),
+            Some(BalanceDiscrepancy { accounted: 5_000, actual: 4_000, paused: false })
+        );
+        assert_eq!(check_balance_invariant(&mut dex_slippage, 5_000), None);
+    }
+
+    #[test]
+    fn test_low_confidence_oracle_rejected() {
+        let program_id = Pubkey::new_unique();
+        let oracle = Pubkey::new_unique();
+        let dex_slippage = DexSlippage {
+            max_oracle_confidence_bps: 100,
+            ..DexSlippage::default()
+        };
+
+        // +/- 5% confidence on a 100_000 price
+        let mut oracle_lamports = 0;
+        let mut oracle_data = OraclePrice {
+            price: 100_000,
+            confidence: 5_000,
+        }
+        .try_to_vec()
+        .unwrap();
+        let accounts = vec![
+            AccountInfo::new(
+                &oracle,
+                false,
+                false,
+                &mut oracle_lamports,
+                &mut oracle_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let oracle_price = read_oracle_price(&dex_slippage, &accounts[0]).unwrap();
+        assert_eq!(
+            check_oracle_confidence(&dex_slippage, &oracle_price),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        // A tight +/- 0.5% reading is accepted
+        let tight = OraclePrice {
+            price: 100_000,
+            confidence: 500,
+        };
+        assert_eq!(check_oracle_confidence(&dex_slippage, &tight).is_ok(), true);
+
+        // The check can't be skipped by leaving the oracle out. It fails before
+        // any pool is read, so the oracle account stands in for the pools.
+        let res = calculate_arbitrage(
+            &dex_slippage,
+            &accounts[0],
+            &accounts[0],
+            &accounts[0],
+            &accounts[0],
+            &accounts[0],
+            &accounts[0],
+            &[],
+            1_000,
+        );
+        assert_eq!(res, Err(ProgramError::NotEnoughAccountKeys));
+
+        // Nor by passing a price feed from another program
+        let mut pinned = DexSlippage {
+            oracle_program: Pubkey::new_unique(),
+            ..DexSlippage::default()
+        };
+        assert_eq!(read_oracle_price(&pinned, &accounts[0]), Err(ProgramError::IncorrectProgramId));
+        pinned.oracle_program = program_id;
+        assert_eq!(read_oracle_price(&pinned, &accounts[0]), Ok(oracle_price));
+    }
+
+    // Accounts for exercising process_instruction's transfer against the real SPL token program
+    struct TransferHarness {
+        program_id: Pubkey,
+        owner: Keypair,
+        authority: Keypair,
+        state_account: Pubkey,
+        token_account_from: Pubkey,
+        token_account_to: Pubkey,
+    }
+
+    fn transfer_program_test() -> (ProgramTest, TransferHarness) {
+        let harness = TransferHarness {
+            program_id: Pubkey::new_unique(),
+            owner: Keypair::new(),
+            authority: Keypair::new(),
+            state_account: Pubkey::new_unique(),
+            token_account_from: Pubkey::new_unique(),
+            token_account_to: Pubkey::new_unique(),
+        };
+        let mint = Pubkey::new_unique();
+        let rent = Rent::default();
+
+        // ProgramTest loads the SPL token program by default
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            harness.program_id,
+            processor!(process_instruction),
+        );
+
+        let state_data = state_account_data(&DexSlippage {
+            owner: harness.owner.pubkey(),
+            ..DexSlippage::default()
+        });
+        program_test.add_account(
+            harness.state_account,
+            Account {
+                lamports: rent.minimum_balance(state_data.len()),
+                data: state_data,
+                owner: harness.program_id,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            harness.token_account_from,
+            Account {
+                lamports: rent.minimum_balance(TokenAccount::LEN),
+                data: token_account_data(mint, harness.authority.pubkey(), 1_000),
+                owner: spl_token::id(),
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            harness.token_account_to,
+            Account {
+                lamports: rent.minimum_balance(TokenAccount::LEN),
+                data: token_account_data(mint, harness.owner.pubkey(), 0),
+                owner: spl_token::id(),
+                ..Account::default()
+            },
+        );
+
+        (program_test, harness)
+    }
+
+    fn transfer_instruction(harness: &TransferHarness, amount: u64, authority_signs: bool) -> Instruction {
+        Instruction::new_with_bytes(
+            harness.program_id,
+            &amount.to_le_bytes(),
+            vec![
+                AccountMeta::new_readonly(harness.owner.pubkey(), true),
+                AccountMeta::new_readonly(spl_token::id(), false),
+                AccountMeta::new(harness.token_account_from, false),
+                AccountMeta::new(harness.token_account_to, false),
+                AccountMeta::new_readonly(harness.authority.pubkey(), authority_signs),
+                AccountMeta::new(harness.state_account, false),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_transfer_tokens_end_to_end() {
+        let (program_test, harness) = transfer_program_test();
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let transaction = Transaction::new_signed_with_payer(
+            &[transfer_instruction(&harness, 400, true)],
+            Some(&payer.pubkey()),
+            &[&payer, &harness.owner, &harness.authority],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+
+        let from = banks_client.get_account(harness.token_account_from).await.unwrap().unwrap();
+        let to = banks_client.get_account(harness.token_account_to).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&from.data).unwrap().amount, 600);
+        assert_eq!(TokenAccount::unpack(&to.data).unwrap().amount, 400);
+
+        let state = banks_client.get_account(harness.state_account).await.unwrap().unwrap();
+        let dex_slippage = DexSlippage::deserialize(&mut &state.data[..]).unwrap();
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 400);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_tokens_unauthorized() {
+        let (program_test, harness) = transfer_program_test();
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // The token authority never signs, so the SPL transfer CPI must fail
+        let transaction = Transaction::new_signed_with_payer(
+            &[transfer_instruction(&harness, 400, false)],
+            Some(&payer.pubkey()),
+            &[&payer, &harness.owner],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_err(), true);
+
+        let from = banks_client.get_account(harness.token_account_from).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&from.data).unwrap().amount, 1_000);
+    }
+
+    #[test]
+    fn test_liquidity_ratio_precision() {
+        // The old `a * 1000 / b` ratio
+        let coarse = |a: u64, b: u64| a * 1000 / b;
+
+        assert_eq!(coarse(1, 3), 333);
+        assert_eq!(scaled_liquidity_ratio(1, 3, 0), Ok(333_333));
+        assert_eq!(scaled_liquidity_ratio(1, 3, 1000), Ok(coarse(1, 3)));
+
+        // Small imbalances vanish at the coarse scale but not at 1e6
+        assert_eq!(coarse(10_001, 10_000), 1000);
+        assert_eq!(scaled_liquidity_ratio(10_001, 10_000, 1_000_000), Ok(1_000_100));
+
+        assert_eq!(scaled_liquidity_ratio(1, 0, 0), Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_iteration_limits_minimum() {
+        assert_eq!(validate_iteration_limits(1, 1).is_ok(), true);
+        assert_eq!(validate_iteration_limits(0, 1), Err(ProgramError::InvalidArgument));
+        assert_eq!(validate_iteration_limits(1, 0), Err(ProgramError::InvalidArgument));
+
+        let dex_slippage = DexSlippage {
+            optimization_iterations: 1,
+            arbitrage_iterations: 1,
+            ..DexSlippage::default()
+        };
+        assert_eq!(optimization_iterations(&dex_slippage), 1);
+        assert_eq!(arbitrage_iterations(&dex_slippage), 1);
+        assert_eq!(route_optimization_factor(optimization_iterations(&dex_slippage)), 3);
+
+        // Unset accounts keep the original loop counts
+        assert_eq!(optimization_iterations(&DexSlippage::default()), 10);
+        assert_eq!(arbitrage_iterations(&DexSlippage::default()), 5);
+    }
+
+    #[test]
+    fn test_iteration_limits_maximum() {
+        assert_eq!(
+            validate_iteration_limits(MAX_OPTIMIZATION_ITERATIONS, MAX_ARBITRAGE_ITERATIONS).is_ok(),
+            true
+        );
+        assert_eq!(
+            validate_iteration_limits(MAX_OPTIMIZATION_ITERATIONS + 1, 1),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            validate_iteration_limits(1, MAX_ARBITRAGE_ITERATIONS + 1),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        let dex_slippage = DexSlippage {
+            optimization_iterations: MAX_OPTIMIZATION_ITERATIONS,
+            arbitrage_iterations: MAX_ARBITRAGE_ITERATIONS,
+            ..DexSlippage::default()
+        };
+        assert_eq!(optimization_iterations(&dex_slippage), MAX_OPTIMIZATION_ITERATIONS);
+        assert_eq!(arbitrage_iterations(&dex_slippage), MAX_ARBITRAGE_ITERATIONS);
+        assert_eq!(
+            route_optimization_factor(MAX_OPTIMIZATION_ITERATIONS),
+            (1u64 << (MAX_OPTIMIZATION_ITERATIONS + 1)) - 1
+        );
+    }
+
+    #[test]
+    fn test_perform_mev_aborts_on_frozen_account() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let mut frozen_data = vec![0u8; TokenAccount::LEN];
+        TokenAccount::pack(
+            TokenAccount {
+                mint,
+                owner: program_id,
+                amount: 1_000,
+                state: AccountState::Frozen,
+                ..TokenAccount::default()
+            },
+            &mut frozen_data,
+        )
+        .unwrap();
+
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint, program_id, 1_000),
+            frozen_data,
+        );
+        let accounts = fixture.accounts();
+
+        assert_eq!(ensure_not_frozen(&accounts[1]).is_ok(), true);
+        assert_eq!(ensure_not_frozen(&accounts[2]), Err(ProgramError::InvalidAccountData));
+
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            ..DexSlippage::default()
+        };
+        assert_eq!(
+            perform_mev(&mut dex_slippage, &accounts[0], None, &accounts[1], &accounts[2], &[], None, 100, 0, 1),
+            Err(ProgramError::InvalidAccountData)
+        );
+        // Nothing was borrowed
+        assert_eq!(dex_slippage.mev_in_progress, false);
+    }
+
+    fn sweep_profit_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        _instruction_data: &[u8],
+    ) -> ProgramResult {
+        sweep_profit(program_id, accounts)
+    }
+
+    fn wrapped_sol_account(owner: Pubkey, amount: u64, rent_exempt_reserve: u64) -> Account {
+        let mut data = vec![0u8; TokenAccount::LEN];
+        TokenAccount::pack(
+            TokenAccount {
+                mint: spl_token::native_mint::id(),
+                owner,
+                amount,
+                state: AccountState::Initialized,
+                is_native: COption::Some(rent_exempt_reserve),
+                ..TokenAccount::default()
+            },
+            &mut data,
+        )
+        .unwrap();
+
+        Account {
+            lamports: rent_exempt_reserve + amount,
+            data,
+            owner: spl_token::id(),
+            ..Account::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_profit_as_native_sol() {
+        let program_id = Pubkey::new_unique();
+        let owner = Keypair::new();
+        let state_account = Pubkey::new_unique();
+        let profit_vault = Pubkey::new_unique();
+        let temporary_wsol = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let rent = Rent::default();
+        let rent_exempt_reserve = rent.minimum_balance(TokenAccount::LEN);
+
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(sweep_profit_processor),
+        );
+        let state_data = state_account_data(&DexSlippage {
+            owner: owner.pubkey(),
+            unswept_profit: 250_000,
+            profit_in_sol: true,
+            ..DexSlippage::default()
+        });
+        program_test.add_account(
+            state_account,
+            Account {
+                lamports: rent.minimum_balance(state_data.len()),
+                data: state_data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            owner.pubkey(),
+            Account {
+                lamports: 1_000_000,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(profit_vault, wrapped_sol_account(authority, 400_000, rent_exempt_reserve));
+        program_test.add_account(temporary_wsol, wrapped_sol_account(authority, 0, rent_exempt_reserve));
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let instruction = Instruction::new_with_bytes(
+            program_id,
+            &[],
+            vec![
+                AccountMeta::new(owner.pubkey(), true),
+                AccountMeta::new(state_account, false),
+                AccountMeta::new_readonly(authority, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+                AccountMeta::new(profit_vault, false),
+                AccountMeta::new(temporary_wsol, false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &[&payer, &owner],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+
+        // The profit arrives as lamports, along with the temporary account's rent
+        let owner_account = banks_client.get_account(owner.pubkey()).await.unwrap().unwrap();
+        assert_eq!(owner_account.lamports, 1_000_000 + 250_000 + rent_exempt_reserve);
+        assert_eq!(banks_client.get_account(temporary_wsol).await.unwrap().is_none(), true);
+
+        let vault = banks_client.get_account(profit_vault).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&vault.data).unwrap().amount, 150_000);
+    }
+
+    #[test]
+    fn test_set_guards() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let mut fixture = SetterFixture::new(program_id, owner, state_account_data(&DexSlippage {
+            owner,
+            slippage_percent: 1,
+            liquidity_threshold: 10,
+            ..DexSlippage::default()
+        }));
+        let accounts = fixture.accounts();
+
+        assert_eq!(set_guards(&program_id, &accounts, 3, 50_000).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.slippage_percent, 3);
+        assert_eq!(dex_slippage.is_slippage_set, true);
+        assert_eq!(dex_slippage.liquidity_threshold, 50_000);
+
+        // One invalid guard leaves both untouched
+        assert_eq!(set_guards(&program_id, &accounts, 101, 70_000), Err(ProgramError::InvalidArgument));
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.slippage_percent, 3);
+        assert_eq!(dex_slippage.liquidity_threshold, 50_000);
+    }
+
+    #[test]
+    fn test_state_account_rejected_as_token_account() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let state_account = Pubkey::new_unique();
+        let token = Pubkey::new_unique();
+        let stray = Pubkey::new_unique();
+
+        let mut state_lamports = 0;
+        let mut token_lamports = 0;
+        let mut stray_lamports = 0;
+        let mut state_data = state_account_data(&DexSlippage::default());
+        let mut token_data = token_account_data(Pubkey::new_unique(), program_id, 0);
+        let mut stray_data = vec![];
+        let accounts = vec![
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token,
+                false,
+                true,
+                &mut token_lamports,
+                &mut token_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &stray,
+                false,
+                true,
+                &mut stray_lamports,
+                &mut stray_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(
+            validate_token_account(&accounts[0], &accounts[0], &program_id),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            validate_token_account(&accounts[2], &accounts[0], &program_id),
+            Err(ProgramError::IllegalOwner)
+        );
+        assert_eq!(validate_token_account(&accounts[1], &accounts[0], &program_id).is_ok(), true);
+    }
+
+    #[test]
+    fn test_attempts_capped_within_slot() {
+        let mut dex_slippage = DexSlippage {
+            max_attempts_per_slot: 2,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(record_attempt(&mut dex_slippage, 100).is_ok(), true);
+        assert_eq!(record_attempt(&mut dex_slippage, 100).is_ok(), true);
+        assert_eq!(record_attempt(&mut dex_slippage, 100), Err(ProgramError::InvalidArgument));
+        assert_eq!(dex_slippage.attempts_this_slot, 2);
+    }
+
+    #[test]
+    fn test_attempts_reset_on_new_slot() {
+        let mut dex_slippage = DexSlippage {
+            max_attempts_per_slot: 1,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(record_attempt(&mut dex_slippage, 100).is_ok(), true);
+        assert_eq!(record_attempt(&mut dex_slippage, 100), Err(ProgramError::InvalidArgument));
+
+        assert_eq!(record_attempt(&mut dex_slippage, 101).is_ok(), true);
+        assert_eq!(dex_slippage.attempts_slot, 101);
+        assert_eq!(dex_slippage.attempts_this_slot, 1);
+    }
+
+    #[test]
+    fn test_flashloan_residual_swept_to_vault() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+        let token_in = Pubkey::new_unique();
+        let token_out = Pubkey::new_unique();
+        let pool_a = Pubkey::new_unique();
+        let pool_b = Pubkey::new_unique();
+        let vault = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let (authority, authority_bump) = Pubkey::find_program_address(&[b"authority"], &program_id);
+
+        let mut router_lamports = 0;
+        let mut token_in_lamports = 0;
+        let mut token_out_lamports = 0;
+        let mut pool_a_lamports = 0;
+        let mut pool_b_lamports = 0;
+        let mut authority_lamports = 0;
+        let mut vault_lamports = 0;
+        let mut token_program_lamports = 0;
+        let mut router_data = vec![];
+        let mut token_in_data = token_account_data(mint, authority, 1_000);
+        let mut token_out_data = token_account_data(mint, authority, 1_000);
+        let mut pool_a_data = token_account_data(mint, router, 50_000);
+        let mut pool_b_data = token_account_data(mint, router, 50_000);
+        let mut authority_data = vec![];
+        let mut vault_data = token_account_data(mint, authority, 0);
+        let mut token_program_data = vec![];
+        let accounts = vec![
+            AccountInfo::new(
+                &router,
+                false,
+                false,
+                &mut router_lamports,
+                &mut router_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_in,
+                false,
+                true,
+                &mut token_in_lamports,
+                &mut token_in_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_out,
+                false,
+                true,
+                &mut token_out_lamports,
+                &mut token_out_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &pool_a,
+                false,
+                false,
+                &mut pool_a_lamports,
+                &mut pool_a_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &pool_b,
+                false,
+                false,
+                &mut pool_b_lamports,
+                &mut pool_b_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &authority,
+                false,
+                false,
+                &mut authority_lamports,
+                &mut authority_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &vault,
+                false,
+                true,
+                &mut vault_lamports,
+                &mut vault_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_program,
+                false,
+                false,
+                &mut token_program_lamports,
+                &mut token_program_data,
+                &program_id,
+                true,
+                Epoch::default(),
+            ),
+        ];
+
+        let bump = [authority_bump];
+        let authority_seeds: &[&[u8]] = &[b"authority", &bump];
+        let destination = ProfitDestination {
+            token_program: &accounts[7],
+            authority: &accounts[5],
+            authority_seeds,
+            vault: &accounts[6],
+        };
+
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            ..DexSlippage::default()
+        };
+
+        // 100 -> 200 flashloan -> 400 intermediate -> 500 out, leaving 300 after repayment
+        assert_eq!(
+            perform_mev(
+                &mut dex_slippage,
+                &accounts[0],
+                Some(&accounts[3]),
+                &accounts[1],
+                &accounts[2],
+                &accounts[3..5],
+                Some(&destination),
+                100,
+                0,
+                1,
+            )
+            .is_ok(),
+            true
+        );
+        assert_eq!(dex_slippage.unswept_profit, 300);
+
+        assert_eq!(flashloan_residual(500, 200), 300);
+        assert_eq!(flashloan_residual(200, 200), 0);
+        assert_eq!(flashloan_residual(199, 200), 0);
+    }
+
+    fn sweep_residual_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let (compound, residual) = instruction_data.split_first().ok_or(ProgramError::InvalidInstructionData)?;
+        let residual = u64::try_from_slice(residual).map_err(|_| ProgramError::InvalidInstructionData)?;
+        let (_, authority_bump) = Pubkey::find_program_address(&[b"authority"], program_id);
+        let bump = [authority_bump];
+        let authority_seeds: &[&[u8]] = &[b"authority", &bump];
+        let destination = ProfitDestination {
+            token_program: &accounts[2],
+            authority: &accounts[1],
+            authority_seeds,
+            vault: &accounts[3],
+        };
+        let dex_slippage = DexSlippage {
+            compound: *compound == 1,
+            ..DexSlippage::default()
+        };
+        sweep_residual(&dex_slippage, Some(&destination), &accounts[0], residual)
+    }
+
+    #[tokio::test]
+    async fn test_sweep_residual_moves_tokens_to_vault() {
+        let program_id = Pubkey::new_unique();
+        let token_out = Pubkey::new_unique();
+        let vault = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let rent = Rent::default();
+
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(sweep_residual_processor),
+        );
+        for (key, amount) in [(token_out, 1_000), (vault, 0)] {
+            program_test.add_account(
+                key,
+                Account {
+                    lamports: rent.minimum_balance(TokenAccount::LEN),
+                    data: token_account_data(mint, authority, amount),
+                    owner: spl_token::id(),
+                    ..Account::default()
+                },
+            );
+        }
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        // Swept once, then a compounding run leaves its residual in the trading account
+        for compound in [0u8, 1] {
+            let instruction = Instruction::new_with_bytes(
+                program_id,
+                &[&[compound][..], &300u64.to_le_bytes()].concat(),
+                vec![
+                    AccountMeta::new(token_out, false),
+                    AccountMeta::new_readonly(authority, false),
+                    AccountMeta::new_readonly(spl_token::id(), false),
+                    AccountMeta::new(vault, false),
+                ],
+            );
+            let transaction = Transaction::new_signed_with_payer(
+                &[instruction],
+                Some(&payer.pubkey()),
+                &[&payer],
+                recent_blockhash,
+            );
+            assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+        }
+
+        let token_out_account = banks_client.get_account(token_out).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&token_out_account.data).unwrap().amount, 700);
+        let vault_account = banks_client.get_account(vault).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&vault_account.data).unwrap().amount, 300);
+    }
+
+    #[test]
+    fn test_rebalance_targets_reconstruct_odd_total() {
+        assert_eq!(rebalance_targets(1_001, true), Ok((501, 500)));
+        assert_eq!(rebalance_targets(1_001, false), Ok((500, 501)));
+        assert_eq!(rebalance_targets(1_000, true), Ok((500, 500)));
+
+        let (target_a, target_b) = rebalance_targets(u64::MAX, false).unwrap();
+        assert_eq!(target_a + target_b, u64::MAX);
+    }
+
+    #[test]
+    fn test_rebalance_adjustment_is_checked() {
+        // Buying first takes the adjustment negative instead of underflowing
+        assert_eq!(adjust_balance(0, 200, TradeSide::Buy), Ok(-200));
+        assert_eq!(adjust_balance(-200, 500, TradeSide::Sell), Ok(300));
+
+        assert_eq!(adjust_balance(i64::MIN, 1, TradeSide::Buy), Err(ProgramError::ArithmeticOverflow));
+        assert_eq!(adjust_balance(i64::MAX, 1, TradeSide::Sell), Err(ProgramError::ArithmeticOverflow));
+        assert_eq!(adjust_balance(0, u64::MAX, TradeSide::Sell), Err(ProgramError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_approve_sanctioned_delegate() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let owner = Pubkey::new_unique();
+        let source = Pubkey::new_unique();
+        let delegate = Pubkey::new_unique();
+        let authority = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+
+        let mut owner_lamports = 0;
+        let mut token_program_lamports = 0;
+        let mut source_lamports = 0;
+        let mut delegate_lamports = 0;
+        let mut authority_lamports = 0;
+        let mut state_lamports = 0;
+        let mut owner_data = vec![];
+        let mut token_program_data = vec![];
+        let mut source_data = token_account_data(Pubkey::new_unique(), authority, 1_000);
+        let mut delegate_data = vec![];
+        let mut authority_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            approved_delegate: Some(delegate),
+            ..DexSlippage::default()
+        });
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_program,
+                false,
+                false,
+                &mut token_program_lamports,
+                &mut token_program_data,
+                &program_id,
+                true,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &source,
+                false,
+                true,
+                &mut source_lamports,
+                &mut source_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &delegate,
+                false,
+                false,
+                &mut delegate_lamports,
+                &mut delegate_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &authority,
+                true,
+                false,
+                &mut authority_lamports,
+                &mut authority_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(approve_delegate(&program_id, &accounts, 500).is_ok(), true);
+    }
+
+    #[test]
+    fn test_reject_unsanctioned_delegate() {
+        let approved = Pubkey::new_unique();
+        let relayer = Pubkey::new_unique();
+
+        let dex_slippage = DexSlippage {
+            approved_delegate: Some(approved),
+            ..DexSlippage::default()
+        };
+        assert_eq!(check_delegate(&dex_slippage, &approved).is_ok(), true);
+        assert_eq!(check_delegate(&dex_slippage, &relayer), Err(ProgramError::InvalidArgument));
+
+        // Nothing can be approved until the owner sanctions a delegate
+        assert_eq!(
+            check_delegate(&DexSlippage::default(), &approved),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_absurd_spread_rejected() {
+        // A 3-hop cycle turning 10_000 into 50_000 is a 40_000 bps spread
+        assert_eq!(exceeds_max_spread(10_000, 50_000, 500), true);
+        assert_eq!(exceeds_max_spread(10_000, 10_300, 500), false);
+        assert_eq!(exceeds_max_spread(10_000, 10_500, 500), false);
+        assert_eq!(exceeds_max_spread(10_000, 10_501, 500), true);
+
+        // Disabled when unset
+        assert_eq!(exceeds_max_spread(10_000, 50_000, 0), false);
+    }
+
+    fn config_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        match instruction_data.split_first() {
+            Some((0, [])) => export_config(program_id, accounts),
+            Some((1, config)) => import_config(program_id, accounts, config),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config_export_import_round_trip() {
+        let program_id = Pubkey::new_unique();
+        let owner = Keypair::new();
+        let source_account = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+
+        let exported_state = DexSlippage {
+            owner: Pubkey::new_unique(),
+            arb_tx_price: 42,
+            is_slippage_set: true,
+            slippage_percent: 3,
+            mev_enabled: true,
+            liquidity_threshold: 50_000,
+            min_profit_threshold: 1_000,
+            total_profit: 9_000,
+            last_profit_nonce: 12,
+            log_level: LOG_LEVEL_VERBOSE,
+            base_mint: Pubkey::new_unique(),
+            quote_mint: Pubkey::new_unique(),
+            arbitrage_iterations: 8,
+            max_spread_bps: 500,
+            ..DexSlippage::default()
+        };
+        let current_state = DexSlippage {
+            owner: owner.pubkey(),
+            last_profit_nonce: 3,
+            ..DexSlippage::default()
+        };
+
+        let mut program_test = ProgramTest::new("solana_mev_engine", program_id, processor!(config_processor));
+        for (key, dex_slippage) in [(source_account, &exported_state), (state_account, &current_state)] {
+            program_test.add_account(
+                key,
+                Account {
+                    lamports: Rent::default().minimum_balance(DexSlippage::LEN),
+                    data: state_account_data(dex_slippage),
+                    owner: program_id,
+                    ..Account::default()
+                },
+            );
+        }
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let transaction = Transaction::new_signed_with_payer(
+            &[Instruction::new_with_bytes(
+                program_id,
+                &[0],
+                vec![AccountMeta::new_readonly(source_account, false)],
+            )],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        let exported = banks_client.process_transaction_with_metadata(transaction).await.unwrap();
+        assert_eq!(exported.result.is_ok(), true);
+
+        // The logged payload is the full Borsh encoding of the state, not a compacted digest
+        let config = logged_event(&exported.metadata.unwrap().log_messages, "config").unwrap();
+        assert_eq!(config, exported_state.try_to_vec().unwrap());
+
+        let import = |config: &[u8]| {
+            Transaction::new_signed_with_payer(
+                &[Instruction::new_with_bytes(
+                    program_id,
+                    &[&[1][..], config].concat(),
+                    vec![
+                        AccountMeta::new_readonly(owner.pubkey(), true),
+                        AccountMeta::new(state_account, false),
+                    ],
+                )],
+                Some(&payer.pubkey()),
+                &[&payer, &owner],
+                recent_blockhash,
+            )
+        };
+        assert_eq!(banks_client.process_transaction(import(&config)).await.is_ok(), true);
+
+        // Settings come from the backup, ownership and stats stay with the account
+        let state = banks_client.get_account(state_account).await.unwrap().unwrap();
+        let dex_slippage = DexSlippage::from_versioned_slice(&state.data).unwrap();
+        assert_eq!(dex_slippage.owner, owner.pubkey());
+        assert_eq!(dex_slippage.arb_tx_price, 42);
+        assert_eq!(dex_slippage.slippage_percent, 3);
+        assert_eq!(dex_slippage.liquidity_threshold, 50_000);
+        assert_eq!(dex_slippage.base_mint, exported_state.base_mint);
+        assert_eq!(dex_slippage.max_spread_bps, 500);
+        assert_eq!(dex_slippage.total_profit, 0);
+        assert_eq!(dex_slippage.last_profit_nonce, 3);
+
+        // Invalid fields are rejected without touching state
+        let mut invalid = DexSlippage::try_from_slice(&config).unwrap();
+        invalid.slippage_percent = 101;
+        let res = banks_client.process_transaction(import(&invalid.try_to_vec().unwrap())).await;
+        assert_eq!(
+            res.unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::InvalidArgument)
+        );
+        let unchanged = banks_client.get_account(state_account).await.unwrap().unwrap();
+        assert_eq!(unchanged.data, state.data);
+    }
+
+    #[test]
+    fn test_validate_config_rejects_out_of_range_settings() {
+        let owner = Pubkey::new_unique();
+        let co_owner = Pubkey::new_unique();
+        let valid = || DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        };
+        assert_eq!(validate_config(&valid()).is_ok(), true);
+
+        let mut dex_slippage = valid();
+        dex_slippage.max_retry_slippage_bps = 10_001;
+        assert_eq!(validate_config(&dex_slippage), Err(ProgramError::InvalidArgument));
+
+        let mut dex_slippage = valid();
+        dex_slippage.max_spread_bps = 10_001;
+        assert_eq!(validate_config(&dex_slippage), Err(ProgramError::InvalidArgument));
+
+        // Duplicate owners would let one key count twice toward the threshold
+        let mut dex_slippage = valid();
+        dex_slippage.owners[..3].copy_from_slice(&[owner, co_owner, co_owner]);
+        dex_slippage.threshold = 2;
+        assert_eq!(validate_config(&dex_slippage), Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_near_max_flashloan_amount_overflows() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        assert_eq!(flashloan_size(u64::MAX / 2), Ok(u64::MAX - 1));
+        assert_eq!(flashloan_size(u64::MAX / 2 + 1), Err(ProgramError::ArithmeticOverflow));
+
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint, program_id, 1_000),
+            token_account_data(mint, program_id, 1_000),
+        );
+        let accounts = fixture.accounts();
+
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            ..DexSlippage::default()
+        };
+        assert_eq!(
+            perform_mev(
+                &mut dex_slippage,
+                &accounts[0],
+                None,
+                &accounts[1],
+                &accounts[2],
+                &accounts[1..],
+                None,
+                u64::MAX - 10,
+                0,
+                1
+            ),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+        // No wrapped loan was taken out
+        assert_eq!(dex_slippage.mev_in_progress, false);
+        assert_eq!(dex_slippage.outstanding_loan, 0);
+    }
+
+    #[test]
+    fn test_router_filter_modes() {
+        let listed = Pubkey::new_unique();
+        let unlisted = Pubkey::new_unique();
+
+        let mut filtered_routers = [Pubkey::default(); MAX_FILTERED_ROUTERS];
+        filtered_routers[0] = listed;
+        let mut dex_slippage = DexSlippage {
+            filtered_routers,
+            ..DexSlippage::default()
+        };
+
+        // No filtering by default
+        assert_eq!(validate_router(&dex_slippage, &listed).is_ok(), true);
+        assert_eq!(validate_router(&dex_slippage, &unlisted).is_ok(), true);
+
+        dex_slippage.router_filter_mode = RouterFilterMode::Allowlist;
+        assert_eq!(validate_router(&dex_slippage, &listed).is_ok(), true);
+        assert_eq!(validate_router(&dex_slippage, &unlisted), Err(ProgramError::InvalidArgument));
+
+        dex_slippage.router_filter_mode = RouterFilterMode::Denylist;
+        assert_eq!(validate_router(&dex_slippage, &listed), Err(ProgramError::InvalidArgument));
+        assert_eq!(validate_router(&dex_slippage, &unlisted).is_ok(), true);
+    }
+
+    #[test]
+    fn test_too_small_state_buffer_not_partially_written() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        // Still on the original layout, so the current state no longer fits
+        let original = DexSlippageV1 {
+            owner,
+            slippage_percent: 1,
+            ..DexSlippageV1::default()
+        }
+        .try_to_vec()
+        .unwrap();
+
+        let mut fixture = SetterFixture::new(program_id, owner, original.clone());
+        let accounts = fixture.accounts();
+
+        let res = set_approved_delegate(&program_id, &accounts, Some(Pubkey::new_unique()));
+        assert_eq!(res, Err(ProgramError::AccountDataTooSmall));
+        assert_eq!(*accounts[1].data.borrow(), original);
+
+        // A truncated buffer fails before the state is even read
+        let mut truncated = SetterFixture::new(program_id, owner, original[..original.len() - 8].to_vec());
+        let accounts = truncated.accounts();
+
+        assert_eq!(set_slippage(&program_id, &accounts, 5).is_err(), true);
+        assert_eq!(*accounts[1].data.borrow(), &original[..original.len() - 8]);
+    }
+
+    #[test]
+    fn test_fill_below_tolerance_rejected() {
+        let dex_slippage = DexSlippage {
+            slippage_percent: 2,
+            ..DexSlippage::default()
+        };
+
+        let within = SwapFill {
+            quoted: 10_000,
+            filled: 9_800,
+        };
+        assert_eq!(check_fill(&dex_slippage, TradeSide::Buy, &within).is_ok(), true);
+
+        // A 5% worse fill than quoted violates the 2% tolerance
+        let violating = SwapFill {
+            quoted: 10_000,
+            filled: 9_500,
+        };
+        assert_eq!(
+            check_fill(&dex_slippage, TradeSide::Buy, &violating),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_set_arb_tx_price() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let mut fixture = SetterFixture::new(program_id, owner, state_account_data(&DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        }));
+        let accounts = fixture.accounts();
+
+        assert_eq!(set_arb_tx_price(&program_id, &accounts, 0), Err(ProgramError::InvalidArgument));
+        assert_eq!(set_arb_tx_price(&program_id, &accounts, 500).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.arb_tx_price, 500);
+
+        // A profit that doesn't cover the reference price is no opportunity
+        assert_eq!(covers_arb_tx_price(400, dex_slippage.arb_tx_price), false);
+        assert_eq!(covers_arb_tx_price(501, dex_slippage.arb_tx_price), true);
+        assert_eq!(covers_arb_tx_price(400, 0), true);
+    }
+
+    #[test]
+    fn test_state_borrow_released_around_cpi() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+
+        let mut state_lamports = 0;
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        });
+        let state_info = AccountInfo::new(
+            &state_account,
+            false,
+            true,
+            &mut state_lamports,
+            &mut state_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+
+        let mut dex_slippage = load_state(&state_info).unwrap();
+
+        // A re-entrant CPI can borrow the state while the handler holds a copy
+        {
+            let reentrant_borrow = state_info.try_borrow_mut_data();
+            assert_eq!(reentrant_borrow.is_ok(), true);
+        }
+
+        // Re-acquired after the CPI to persist the update
+        dex_slippage.trading_balance_in_tokens = 1_000;
+        assert_eq!(store_state(&state_info, &dex_slippage).is_ok(), true);
+        assert_eq!(load_state(&state_info).unwrap().trading_balance_in_tokens, 1_000);
+
+        // Holding the borrow across the CPI is what used to break re-entrancy
+        let held = state_info.try_borrow_mut_data().unwrap();
+        assert_eq!(load_state(&state_info).err(), Some(ProgramError::AccountBorrowFailed));
+        drop(held);
+    }
+
+    #[test]
+    fn test_cached_plan_staleness() {
+        let dex_slippage = DexSlippage {
+            max_plan_age_slots: 10,
+            ..DexSlippage::default()
+        };
+        let plan = ArbitragePlan {
+            direction: ArbitrageDirection::Forward,
+            amount: 1_000,
+            potential_profit: 50,
+            slot: 100,
+        };
+
+        // Fresh plans execute
+        assert_eq!(check_plan_age(&dex_slippage, &plan, 100).is_ok(), true);
+        assert_eq!(check_plan_age(&dex_slippage, &plan, 110).is_ok(), true);
+
+        // Stale ones force a recompute
+        assert_eq!(check_plan_age(&dex_slippage, &plan, 111), Err(ProgramError::InvalidAccountData));
+    }
+
+    #[test]
+    fn test_perform_mev_executes_cached_plan() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let mint_in = Pubkey::new_unique();
+        let mint_out = Pubkey::new_unique();
+
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint_in, program_id, 1_000),
+            token_account_data(mint_out, program_id, 1_000),
+        );
+        let accounts = fixture.accounts();
+
+        // token_out's mint is over its cap, so only the reversed trade ends on an uncapped account
+        let mut position_caps = [(Pubkey::default(), 0); MAX_POSITION_CAPS];
+        position_caps[0] = (mint_out, 500);
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            strategy: MevStrategy::SpotArb,
+            position_caps,
+            cached_plan: Some(ArbitragePlan {
+                direction: ArbitrageDirection::Reverse,
+                amount: 40,
+                potential_profit: 10,
+                slot: 0,
+            }),
+            ..DexSlippage::default()
+        };
+
+        // 40 planned, not the 100 asked for: 40 -> 50 out, 10 profit
+        let res = perform_mev(
+            &mut dex_slippage,
+            &accounts[0],
+            None,
+            &accounts[1],
+            &accounts[2],
+            &accounts[1..],
+            None,
+            100,
+            0,
+            1,
+        );
+        assert_eq!(res.is_ok(), true);
+        assert_eq!(dex_slippage.unswept_profit, 10);
+
+        // The plan was used up, the next call trades as asked
+        assert_eq!(dex_slippage.cached_plan, None);
+        let res = perform_mev(
+            &mut dex_slippage,
+            &accounts[0],
+            None,
+            &accounts[1],
+            &accounts[2],
+            &accounts[1..],
+            None,
+            100,
+            0,
+            2,
+        );
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_guardian_can_only_pause() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let guardian = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+
+        let mut guardian_lamports = 0;
+        let mut state_lamports = 0;
+        let mut guardian_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            guardian,
+            ..DexSlippage::default()
+        });
+        let accounts = vec![
+            AccountInfo::new(
+                &guardian,
+                true,
+                false,
+                &mut guardian_lamports,
+                &mut guardian_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(set_paused(&program_id, &accounts, true).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.paused, true);
+
+        // Unpausing and any other operation remain owner-only
+        assert_eq!(set_paused(&program_id, &accounts, false), Err(ProgramError::IncorrectProgramId));
+        assert_eq!(set_max_spread(&program_id, &accounts, 100), Err(ProgramError::IncorrectProgramId));
+        assert_eq!(set_guardian(&program_id, &accounts, Pubkey::default()), Err(ProgramError::IncorrectProgramId));
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.paused, true);
+        assert_eq!(dex_slippage.max_spread_bps, 0);
+    }
+
+    #[test]
+    fn test_slippage_change_cooldown() {
+        let mut dex_slippage = DexSlippage {
+            min_slippage_change_interval_slots: 100,
+            ..DexSlippage::default()
+        };
+
+        assert_eq!(record_slippage_change(&mut dex_slippage, 1_000).is_ok(), true);
+
+        // Too soon after the last change
+        assert_eq!(record_slippage_change(&mut dex_slippage, 1_099), Err(ProgramError::InvalidArgument));
+        assert_eq!(dex_slippage.last_slippage_change_slot, 1_000);
+
+        // Allowed once the interval has passed
+        assert_eq!(record_slippage_change(&mut dex_slippage, 1_100).is_ok(), true);
+        assert_eq!(dex_slippage.last_slippage_change_slot, 1_100);
+    }
+
+    #[test]
+    fn test_lowering_slippage_cooldown_is_time_locked() {
+        let mut dex_slippage = DexSlippage {
+            min_slippage_change_interval_slots: 100,
+            ..DexSlippage::default()
+        };
+
+        // Tightening applies at once
+        assert_eq!(change_slippage_interval(&mut dex_slippage, 200, 1_000).is_ok(), true);
+        assert_eq!(dex_slippage.min_slippage_change_interval_slots, 200);
+
+        // Loosening is only requested, and can't be applied before the cooldown passes
+        assert_eq!(change_slippage_interval(&mut dex_slippage, 0, 1_000).is_ok(), true);
+        assert_eq!(dex_slippage.min_slippage_change_interval_slots, 200);
+        assert_eq!(dex_slippage.pending_slippage_change_interval, Some(0));
+        assert_eq!(
+            change_slippage_interval(&mut dex_slippage, 0, 1_199),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(dex_slippage.min_slippage_change_interval_slots, 200);
+
+        // Requesting another value restarts the wait
+        assert_eq!(change_slippage_interval(&mut dex_slippage, 50, 1_150).is_ok(), true);
+        assert_eq!(
+            change_slippage_interval(&mut dex_slippage, 50, 1_200),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(change_slippage_interval(&mut dex_slippage, 50, 1_350).is_ok(), true);
+        assert_eq!(dex_slippage.min_slippage_change_interval_slots, 50);
+        assert_eq!(dex_slippage.pending_slippage_change_interval, None);
+    }
+
+    fn estimate_mev_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let (amount, hop_fees_bps) =
+            <(u64, [u16; 3])>::try_from_slice(instruction_data).map_err(|_| ProgramError::InvalidInstructionData)?;
+        estimate_mev(program_id, accounts, amount, hop_fees_bps)
+    }
+
+    #[tokio::test]
+    async fn test_estimate_mev_decoded_from_log_payload() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let tokens = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        let rent = Rent::default();
+
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(estimate_mev_processor),
+        );
+        for token in tokens {
+            program_test.add_account(
+                token,
+                Account {
+                    lamports: rent.minimum_balance(TokenAccount::LEN),
+                    data: token_account_data(Pubkey::new_unique(), router, 1_000_000),
+                    owner: spl_token::id(),
+                    ..Account::default()
+                },
+            );
+        }
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let mut results = vec![];
+        for hop_fees_bps in [[0, 0, 0], [30, 30, 30], [30, 30, 0]] {
+            // Same router for every hop, and no state account
+            let mut accounts = vec![AccountMeta::new_readonly(router, false); 3];
+            accounts.extend(tokens.iter().map(|token| AccountMeta::new_readonly(*token, false)));
+            let instruction_data = (1_000u64, hop_fees_bps).try_to_vec().unwrap();
+            let transaction = Transaction::new_signed_with_payer(
+                &[Instruction::new_with_bytes(program_id, &instruction_data, accounts)],
+                Some(&payer.pubkey()),
+                &[&payer],
+                recent_blockhash,
+            );
+            results.push(banks_client.process_transaction_with_metadata(transaction).await.unwrap());
+        }
+
+        // The logged payload is the Borsh-encoded estimate
+        let mut estimates = vec![];
+        for result in &results[..2] {
+            assert_eq!(result.result.is_ok(), true);
+            let payload = logged_event(&result.metadata.as_ref().unwrap().log_messages, "mev_estimate").unwrap();
+            estimates.push(MevEstimate::try_from_slice(&payload).unwrap());
+        }
+
+        // Equal reserves: each hop loses to price impact, 1_000 -> 999 -> 998 -> 997
+        assert_eq!(estimates[0].expected_out, 997);
+        assert_eq!(estimates[0].estimated_profit, -3);
+
+        // The router's 30 bps fee is taken on every hop too
+        assert_eq!(estimates[1].expected_out, 988);
+        assert_eq!(estimates[1].estimated_profit, -12);
+
+        // One router can't charge two different fees
+        assert_eq!(
+            results[2].result,
+            Err(TransactionError::InstructionError(0, InstructionError::InvalidArgument))
+        );
+    }
+
+    #[test]
+    fn test_flashloan_unavailable_falls_back_to_own_balance() {
+        let mut dex_slippage = DexSlippage::default();
+
+        // Disabled by default, a short lending pool refuses the trade
+        assert_eq!(fallback_amount(&dex_slippage, 1_000, 5_000), None);
+
+        // Capped by the configured maximum and by the bot's own balance
+        dex_slippage.max_fallback_amount = 600;
+        assert_eq!(fallback_amount(&dex_slippage, 1_000, 5_000), Some(600));
+        assert_eq!(fallback_amount(&dex_slippage, 1_000, 400), Some(400));
+        assert_eq!(fallback_amount(&dex_slippage, 1_000, 0), None);
+
+        // Without a loan only the own principal has to come back
+        let own_amount = fallback_amount(&dex_slippage, 1_000, 5_000).unwrap();
+        let output = own_amount + (own_amount >> 2);
+        assert_eq!(flashloan_residual(output, own_amount), 150);
+    }
+
+    #[test]
+    fn test_short_lending_pool_trades_unleveraged() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+        let token_in = Pubkey::new_unique();
+        let token_out = Pubkey::new_unique();
+        let lending_pool = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let mut router_lamports = 0;
+        let mut token_in_lamports = 0;
+        let mut token_out_lamports = 0;
+        let mut lending_pool_lamports = 0;
+        let mut router_data = vec![];
+        let mut token_in_data = token_account_data(mint, program_id, 1_000);
+        let mut token_out_data = token_account_data(mint, program_id, 1_000);
+        // A 100 trade borrows 200, the pool only has 150
+        let mut lending_pool_data = token_account_data(mint, router, 150);
+        let accounts = vec![
+            AccountInfo::new(
+                &router,
+                false,
+                false,
+                &mut router_lamports,
+                &mut router_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_in,
+                false,
+                true,
+                &mut token_in_lamports,
+                &mut token_in_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_out,
+                false,
+                true,
+                &mut token_out_lamports,
+                &mut token_out_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &lending_pool,
+                false,
+                false,
+                &mut lending_pool_lamports,
+                &mut lending_pool_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            ..DexSlippage::default()
+        };
+
+        // Without a fallback the trade is refused before anything is borrowed
+        let res = perform_mev(
+            &mut dex_slippage,
+            &accounts[0],
+            Some(&accounts[3]),
+            &accounts[1],
+            &accounts[2],
+            &accounts[1..3],
+            None,
+            100,
+            0,
+            1,
+        );
+        assert_eq!(res, Err(ProgramError::InsufficientFunds));
+        assert_eq!(dex_slippage.mev_in_progress, false);
+        assert_eq!(dex_slippage.outstanding_loan, 0);
+
+        // With one, 80 own -> 100 out, 20 profit and no loan to repay
+        dex_slippage.max_fallback_amount = 80;
+        let res = perform_mev(
+            &mut dex_slippage,
+            &accounts[0],
+            Some(&accounts[3]),
+            &accounts[1],
+            &accounts[2],
+            &accounts[1..3],
+            None,
+            100,
+            0,
+            2,
+        );
+        assert_eq!(res.is_ok(), true);
+        assert_eq!(dex_slippage.unswept_profit, 20);
+        assert_eq!(dex_slippage.outstanding_loan, 0);
+    }
+
+    #[test]
+    fn test_withdraw_funds_requires_owner_signature() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let receiver = Pubkey::new_unique();
+
+        let mut owner_lamports = 0;
+        let mut state_lamports = 1_000;
+        let mut receiver_lamports = 0;
+        let mut owner_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        });
+        let mut receiver_data = vec![];
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                false,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &receiver,
+                false,
+                true,
+                &mut receiver_lamports,
+                &mut receiver_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(withdraw_funds(&program_id, &accounts), Err(ProgramError::MissingRequiredSignature));
+        assert_eq!(accounts[1].lamports(), 1_000);
+        assert_eq!(accounts[2].lamports(), 0);
+    }
+
+    #[test]
+    fn test_withdraw_funds_with_signing_owner() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let receiver = Pubkey::new_unique();
+
+        let mut owner_lamports = 0;
+        let mut state_lamports = 1_000;
+        let mut receiver_lamports = 0;
+        let mut owner_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        });
+        let mut receiver_data = vec![];
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &receiver,
+                false,
+                true,
+                &mut receiver_lamports,
+                &mut receiver_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(withdraw_funds(&program_id, &accounts).is_ok(), true);
+        assert_eq!(accounts[1].lamports(), 0);
+        assert_eq!(accounts[2].lamports(), 1_000);
+    }
+
+    #[test]
+    fn test_position_cap() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let capped_mint = Pubkey::new_unique();
+        let token = Pubkey::new_unique();
+
+        let mut position_caps = [(Pubkey::default(), 0); MAX_POSITION_CAPS];
+        position_caps[0] = (capped_mint, 10_000);
+        let dex_slippage = DexSlippage {
+            position_caps,
+            ..DexSlippage::default()
+        };
+
+        let mut token_lamports = 0;
+        let mut token_data = token_account_data(capped_mint, program_id, 8_000);
+        let token_info = AccountInfo::new(
+            &token,
+            false,
+            true,
+            &mut token_lamports,
+            &mut token_data,
+            &token_program,
+            false,
+            Epoch::default(),
+        );
+
+        // Within the cap
+        assert_eq!(check_position_cap(&dex_slippage, &token_info, 2_000).is_ok(), true);
+
+        // Exceeding it
+        assert_eq!(check_position_cap(&dex_slippage, &token_info, 2_001), Err(ProgramError::InvalidArgument));
+
+        // Uncapped mints are unrestricted
+        assert_eq!(position_cap(&dex_slippage, &Pubkey::new_unique()), None);
+    }
+
+    fn trade_receipt_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let accounts_iter = &mut accounts.iter();
+        let payer = next_account_info(accounts_iter)?;
+        let receipt_account = next_account_info(accounts_iter)?;
+        let system_program = next_account_info(accounts_iter)?;
+
+        let field = |i: usize| u64::from_le_bytes(instruction_data[i * 8..(i + 1) * 8].try_into().unwrap());
+        emit_trade_receipt(program_id, payer, receipt_account, system_program, field(0), field(1), field(2))
+    }
+
+    #[tokio::test]
+    async fn test_trade_receipt_pda_created() {
+        let program_id = Pubkey::new_unique();
+        let nonce: u64 = 7;
+        let (receipt, _) = Pubkey::find_program_address(&[b"trade", &nonce.to_le_bytes()], &program_id);
+
+        let program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(trade_receipt_processor),
+        );
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let mut instruction_data = nonce.to_le_bytes().to_vec();
+        instruction_data.extend_from_slice(&1_000u64.to_le_bytes());
+        instruction_data.extend_from_slice(&125u64.to_le_bytes());
+        let instruction = Instruction::new_with_bytes(
+            program_id,
+            &instruction_data,
+            vec![
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new(receipt, false),
+                AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+
+        let receipt_account = banks_client.get_account(receipt).await.unwrap().unwrap();
+        assert_eq!(receipt_account.owner, program_id);
+        let receipt = TradeReceipt::try_from_slice(&receipt_account.data).unwrap();
+        assert_eq!(receipt.nonce, nonce);
+        assert_eq!(receipt.amount, 1_000);
+        assert_eq!(receipt.profit, 125);
+    }
+
+    #[test]
+    fn test_too_many_accounts_rejected() {
+        let program_id = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+
+        let mut lamports = 0;
+        let mut data = vec![];
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let accounts = vec![account; MAX_TRANSFER_ACCOUNTS + 1];
+
+        let res = process_instruction(&program_id, &accounts, &100u64.to_le_bytes());
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_realized_slippage_report() {
+        let dex_slippage = DexSlippage {
+            slippage_percent: 2,
+            ..DexSlippage::default()
+        };
+
+        // Expected 10_000 out, the balance only grew by 9_850
+        let report = slippage_report(&dex_slippage, 10_000, 5_000, 14_850);
+        assert_eq!(
+            report,
+            SlippageReport {
+                expected_out: 10_000,
+                realized_out: 9_850,
+                realized_slippage_bps: 150,
+                budgeted_slippage_bps: 200,
+            }
+        );
+
+        // A better than expected fill has no slippage
+        assert_eq!(slippage_report(&dex_slippage, 10_000, 0, 10_100).realized_slippage_bps, 0);
+    }
+
+    #[test]
+    fn test_liquidity_threshold_uses_its_mint() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let mint_a = Pubkey::new_unique();
+        let mint_b = Pubkey::new_unique();
+        let reserve_a = Pubkey::new_unique();
+        let reserve_b = Pubkey::new_unique();
+
+        let mut reserve_a_lamports = 0;
+        let mut reserve_b_lamports = 0;
+        let mut reserve_a_data = token_account_data(mint_a, program_id, 500);
+        let mut reserve_b_data = token_account_data(mint_b, program_id, 1_000_000);
+        let pool_accounts = vec![
+            AccountInfo::new(
+                &reserve_a,
+                false,
+                false,
+                &mut reserve_a_lamports,
+                &mut reserve_a_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &reserve_b,
+                false,
+                false,
+                &mut reserve_b_lamports,
+                &mut reserve_b_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let mut dex_slippage = DexSlippage {
+            liquidity_threshold: 1_000,
+            liquidity_threshold_mint: mint_a,
+            ..DexSlippage::default()
+        };
+
+        // Only mint A's reserve counts, B's larger reserve doesn't satisfy it
+        assert_eq!(check_liquidity(&dex_slippage, &pool_accounts), Err(ProgramError::InsufficientFunds));
+
+        dex_slippage.liquidity_threshold_mint = mint_b;
+        assert_eq!(check_liquidity(&dex_slippage, &pool_accounts).is_ok(), true);
+
+        dex_slippage.liquidity_threshold_mint = Pubkey::new_unique();
+        assert_eq!(check_liquidity(&dex_slippage, &pool_accounts), Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_safe_mode_aborts_unprofitable_trade() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+
+        let lending_pool = Pubkey::new_unique();
+        let mut lending_pool_lamports = 0;
+        let mut lending_pool_data = token_account_data(Pubkey::new_unique(), router, 50_000);
+        let lending_pool_info = AccountInfo::new(
+            &lending_pool,
+            false,
+            false,
+            &mut lending_pool_lamports,
+            &mut lending_pool_data,
+            &token_program,
+            false,
+            Epoch::default(),
+        );
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(Pubkey::new_unique(), program_id, 1_000_000),
+            token_account_data(Pubkey::new_unique(), program_id, 1_000_000),
+        );
+        let accounts = fixture.accounts();
+
+        // A round trip through the same pool loses to price impact
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            safe_mode: true,
+            ..DexSlippage::default()
+        };
+        assert_eq!(
+            perform_mev(
+                &mut dex_slippage,
+                &accounts[0],
+                Some(&lending_pool_info),
+                &accounts[1],
+                &accounts[2],
+                &accounts[1..],
+                None,
+                1_000,
+                0,
+                1
+            ),
+            Err(ProgramError::InvalidArgument)
+        );
+        // Aborted before any funds moved
+        assert_eq!(dex_slippage.mev_in_progress, false);
+        assert_eq!(dex_slippage.outstanding_loan, 0);
+    }
+
+    #[test]
+    fn test_safe_mode_allows_profitable_trade() {
+        assert_eq!(require_simulated_profit(2_100, 2_000).is_ok(), true);
+        assert_eq!(require_simulated_profit(2_000, 2_000), Err(ProgramError::InvalidArgument));
+        assert_eq!(require_simulated_profit(1_900, 2_000), Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_evaluate_routes_reports_best() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+        let route0_token1 = Pubkey::new_unique();
+        let route0_token2 = Pubkey::new_unique();
+        let route0_token3 = Pubkey::new_unique();
+        let route1_token1 = Pubkey::new_unique();
+        let route1_token2 = Pubkey::new_unique();
+        let route1_token3 = Pubkey::new_unique();
+        let route2_token1 = Pubkey::new_unique();
+        let route2_token2 = Pubkey::new_unique();
+        let route2_token3 = Pubkey::new_unique();
+
+        // Deeper pools lose less to price impact: route 1 is the best of the three
+        let mut router_lamports = 0;
+        let mut route0_token1_lamports = 0;
+        let mut route0_token2_lamports = 0;
+        let mut route0_token3_lamports = 0;
+        let mut route1_token1_lamports = 0;
+        let mut route1_token2_lamports = 0;
+        let mut route1_token3_lamports = 0;
+        let mut route2_token1_lamports = 0;
+        let mut route2_token2_lamports = 0;
+        let mut route2_token3_lamports = 0;
+        let mut router_data = vec![];
+        let mut route0_token1_data = token_account_data(Pubkey::new_unique(), router, 10_000);
+        let mut route0_token2_data = token_account_data(Pubkey::new_unique(), router, 10_000);
+        let mut route0_token3_data = token_account_data(Pubkey::new_unique(), router, 10_000);
+        let mut route1_token1_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let mut route1_token2_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let mut route1_token3_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let mut route2_token1_data = token_account_data(Pubkey::new_unique(), router, 100_000);
+        let mut route2_token2_data = token_account_data(Pubkey::new_unique(), router, 100_000);
+        let mut route2_token3_data = token_account_data(Pubkey::new_unique(), router, 100_000);
+        let accounts = vec![
+            AccountInfo::new(
+                &router,
+                false,
+                false,
+                &mut router_lamports,
+                &mut router_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route0_token1,
+                false,
+                false,
+                &mut route0_token1_lamports,
+                &mut route0_token1_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route0_token2,
+                false,
+                false,
+                &mut route0_token2_lamports,
+                &mut route0_token2_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route0_token3,
+                false,
+                false,
+                &mut route0_token3_lamports,
+                &mut route0_token3_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route1_token1,
+                false,
+                false,
+                &mut route1_token1_lamports,
+                &mut route1_token1_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route1_token2,
+                false,
+                false,
+                &mut route1_token2_lamports,
+                &mut route1_token2_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route1_token3,
+                false,
+                false,
+                &mut route1_token3_lamports,
+                &mut route1_token3_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route2_token1,
+                false,
+                false,
+                &mut route2_token1_lamports,
+                &mut route2_token1_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route2_token2,
+                false,
+                false,
+                &mut route2_token2_lamports,
+                &mut route2_token2_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &route2_token3,
+                false,
+                false,
+                &mut route2_token3_lamports,
+                &mut route2_token3_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let mut routes = vec![];
+        for route in 0..3 {
+            routes.extend_from_slice(&[accounts[0].clone(), accounts[0].clone(), accounts[0].clone()]);
+            routes.extend_from_slice(&accounts[1 + route * 3..4 + route * 3]);
+        }
+
+        assert_eq!(evaluate_routes(&program_id, &routes, 1_000).is_ok(), true);
+        assert_eq!(
+            best_route(&DexSlippage::default(), &routes, 1_000),
+            Ok(BestRoute {
+                index: 1,
+                estimated_profit: -3,
+            })
+        );
+
+        // The router's fee is taken on every hop
+        let mut config = DexSlippage::default();
+        config.router_fees[0] = (router, 30);
+        assert_eq!(
+            best_route(&config, &routes, 1_000),
+            Ok(BestRoute {
+                index: 1,
+                estimated_profit: -12,
+            })
+        );
+
+        // Incomplete groups are rejected
+        assert_eq!(
+            best_route(&DexSlippage::default(), &routes[..5], 1_000),
+            Err(ProgramError::NotEnoughAccountKeys)
+        );
+    }
+
+    #[test]
+    fn test_losing_cycle_not_profitable() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+        let token1 = Pubkey::new_unique();
+        let token2 = Pubkey::new_unique();
+        let token3 = Pubkey::new_unique();
+
+        let mut router_lamports = 0;
+        let mut token1_lamports = 0;
+        let mut token2_lamports = 0;
+        let mut token3_lamports = 0;
+        let mut router_data = vec![];
+        let mut token1_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let mut token2_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let mut token3_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let accounts = vec![
+            AccountInfo::new(
+                &router,
+                false,
+                false,
+                &mut router_lamports,
+                &mut router_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token1,
+                false,
+                false,
+                &mut token1_lamports,
+                &mut token1_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token2,
+                false,
+                false,
+                &mut token2_lamports,
+                &mut token2_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token3,
+                false,
+                false,
+                &mut token3_lamports,
+                &mut token3_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        // Equal pools: both cycles lose 3 to price impact
+        let outcome = calculate_arbitrage(
+            &DexSlippage::default(),
+            &accounts[0],
+            &accounts[0],
+            &accounts[0],
+            &accounts[1],
+            &accounts[2],
+            &accounts[3],
+            &[],
+            1_000,
+        )
+        .unwrap();
+        assert_eq!(outcome.potential_profit, -3);
+        assert_eq!(outcome.is_opportunity, false);
+
+        // An aggressive negative threshold accepts the small loss
+        let aggressive = DexSlippage {
+            min_profit_threshold: -10,
+            ..DexSlippage::default()
+        };
+        let outcome = calculate_arbitrage(
+            &aggressive,
+            &accounts[0],
+            &accounts[0],
+            &accounts[0],
+            &accounts[1],
+            &accounts[2],
+            &accounts[3],
+            &[],
+            1_000,
+        )
+        .unwrap();
+        assert_eq!(outcome.is_opportunity, true);
+    }
+
+    #[test]
+    fn test_calculate_arbitrage_takes_reverse_cycle() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router1 = Pubkey::new_unique();
+        let router2 = Pubkey::new_unique();
+        let router3 = Pubkey::new_unique();
+        let token1 = Pubkey::new_unique();
+        let token2 = Pubkey::new_unique();
+        let token3 = Pubkey::new_unique();
+
+        let mut router1_lamports = 0;
+        let mut router2_lamports = 0;
+        let mut router3_lamports = 0;
+        let mut token1_lamports = 0;
+        let mut token2_lamports = 0;
+        let mut token3_lamports = 0;
+        let mut router1_data = vec![];
+        let mut router2_data = vec![];
+        let mut router3_data = vec![];
+        let mut token1_data = token_account_data(Pubkey::new_unique(), router1, 2_000_000);
+        let mut token2_data = token_account_data(Pubkey::new_unique(), router1, 500_000);
+        let mut token3_data = token_account_data(Pubkey::new_unique(), router1, 100_000);
+        let accounts = vec![
+            AccountInfo::new(
+                &router1,
+                false,
+                false,
+                &mut router1_lamports,
+                &mut router1_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &router2,
+                false,
+                false,
+                &mut router2_lamports,
+                &mut router2_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &router3,
+                false,
+                false,
+                &mut router3_lamports,
+                &mut router3_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token1,
+                false,
+                false,
+                &mut token1_lamports,
+                &mut token1_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token2,
+                false,
+                false,
+                &mut token2_lamports,
+                &mut token2_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token3,
+                false,
+                false,
+                &mut token3_lamports,
+                &mut token3_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        // Neither cycle can beat a round trip through the same reserves, but with router3's fee
+        // the forward cycle loses 209 and the reverse one 181. Only the reverse cycle clears -200.
+        let mut dex_slippage = DexSlippage {
+            min_profit_threshold: -200,
+            ..DexSlippage::default()
+        };
+        dex_slippage.router_fees[0] = (router3, 30);
+
+        let outcome = calculate_arbitrage(
+            &dex_slippage,
+            &accounts[0],
+            &accounts[1],
+            &accounts[2],
+            &accounts[3],
+            &accounts[4],
+            &accounts[5],
+            &[],
+            10_000,
+        )
+        .unwrap();
+        assert_eq!(
+            outcome,
+            ArbitrageOutcome {
+                direction: ArbitrageDirection::Reverse,
+                potential_profit: -181,
+                is_opportunity: true,
+            }
+        );
+
+        // Quoted on its own, the forward cycle falls short of the threshold
+        let forward1 = get_price_from_router(&dex_slippage, &accounts[0], &accounts[3], &accounts[4], 10_000).unwrap();
+        let forward2 =
+            get_price_from_router(&dex_slippage, &accounts[1], &accounts[4], &accounts[5], forward1.expected).unwrap();
+        let forward3 =
+            get_price_from_router(&dex_slippage, &accounts[2], &accounts[5], &accounts[3], forward2.expected).unwrap();
+        assert_eq!(forward3.expected as i64 - 10_000, -209);
+        assert_eq!(meets_profit_threshold(-209, dex_slippage.min_profit_threshold), false);
+    }
+
+    #[test]
+    fn test_daily_withdraw_limit() {
+        let mut dex_slippage = DexSlippage {
+            daily_withdraw_limit: 1_000,
+            withdraw_window_start_slot: 100,
+            ..DexSlippage::default()
+        };
+
+        // Within the limit
+        assert_eq!(record_daily_withdrawal(&mut dex_slippage, 600, 100).is_ok(), true);
+        assert_eq!(record_daily_withdrawal(&mut dex_slippage, 400, 200).is_ok(), true);
+        assert_eq!(dex_slippage.withdrawn_today, 1_000);
+
+        // Over the limit within the same window
+        assert_eq!(record_daily_withdrawal(&mut dex_slippage, 1, 100 + SLOTS_PER_DAY - 1), Err(ProgramError::InsufficientFunds));
+        assert_eq!(dex_slippage.withdrawn_today, 1_000);
+
+        // The window resets after a day
+        assert_eq!(record_daily_withdrawal(&mut dex_slippage, 700, 100 + SLOTS_PER_DAY).is_ok(), true);
+        assert_eq!(dex_slippage.withdrawn_today, 700);
+        assert_eq!(dex_slippage.withdraw_window_start_slot, 100 + SLOTS_PER_DAY);
+    }
+
+    fn initialize_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let nonce = u64::from_le_bytes(instruction_data[1..9].try_into().unwrap());
+        initialize(program_id, accounts, instruction_data[0], nonce, &instruction_data[9..])
+    }
+
+    #[tokio::test]
+    async fn test_initialize_two_strategies_for_one_owner() {
+        let program_id = Pubkey::new_unique();
+        let program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(initialize_processor),
+        );
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        for (strategy_index, slippage_percent) in [(0u8, 1u8), (1, 5)] {
+            let (state_pda, _) =
+                Pubkey::find_program_address(&[b"state", &[strategy_index], &0u64.to_le_bytes()], &program_id);
+            let mut instruction_data = vec![strategy_index];
+            instruction_data.extend(0u64.to_le_bytes());
+            instruction_data.extend(
+                DexSlippage {
+                    owner: payer.pubkey(),
+                    slippage_percent,
+                    ..DexSlippage::default()
+                }
+                .try_to_vec()
+                .unwrap(),
+            );
+            let instruction = Instruction::new_with_bytes(
+                program_id,
+                &instruction_data,
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(state_pda, false),
+                    AccountMeta::new_readonly(solana_program::system_program::id(), false),
+                    AccountMeta::new_readonly(solana_program::sysvar::rent::id(), false),
+                ],
+            );
+            let transaction = Transaction::new_signed_with_payer(
+                &[instruction],
+                Some(&payer.pubkey()),
+                &[&payer],
+                recent_blockhash,
+            );
+            assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+        }
+
+        // Both strategies exist side by side with their own config
+        for (strategy_index, slippage_percent) in [(0u8, 1u8), (1, 5)] {
+            let (state_pda, _) =
+                Pubkey::find_program_address(&[b"state", &[strategy_index], &0u64.to_le_bytes()], &program_id);
+            let state = banks_client.get_account(state_pda).await.unwrap().unwrap();
+            let dex_slippage = DexSlippage::deserialize(&mut &state.data[..]).unwrap();
+            assert_eq!(dex_slippage.owner, payer.pubkey());
+            assert_eq!(dex_slippage.slippage_percent, slippage_percent);
+        }
+    }
+
+    #[test]
+    fn test_delegation_past_ttl_revoked() {
+        let mut dex_slippage = DexSlippage {
+            approval_slot: 1_000,
+            approval_ttl_slots: 500,
+            ..DexSlippage::default()
+        };
+
+        // Still live at the TTL
+        assert_eq!(expire_delegation(&mut dex_slippage, 1_500), false);
+        assert_eq!(dex_slippage.approval_slot, 1_000);
+
+        // Past it, the delegation is treated as revoked, exactly once
+        assert_eq!(expire_delegation(&mut dex_slippage, 1_501), true);
+        assert_eq!(dex_slippage.approval_slot, 0);
+        assert_eq!(expire_delegation(&mut dex_slippage, 2_000), false);
+    }
+
+    #[test]
+    fn test_rebalance_rejects_same_mint() {
+        let program_id = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint, program_id, 1_000),
+            token_account_data(mint, program_id, 3_000),
+        );
+        let accounts = fixture.accounts();
+
+        let res = rebalance_portfolio(&DexSlippage::default(), &accounts[0], &accounts[1], &accounts[2], 1_000);
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_rebalance_legs_bounded_by_side_slippage() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(Pubkey::new_unique(), program_id, 1_000),
+            token_account_data(Pubkey::new_unique(), program_id, 3_000),
+        );
+        let accounts = fixture.accounts();
+
+        // Buying token A for 200 of token B is quoted at 62, each leg bounded by its own side
+        let dex_slippage = DexSlippage {
+            buy_slippage_bps: 100,
+            sell_slippage_bps: 500,
+            ..DexSlippage::default()
+        };
+        assert_eq!(
+            buy_token(&dex_slippage, &accounts[0], &accounts[1], &accounts[2], 200),
+            Ok(RouterQuote {
+                expected: 62,
+                min_out: 61,
+            })
+        );
+        assert_eq!(
+            sell_token(&dex_slippage, &accounts[0], &accounts[2], &accounts[1], 200),
+            Ok(RouterQuote {
+                expected: 62,
+                min_out: 58,
+            })
+        );
+
+        // Nothing is swapped yet, so the stepped rebalance leaves the balances alone
+        let res = rebalance_portfolio(&dex_slippage, &accounts[0], &accounts[1], &accounts[2], 1_000);
+        assert_eq!(res.is_ok(), true);
+        assert_eq!(get_token_balance(&accounts[1]).unwrap(), 1_000);
+        assert_eq!(get_token_balance(&accounts[2]).unwrap(), 3_000);
+    }
+
+    #[test]
+    fn test_profit_conversion_through_configured_router() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let profit_mint = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let other_router = Pubkey::new_unique();
+        let profit_pool = Pubkey::new_unique();
+        let profit_mint_pool = Pubkey::new_unique();
+
+        let mut router_lamports = 0;
+        let mut other_router_lamports = 0;
+        let mut profit_pool_lamports = 0;
+        let mut profit_mint_pool_lamports = 0;
+        let mut router_data = vec![];
+        let mut other_router_data = vec![];
+        let mut profit_pool_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let mut profit_mint_pool_data = token_account_data(profit_mint, router, 1_000_000);
+        let accounts = vec![
+            AccountInfo::new(
+                &router,
+                false,
+                false,
+                &mut router_lamports,
+                &mut router_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &other_router,
+                false,
+                false,
+                &mut other_router_lamports,
+                &mut other_router_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &profit_pool,
+                false,
+                false,
+                &mut profit_pool_lamports,
+                &mut profit_pool_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &profit_mint_pool,
+                false,
+                false,
+                &mut profit_mint_pool_lamports,
+                &mut profit_mint_pool_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        let mut filtered_routers = [Pubkey::default(); MAX_FILTERED_ROUTERS];
+        filtered_routers[0] = router;
+        let mut dex_slippage = DexSlippage {
+            profit_mint,
+            profit_conversion_router: router,
+            router_filter_mode: RouterFilterMode::Allowlist,
+            filtered_routers,
+            ..DexSlippage::default()
+        };
+
+        let quote = quote_profit_conversion(&dex_slippage, &accounts[0], &accounts[2], &accounts[3], 1_000).unwrap();
+        assert_eq!(quote.expected, 999);
+
+        // Any other router is refused
+        let res = quote_profit_conversion(&dex_slippage, &accounts[1], &accounts[2], &accounts[3], 1_000);
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+
+        // So is the configured one once it drops off the allowlist
+        dex_slippage.filtered_routers = [Pubkey::default(); MAX_FILTERED_ROUTERS];
+        let res = quote_profit_conversion(&dex_slippage, &accounts[0], &accounts[2], &accounts[3], 1_000);
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_reset_stats_preserves_config() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let mut dex_slippage = DexSlippage {
+            owner,
+            slippage_percent: 3,
+            liquidity_threshold: 50_000,
+            min_profit_threshold: 100,
+            trading_balance_in_tokens: 9_000,
+            ..DexSlippage::default()
+        };
+        assert_eq!(record_profit(&mut dex_slippage, 400, 1), Ok(true));
+        assert_eq!(record_profit(&mut dex_slippage, 600, 2), Ok(true));
+        assert_eq!(dex_slippage.trade_count, 2);
+        assert_eq!(dex_slippage.recent_profits[..2], [400, 600]);
+
+        let mut fixture = SetterFixture::new(program_id, owner, state_account_data(&dex_slippage));
+        let accounts = fixture.accounts();
+
+        assert_eq!(reset_stats(&program_id, &accounts).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.trade_count, 0);
+        assert_eq!(dex_slippage.total_profit, 0);
+        assert_eq!(dex_slippage.recent_profits, [0; PROFIT_HISTORY_LEN]);
+        assert_eq!(dex_slippage.recent_profits_next, 0);
+
+        // Config, balances and replay protection are untouched
+        assert_eq!(dex_slippage.slippage_percent, 3);
+        assert_eq!(dex_slippage.liquidity_threshold, 50_000);
+        assert_eq!(dex_slippage.min_profit_threshold, 100);
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 9_000);
+        assert_eq!(dex_slippage.last_profit_nonce, 2);
+    }
+
+    #[test]
+    fn test_discrepancy_within_tolerance_does_not_pause() {
+        let mut dex_slippage = DexSlippage {
+            trading_balance_in_tokens: 10_000,
+            auto_pause_on_discrepancy: true,
+            max_discrepancy_tolerance: 5,
+            ..DexSlippage::default()
+        };
+
+        // Still reported, but the bot keeps running
+        let discrepancy = check_balance_invariant(&mut dex_slippage, 9_995).unwrap();
+        assert_eq!(discrepancy.paused, false);
+        assert_eq!(dex_slippage.paused, false);
+    }
+
+    #[test]
+    fn test_discrepancy_over_tolerance_pauses() {
+        let mut dex_slippage = DexSlippage {
+            trading_balance_in_tokens: 10_000,
+            auto_pause_on_discrepancy: true,
+            max_discrepancy_tolerance: 5,
+            ..DexSlippage::default()
+        };
+
+        let discrepancy = check_balance_invariant(&mut dex_slippage, 10_006).unwrap();
+        assert_eq!(discrepancy.paused, true);
+        assert_eq!(dex_slippage.paused, true);
+    }
+
+    #[test]
+    fn test_withdraw_funds_receiver_overflow() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let receiver = Pubkey::new_unique();
+
+        let mut owner_lamports = 0;
+        let mut state_lamports = 1_000;
+        let mut receiver_lamports = u64::MAX - 10;
+        let mut owner_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        });
+        let mut receiver_data = vec![];
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &receiver,
+                false,
+                true,
+                &mut receiver_lamports,
+                &mut receiver_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(withdraw_funds(&program_id, &accounts), Err(ProgramError::ArithmeticOverflow));
+
+        // Neither balance was touched
+        assert_eq!(accounts[1].lamports(), 1_000);
+        assert_eq!(accounts[2].lamports(), u64::MAX - 10);
+    }
+
+    #[test]
+    fn test_perform_mev_strategy_selection() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let lending_pool = Pubkey::new_unique();
+        let mut lending_pool_lamports = 0;
+        let mut lending_pool_data = token_account_data(mint, router, 50_000);
+        let lending_pool_info = AccountInfo::new(
+            &lending_pool,
+            false,
+            false,
+            &mut lending_pool_lamports,
+            &mut lending_pool_data,
+            &token_program,
+            false,
+            Epoch::default(),
+        );
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint, program_id, 1_000),
+            token_account_data(mint, program_id, 1_000),
+        );
+        let accounts = fixture.accounts();
+
+        // Flashloan: 100 -> 200 borrowed -> 400 -> 500 out, 300 left after repaying.
+        // Spot: 100 own -> 125 out, 25 profit. Liquidity provision: no arbitrage profit.
+        for (strategy, expected_profit) in [
+            (MevStrategy::FlashloanArb, 300),
+            (MevStrategy::SpotArb, 25),
+            (MevStrategy::LiquidityProvision, 0),
+        ] {
+            let mut dex_slippage = DexSlippage {
+                enable_trading: true,
+                mev_enabled: true,
+                strategy,
+                ..DexSlippage::default()
+            };
+            let res = perform_mev(
+                &mut dex_slippage,
+                &accounts[0],
+                Some(&lending_pool_info),
+                &accounts[1],
+                &accounts[2],
+                &accounts[1..],
+                None,
+                100,
+                0,
+                1,
+            );
+            assert_eq!(res.is_ok(), true);
+            assert_eq!(dex_slippage.unswept_profit, expected_profit);
+            assert_eq!(dex_slippage.outstanding_loan, 0);
+        }
+    }
+
+    #[test]
+    fn test_liquidity_ratio_large_amounts() {
+        // 1e18 * 1000 overflows a u64 intermediate
+        let liquidity_a: u64 = 1_000_000_000_000_000_000;
+        let liquidity_b: u64 = 500_000_000_000_000_000;
+        assert_eq!(liquidity_a.checked_mul(1000), None);
+        assert_eq!(scaled_liquidity_ratio(liquidity_a, liquidity_b, 1000), Ok(2000));
+        assert_eq!(scaled_liquidity_ratio(liquidity_a, liquidity_b, 0), Ok(2_000_000));
+
+        // A ratio that doesn't fit a u64 is an error, not a truncation
+        assert_eq!(scaled_liquidity_ratio(u64::MAX, 1, 1000), Err(ProgramError::ArithmeticOverflow));
+    }
+
+    #[test]
+    fn test_oracle_quorum_uses_median() {
+        let dex_slippage = DexSlippage {
+            oracle_quorum: 2,
+            oracle_band_bps: 100,
+            ..DexSlippage::default()
+        };
+
+        // The third feed is an outlier
+        let mut prices = [
+            OraclePrice { price: 100_000, confidence: 10 },
+            OraclePrice { price: 150_000, confidence: 10 },
+            OraclePrice { price: 100_050, confidence: 10 },
+        ];
+        let price = median_oracle_price(&dex_slippage, &mut prices).unwrap();
+        assert_eq!(price.price, 100_050);
+
+        // Requiring all three to agree rejects the price
+        let strict = DexSlippage {
+            oracle_quorum: 3,
+            ..dex_slippage
+        };
+        assert_eq!(
+            median_oracle_price(&strict, &mut prices).err(),
+            Some(ProgramError::InvalidAccountData)
+        );
+
+        // Configured oracles can't be skipped by supplying none of them
+        let mut configured = DexSlippage::default();
+        assert_eq!(oracle_required(&configured), false);
+        configured.oracles[0] = Pubkey::new_unique();
+        assert_eq!(oracle_required(&configured), true);
+        assert_eq!(read_oracle_quorum(&configured, &[]), Err(ProgramError::NotEnoughAccountKeys));
+    }
+
+    #[test]
+    fn test_v1_state_reads_with_defaults() {
+        let owner = Pubkey::new_unique();
+        let data = DexSlippageV1 {
+            owner,
+            arb_tx_price: 5_000,
+            enable_trading: true,
+            slippage_percent: 3,
+            liquidity_threshold: 1_000,
+            ..DexSlippageV1::default()
+        }
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(data.len(), DexSlippageV1::LEN);
+
+        // The current layout can't read it directly
+        assert_eq!(DexSlippage::try_from_slice(&data).is_err(), true);
+
+        let dex_slippage = DexSlippage::from_versioned_slice(&data).unwrap();
+        assert_eq!(dex_slippage.owner, owner);
+        assert_eq!(dex_slippage.arb_tx_price, 5_000);
+        assert_eq!(dex_slippage.enable_trading, true);
+        assert_eq!(dex_slippage.slippage_percent, 3);
+        assert_eq!(dex_slippage.liquidity_threshold, 1_000);
+        assert_eq!(dex_slippage.threshold, 0);
+        assert_eq!(dex_slippage.pending_owner, None);
+        assert_eq!(dex_slippage.strategy, MevStrategy::FlashloanArb);
+        // Predates min_profit_threshold, so keeps the threshold it was hardcoded to
+        assert_eq!(dex_slippage.min_profit_threshold, LEGACY_MIN_PROFIT_THRESHOLD);
+    }
+
+    #[test]
+    fn test_len_matches_fully_populated_state() {
+        let dex_slippage = DexSlippage {
+            pending_owner: Some(Pubkey::new_unique()),
+            approved_delegate: Some(Pubkey::new_unique()),
+            cached_plan: Some(ArbitragePlan {
+                direction: ArbitrageDirection::Reverse,
+                amount: 1_000,
+                potential_profit: 50,
+                slot: 100,
+            }),
+            pending_slippage_change_interval: Some(10),
+            ..DexSlippage::default()
+        };
+        assert_eq!(dex_slippage.try_to_vec().unwrap().len(), DexSlippage::LEN);
+
+        // Every None saves its payload, so the default state serializes shorter
+        let none_savings = 32 + 32 + 25 + 8;
+        assert_eq!(DexSlippage::default().try_to_vec().unwrap().len(), DexSlippage::LEN - none_savings);
+    }
+
+    #[test]
+    fn test_unsigned_profit_threshold_upgraded() {
+        // u64::MAX written by an unsigned layout has the same bytes as -1
+        let data = state_account_data(&DexSlippage {
+            min_profit_threshold: -1,
+            ..DexSlippage::default()
+        });
+        let unsigned = DexSlippage::from_versioned_slice(&data[..1660]).unwrap();
+        assert_eq!(unsigned.min_profit_threshold, i64::MAX);
+        assert_eq!(meets_profit_threshold(i64::MAX - 1, unsigned.min_profit_threshold), false);
+
+        // Signed layouts keep a negative threshold as set
+        let signed = DexSlippage::from_versioned_slice(&data[..SIGNED_PROFIT_THRESHOLD_LAYOUT_LEN]).unwrap();
+        assert_eq!(signed.min_profit_threshold, -1);
+        let current = DexSlippage::from_versioned_slice(&data).unwrap();
+        assert_eq!(current.min_profit_threshold, -1);
+
+        // Thresholds that fit in both read the same
+        let data = state_account_data(&DexSlippage {
+            min_profit_threshold: 500,
+            ..DexSlippage::default()
+        });
+        assert_eq!(DexSlippage::from_versioned_slice(&data[..1660]).unwrap().min_profit_threshold, 500);
+    }
+
+    #[test]
+    fn test_large_event_payload_is_compacted() {
+        // The full state is well past the limit
+        let payload = DexSlippage::default().try_to_vec().unwrap();
+        assert_eq!(payload.len() > MAX_EVENT_PAYLOAD_LEN, true);
+
+        let compact = compact_payload(&payload);
+        assert_eq!(compact.len(), 4 + 32);
+        assert_eq!(compact[..4], (payload.len() as u32).to_le_bytes());
+        assert_eq!(compact[4..], hash(&payload).to_bytes());
+
+        assert_eq!(emit_event("config", &DexSlippage::default()).is_ok(), true);
+    }
+
+    #[test]
+    fn test_rotate_guardian() {
+        let program_id = Pubkey::new_unique();
         let owner = Pubkey::new_unique();
         let state_account = Pubkey::new_unique();
-        let token_program = Pubkey::new_unique();
-        let rent_sysvar = Rent::default();
-
-        let mut state_data = vec![0u8; DexSlippage::LEN];
-        let mut rent_data = rent_sysvar.try_to_vec().unwrap();
-        rent_data.resize(Rent::size_of(), 0);
+        let old_guardian = Pubkey::new_unique();
+        let new_guardian = Pubkey::new_unique();
 
+        let mut owner_lamports = 0;
+        let mut state_lamports = 0;
+        let mut old_guardian_lamports = 0;
+        let mut new_guardian_lamports = 0;
+        let mut owner_data = vec![];
+        let mut old_guardian_data = vec![];
+        let mut new_guardian_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            guardian: old_guardian,
+            ..DexSlippage::default()
+        });
         let accounts = vec![
             AccountInfo::new(
                 &owner,
                 true,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
                 true,
-                &mut [],
+                &mut state_lamports,
                 &mut state_data,
                 &program_id,
                 false,
                 Epoch::default(),
             ),
             AccountInfo::new(
-                &token_program,
+                &old_guardian,
+                true,
                 false,
+                &mut old_guardian_lamports,
+                &mut old_guardian_data,
+                &program_id,
                 false,
-                &mut [],
-                &mut [],
-                &spl_token::id(),
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &new_guardian,
+                true,
+                false,
+                &mut new_guardian_lamports,
+                &mut new_guardian_data,
+                &program_id,
                 false,
                 Epoch::default(),
             ),
         ];
 
-        let instruction_data = DexSlippage {
-            owner,
-            arb_tx_price: 0,
-            enable_trading: false,
-            token_pair: 0,
-            trading_balance_in_tokens: 0,
-            is_slippage_set: false,
-            slippage_percent: 0,
-            mev_enabled: false,
-            liquidity_threshold: 0,
-        }
-        .try_to_vec()
-        .unwrap();
+        // The zero key is rejected
+        assert_eq!(
+            set_guardian(&program_id, &accounts[..2], Pubkey::default()),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        assert_eq!(set_guardian(&program_id, &accounts[..2], new_guardian).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.guardian, new_guardian);
 
+        // The old guardian can no longer pause
+        let old_guardian_accounts = [accounts[2].clone(), accounts[1].clone()];
         assert_eq!(
-            initialize(&program_id, &accounts, &instruction_data).is_ok(),
-            true
+            set_paused(&program_id, &old_guardian_accounts, true),
+            Err(ProgramError::IncorrectProgramId)
         );
+
+        let new_guardian_accounts = [accounts[3].clone(), accounts[1].clone()];
+        assert_eq!(set_paused(&program_id, &new_guardian_accounts, true).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.paused, true);
     }
 
     #[test]
-    fn test_set_slippage() {
+    fn test_bundle_min_out_reverts_on_net_shortfall() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let lending_pool = Pubkey::new_unique();
+        let mut lending_pool_lamports = 0;
+        let mut lending_pool_data = token_account_data(mint, router, 50_000);
+        let lending_pool_info = AccountInfo::new(
+            &lending_pool,
+            false,
+            false,
+            &mut lending_pool_lamports,
+            &mut lending_pool_data,
+            &token_program,
+            false,
+            Epoch::default(),
+        );
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint, program_id, 1_000),
+            token_account_data(mint, program_id, 1_000),
+        );
+        let accounts = fixture.accounts();
+
+        // Every leg passes, but the output balance doesn't grow
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            ..DexSlippage::default()
+        };
+        let res = perform_mev(
+            &mut dex_slippage,
+            &accounts[0],
+            Some(&lending_pool_info),
+            &accounts[1],
+            &accounts[2],
+            &accounts[1..],
+            None,
+            100,
+            1,
+            1,
+        );
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+        assert_eq!(dex_slippage.unswept_profit, 0);
+
+        let res = perform_mev(
+            &mut dex_slippage,
+            &accounts[0],
+            Some(&lending_pool_info),
+            &accounts[1],
+            &accounts[2],
+            &accounts[1..],
+            None,
+            100,
+            0,
+            2,
+        );
+        assert_eq!(res.is_ok(), true);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_rejects_legacy_rent_account() {
+        let (program_test, harness) = transfer_program_test();
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        // The rent sysvar is no longer expected, passing it shifts the state account
+        let mut instruction = transfer_instruction(&harness, 400, true);
+        instruction
+            .accounts
+            .insert(5, AccountMeta::new_readonly(solana_program::sysvar::rent::id(), false));
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &[&payer, &harness.owner, &harness.authority],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_err(), true);
+
+        let from = banks_client.get_account(harness.token_account_from).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&from.data).unwrap().amount, 1_000);
+    }
+
+    #[test]
+    fn test_side_slippage_per_direction() {
+        let dex_slippage = DexSlippage {
+            slippage_percent: 2,
+            buy_slippage_bps: 300,
+            sell_slippage_bps: 50,
+            ..DexSlippage::default()
+        };
+        assert_eq!(side_slippage_bps(&dex_slippage, TradeSide::Buy), 300);
+        assert_eq!(side_slippage_bps(&dex_slippage, TradeSide::Sell), 50);
+
+        // A 2% worse fill is within the buy tolerance but not the sell one
+        let fill = SwapFill {
+            quoted: 10_000,
+            filled: 9_800,
+        };
+        assert_eq!(check_fill(&dex_slippage, TradeSide::Buy, &fill).is_ok(), true);
+        assert_eq!(
+            check_fill(&dex_slippage, TradeSide::Sell, &fill),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        // An unset side falls back to the global slippage
+        let global_only = DexSlippage {
+            slippage_percent: 2,
+            ..DexSlippage::default()
+        };
+        assert_eq!(side_slippage_bps(&global_only, TradeSide::Sell), 200);
+        assert_eq!(check_fill(&global_only, TradeSide::Sell, &fill).is_ok(), true);
+    }
+
+    fn fund_authority_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let lamports = u64::from_le_bytes(instruction_data[..8].try_into().unwrap());
+        fund_authority(program_id, accounts, lamports)
+    }
+
+    #[tokio::test]
+    async fn test_fund_authority_pda() {
+        let program_id = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+
+        let program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(fund_authority_processor),
+        );
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let instruction = Instruction::new_with_bytes(
+            program_id,
+            &5_000_000u64.to_le_bytes(),
+            vec![
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new(authority, false),
+                AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+
+        assert_eq!(banks_client.get_balance(authority).await.unwrap(), 5_000_000);
+    }
+
+    #[tokio::test]
+    async fn test_initialize_twice_fails_cleanly() {
+        let program_id = Pubkey::new_unique();
+        let program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(initialize_processor),
+        );
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let nonce: u64 = 42;
+        let (state_pda, _) = Pubkey::find_program_address(&[b"state", &[0], &nonce.to_le_bytes()], &program_id);
+
+        // Same strategy and nonce, the second attempt loses
+        let mut results = vec![];
+        for slippage_percent in [1u8, 5] {
+            let mut instruction_data = vec![0u8];
+            instruction_data.extend(nonce.to_le_bytes());
+            instruction_data.extend(
+                DexSlippage {
+                    owner: payer.pubkey(),
+                    slippage_percent,
+                    ..DexSlippage::default()
+                }
+                .try_to_vec()
+                .unwrap(),
+            );
+            let instruction = Instruction::new_with_bytes(
+                program_id,
+                &instruction_data,
+                vec![
+                    AccountMeta::new(payer.pubkey(), true),
+                    AccountMeta::new(state_pda, false),
+                    AccountMeta::new_readonly(solana_program::system_program::id(), false),
+                    AccountMeta::new_readonly(solana_program::sysvar::rent::id(), false),
+                ],
+            );
+            let transaction = Transaction::new_signed_with_payer(
+                &[instruction],
+                Some(&payer.pubkey()),
+                &[&payer],
+                recent_blockhash,
+            );
+            results.push(banks_client.process_transaction(transaction).await);
+        }
+        assert_eq!(results[0].is_ok(), true);
+        assert_eq!(
+            results[1].as_ref().unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::AccountAlreadyInitialized)
+        );
+
+        // The winner's config is untouched
+        let state = banks_client.get_account(state_pda).await.unwrap().unwrap();
+        let dex_slippage = DexSlippage::deserialize(&mut &state.data[..]).unwrap();
+        assert_eq!(dex_slippage.slippage_percent, 1);
+    }
+
+    #[test]
+    fn test_read_only_state_account_rejected() {
         let program_id = Pubkey::new_unique();
         let owner = Pubkey::new_unique();
         let state_account = Pubkey::new_unique();
 
-        let mut state_data = vec![0u8; DexSlippage::LEN];
+        let mut owner_lamports = 0;
+        let mut state_lamports = 0;
+        let mut owner_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        });
+        let original = state_data.clone();
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                false,
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(set_slippage(&program_id, &accounts, 5), Err(ProgramError::InvalidArgument));
+        assert_eq!(*accounts[1].data.borrow(), &original[..]);
+    }
+
+    #[test]
+    fn test_min_hops_rejects_two_hop_route() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint, program_id, 1_000),
+            token_account_data(mint, program_id, 1_000),
+        );
+        let accounts = fixture.accounts();
+
+        let mut dex_slippage = DexSlippage {
+            enable_trading: true,
+            mev_enabled: true,
+            min_hops: 3,
+            ..DexSlippage::default()
+        };
+        assert_eq!(check_min_hops(&dex_slippage, TRIANGULAR_HOPS).is_ok(), true);
+
+        // The round trip is a two-hop route
+        let res = perform_mev(
+            &mut dex_slippage,
+            &accounts[0],
+            None,
+            &accounts[1],
+            &accounts[2],
+            &accounts[1..],
+            None,
+            100,
+            0,
+            1,
+        );
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+        assert_eq!(dex_slippage.mev_in_progress, false);
+        assert_eq!(dex_slippage.unswept_profit, 0);
+    }
+
+    #[test]
+    fn test_mev_requires_trading_and_mev_enabled() {
+        let program_id = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let router = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let lending_pool = Pubkey::new_unique();
+        let mut lending_pool_lamports = 0;
+        let mut lending_pool_data = token_account_data(mint, router, 50_000);
+        let lending_pool_info = AccountInfo::new(
+            &lending_pool,
+            false,
+            false,
+            &mut lending_pool_lamports,
+            &mut lending_pool_data,
+            &token_program,
+            false,
+            Epoch::default(),
+        );
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint, program_id, 1_000),
+            token_account_data(mint, program_id, 1_000),
+        );
+        let accounts = fixture.accounts();
+
+        for (enable_trading, mev_enabled, allowed) in [
+            (false, false, false),
+            (true, false, false),
+            (false, true, false),
+            (true, true, true),
+        ] {
+            let mut dex_slippage = DexSlippage {
+                enable_trading,
+                mev_enabled,
+                ..DexSlippage::default()
+            };
+            assert_eq!(mev_allowed(&dex_slippage), allowed);
+
+            let res = perform_mev(
+                &mut dex_slippage,
+                &accounts[0],
+                Some(&lending_pool_info),
+                &accounts[1],
+                &accounts[2],
+                &accounts[1..],
+                None,
+                100,
+                0,
+                1,
+            );
+            if allowed {
+                assert_eq!(res.is_ok(), true);
+                assert_eq!(dex_slippage.unswept_profit, 300);
+            } else {
+                assert_eq!(res, Err(ProgramError::InvalidAccountData));
+                assert_eq!(dex_slippage.unswept_profit, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_withdraw_fees_leaves_trading_balance() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let token_program = spl_token::id();
+        let fee_vault = Pubkey::new_unique();
+        let recipient_account = Pubkey::new_unique();
+        let fee_recipient = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        // A 10% fee on 1_000 profit accrues 100, the rest is swept as usual
+        let mut dex_slippage = DexSlippage {
+            owner,
+            trading_balance_in_tokens: 5_000,
+            fee_bps: 1_000,
+            fee_recipient,
+            ..DexSlippage::default()
+        };
+        assert_eq!(realize_profit(&mut dex_slippage, 1_000).is_ok(), true);
+        assert_eq!(dex_slippage.accrued_fees, 100);
+        assert_eq!(dex_slippage.unswept_profit, 900);
+
+        let mut owner_lamports = 0;
+        let mut state_lamports = 0;
+        let mut authority_lamports = 0;
+        let mut token_program_lamports = 0;
+        let mut fee_vault_lamports = 0;
+        let mut recipient_lamports = 0;
+        let mut owner_data = vec![];
+        let mut state_data = state_account_data(&dex_slippage);
+        let mut authority_data = vec![];
+        let mut token_program_data = vec![];
+        let mut fee_vault_data = token_account_data(mint, authority, 100);
+        let mut recipient_data = token_account_data(mint, fee_recipient, 0);
         let accounts = vec![
             AccountInfo::new(
                 &owner,
                 true,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
                 true,
-                &mut [],
+                &mut state_lamports,
+                &mut state_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &authority,
+                false,
+                false,
+                &mut authority_lamports,
+                &mut authority_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_program,
+                false,
+                false,
+                &mut token_program_lamports,
+                &mut token_program_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &fee_vault,
+                false,
+                true,
+                &mut fee_vault_lamports,
+                &mut fee_vault_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &recipient_account,
+                false,
+                true,
+                &mut recipient_lamports,
+                &mut recipient_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+        ];
+
+        assert_eq!(withdraw_fees(&program_id, &accounts).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.accrued_fees, 0);
+        assert_eq!(dex_slippage.unswept_profit, 900);
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 5_000);
+
+        // Nothing left to withdraw
+        assert_eq!(withdraw_fees(&program_id, &accounts), Err(ProgramError::InsufficientFunds));
+    }
+
+    #[test]
+    fn test_withdraw_fees_rejects_other_recipient() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let state_account = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let token_program = spl_token::id();
+        let fee_vault = Pubkey::new_unique();
+        let recipient_account = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let mut owner_lamports = 0;
+        let mut state_lamports = 0;
+        let mut authority_lamports = 0;
+        let mut token_program_lamports = 0;
+        let mut fee_vault_lamports = 0;
+        let mut recipient_lamports = 0;
+        let mut owner_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            fee_bps: 1_000,
+            fee_recipient: Pubkey::new_unique(),
+            accrued_fees: 100,
+            ..DexSlippage::default()
+        });
+        let mut authority_data = vec![];
+        let mut token_program_data = vec![];
+        let mut fee_vault_data = token_account_data(mint, authority, 100);
+        // Belongs to the owner, not the fee recipient
+        let mut recipient_data = token_account_data(mint, owner, 0);
+        let accounts = vec![
+            AccountInfo::new(
+                &owner,
+                true,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &state_account,
+                false,
+                true,
+                &mut state_lamports,
                 &mut state_data,
                 &program_id,
                 false,
                 Epoch::default(),
             ),
             AccountInfo::new(
-                &state_account,
-                false,
+                &authority,
+                false,
+                false,
+                &mut authority_lamports,
+                &mut authority_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &token_program,
+                false,
+                false,
+                &mut token_program_lamports,
+                &mut token_program_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &fee_vault,
+                false,
+                true,
+                &mut fee_vault_lamports,
+                &mut fee_vault_data,
+                &token_program,
+                false,
+                Epoch::default(),
+            ),
+            AccountInfo::new(
+                &recipient_account,
                 false,
-                &mut [],
-                &mut state_data,
-                &program_id,
+                true,
+                &mut recipient_lamports,
+                &mut recipient_data,
+                &token_program,
                 false,
                 Epoch::default(),
             ),
         ];
 
+        assert_eq!(withdraw_fees(&program_id, &accounts), Err(ProgramError::InvalidArgument));
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.accrued_fees, 100);
+    }
+
+    fn withdraw_tokens_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        let amount = u64::from_le_bytes(instruction_data[..8].try_into().unwrap());
+        withdraw_tokens(program_id, accounts, amount, instruction_data[8] != 0)
+    }
+
+    #[tokio::test]
+    async fn test_withdraw_wrapped_sol_as_native() {
+        let program_id = Pubkey::new_unique();
+        let owner = Keypair::new();
+        let state_account = Pubkey::new_unique();
+        let vault = Pubkey::new_unique();
+        let temporary_wsol = Pubkey::new_unique();
+        let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let rent = Rent::default();
+        let rent_exempt_reserve = rent.minimum_balance(TokenAccount::LEN);
+
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(withdraw_tokens_processor),
+        );
+        let state_data = state_account_data(&DexSlippage {
+            owner: owner.pubkey(),
+            trading_balance_in_tokens: 400_000,
+            ..DexSlippage::default()
+        });
+        program_test.add_account(
+            state_account,
+            Account {
+                lamports: rent.minimum_balance(state_data.len()),
+                data: state_data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            owner.pubkey(),
+            Account {
+                lamports: 1_000_000,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(vault, wrapped_sol_account(authority, 400_000, rent_exempt_reserve));
+        program_test.add_account(temporary_wsol, wrapped_sol_account(authority, 0, rent_exempt_reserve));
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let mut instruction_data = 300_000u64.to_le_bytes().to_vec();
+        instruction_data.push(1);
+        let instruction = Instruction::new_with_bytes(
+            program_id,
+            &instruction_data,
+            vec![
+                AccountMeta::new(owner.pubkey(), true),
+                AccountMeta::new(state_account, false),
+                AccountMeta::new_readonly(authority, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+                AccountMeta::new(vault, false),
+                AccountMeta::new(temporary_wsol, false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &[&payer, &owner],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+
+        // The owner receives lamports, not wSOL, plus the temporary account's rent
+        let owner_account = banks_client.get_account(owner.pubkey()).await.unwrap().unwrap();
+        assert_eq!(owner_account.lamports, 1_000_000 + 300_000 + rent_exempt_reserve);
+        assert_eq!(banks_client.get_account(temporary_wsol).await.unwrap().is_none(), true);
+
+        let vault = banks_client.get_account(vault).await.unwrap().unwrap();
+        assert_eq!(TokenAccount::unpack(&vault.data).unwrap().amount, 100_000);
+
+        let state = banks_client.get_account(state_account).await.unwrap().unwrap();
+        let dex_slippage = DexSlippage::deserialize(&mut &state.data[..]).unwrap();
+        assert_eq!(dex_slippage.trading_balance_in_tokens, 100_000);
+    }
+
+    #[test]
+    fn test_interleg_deviation_aborts_on_adverse_move() {
+        let dex_slippage = DexSlippage {
+            max_interleg_deviation_bps: 500,
+            ..DexSlippage::default()
+        };
+
+        // The price moved against us mid-trade: 10_000 in, only 9_000 back
         assert_eq!(
-            set_slippage(&program_id, &accounts, 5).is_ok(),
-            true
+            check_interleg_deviation(&dex_slippage, 10_000, 9_000),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        // A 2% adverse move is within the 5% bound, a favorable one always passes
+        assert_eq!(check_interleg_deviation(&dex_slippage, 10_000, 9_800).is_ok(), true);
+        assert_eq!(check_interleg_deviation(&dex_slippage, 10_000, 12_500).is_ok(), true);
+    }
+
+    fn snapshot_state_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        snapshot_state(program_id, accounts, instruction_data)
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_state_to_pda() {
+        let program_id = Pubkey::new_unique();
+        let owner = Keypair::new();
+        let state_account = Pubkey::new_unique();
+        let label = b"daily-2026-10-16";
+        let (snapshot, _) = Pubkey::find_program_address(&[b"snapshot", label], &program_id);
+        let rent = Rent::default();
+
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(snapshot_state_processor),
+        );
+        let state_data = state_account_data(&DexSlippage {
+            owner: owner.pubkey(),
+            slippage_percent: 3,
+            total_profit: 12_345,
+            trade_count: 7,
+            ..DexSlippage::default()
+        });
+        program_test.add_account(
+            state_account,
+            Account {
+                lamports: rent.minimum_balance(state_data.len()),
+                data: state_data.clone(),
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            owner.pubkey(),
+            Account {
+                lamports: 1_000_000_000,
+                ..Account::default()
+            },
+        );
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let instruction = Instruction::new_with_bytes(
+            program_id,
+            label,
+            vec![
+                AccountMeta::new(owner.pubkey(), true),
+                AccountMeta::new_readonly(state_account, false),
+                AccountMeta::new(snapshot, false),
+                AccountMeta::new_readonly(solana_program::system_program::id(), false),
+            ],
         );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &[&payer, &owner],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+
+        let snapshot_account = banks_client.get_account(snapshot).await.unwrap().unwrap();
+        assert_eq!(snapshot_account.owner, program_id);
+        let snapshot = StateSnapshot::try_from_slice(&snapshot_account.data).unwrap();
+        assert_eq!(state_account_data(&snapshot.state), state_data);
+        assert_eq!(snapshot.state.total_profit, 12_345);
+        assert_eq!(snapshot.state.trade_count, 7);
     }
 
     #[test]
-    fn test_enable_mev() {
+    fn test_withdraw_keeps_operating_reserve() {
         let program_id = Pubkey::new_unique();
         let owner = Pubkey::new_unique();
         let state_account = Pubkey::new_unique();
+        let receiver = Pubkey::new_unique();
 
-        let mut state_data = vec![0u8; DexSlippage::LEN];
+        let mut owner_lamports = 0;
+        let mut state_lamports = 1_000;
+        let mut receiver_lamports = 0;
+        let mut owner_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            min_operating_lamports: 400,
+            ..DexSlippage::default()
+        });
+        let mut receiver_data = vec![];
         let accounts = vec![
             AccountInfo::new(
                 &owner,
                 true,
-                true,
-                &mut [],
-                &mut state_data,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
                 &program_id,
                 false,
                 Epoch::default(),
@@ -693,35 +10080,88 @@ mod tests {
             AccountInfo::new(
                 &state_account,
                 false,
-                false,
-                &mut [],
+                true,
+                &mut state_lamports,
                 &mut state_data,
                 &program_id,
                 false,
                 Epoch::default(),
             ),
+            AccountInfo::new(
+                &receiver,
+                false,
+                true,
+                &mut receiver_lamports,
+                &mut receiver_data,
+                &program_id,
+                false,
+                Epoch::default(),
+            ),
         ];
 
+        // Only the balance above the reserve is withdrawn
+        assert_eq!(withdraw_funds(&program_id, &accounts).is_ok(), true);
+        assert_eq!(accounts[1].lamports(), 400);
+        assert_eq!(accounts[2].lamports(), 600);
+
+        // Nothing left above the reserve
+        assert_eq!(withdraw_funds(&program_id, &accounts), Err(ProgramError::InsufficientFunds));
+        assert_eq!(accounts[1].lamports(), 400);
+
+        // A fixed amount that would dip into the reserve is rejected
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
         assert_eq!(
-            enable_mev(&program_id, &accounts, true).is_ok(),
-            true
+            check_operating_reserve(&dex_slippage, 1_000, 601),
+            Err(ProgramError::InsufficientFunds)
         );
+        assert_eq!(check_operating_reserve(&dex_slippage, 1_000, 600).is_ok(), true);
     }
 
     #[test]
-    fn test_set_liquidity_threshold() {
+    fn test_spl_arbitrage_trade_counts() {
+        // A single trade
+        assert_eq!(spl_arbitrage_trades(1_000, 1), vec![(Some(1_000), Some(500))]);
+
+        // The maximum, every step trades with a large enough balance
+        let trades = spl_arbitrage_trades(1_000_000, MAX_ARBITRAGE_ITERATIONS);
+        assert_eq!(trades.len(), MAX_ARBITRAGE_ITERATIONS as usize);
+        assert_eq!(trades.iter().all(|(a, b)| a.is_some() && b.is_some()), true);
+        assert_eq!(trades[0], (Some(1_000_000), Some(15)));
+
+        // A small balance: late A steps and early B steps round to zero
+        assert_eq!(
+            spl_arbitrage_trades(4, 5),
+            vec![
+                (Some(4), None),
+                (Some(3), None),
+                (Some(3), None),
+                (None, Some(4)),
+                (None, Some(6)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_state_account_as_owner_rejected() {
         let program_id = Pubkey::new_unique();
-        let owner = Pubkey::new_unique();
         let state_account = Pubkey::new_unique();
 
-        let mut state_data = vec![0u8; DexSlippage::LEN];
+        // Even a state whose recorded owner is its own key must not let the
+        // state account stand in for the owner
+        let mut owner_lamports = 0;
+        let mut state_lamports = 0;
+        let mut owner_data = vec![];
+        let mut state_data = state_account_data(&DexSlippage {
+            owner: state_account,
+            ..DexSlippage::default()
+        });
         let accounts = vec![
             AccountInfo::new(
-                &owner,
-                true,
+                &state_account,
                 true,
-                &mut [],
-                &mut state_data,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
                 &program_id,
                 false,
                 Epoch::default(),
@@ -729,8 +10169,8 @@ mod tests {
             AccountInfo::new(
                 &state_account,
                 false,
-                false,
-                &mut [],
+                true,
+                &mut state_lamports,
                 &mut state_data,
                 &program_id,
                 false,
@@ -738,27 +10178,143 @@ mod tests {
             ),
         ];
 
+        let res = set_slippage(&program_id, &accounts, 5);
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.slippage_percent, DexSlippage::default().slippage_percent);
+    }
+
+    #[test]
+    fn test_circuit_breaker_recovery_window() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let mut fixture = SetterFixture::new(program_id, owner, state_account_data(&DexSlippage {
+            owner,
+            mev_enabled: true,
+            max_consecutive_losses: 2,
+            breaker_recovery_wins: 2,
+            ..DexSlippage::default()
+        }));
+        let accounts = fixture.accounts();
+
+        // Two losses in a row trip the breaker
+        let mut dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        record_trade_outcome(&mut dex_slippage, false);
+        assert_eq!(dex_slippage.mev_enabled, true);
+        record_trade_outcome(&mut dex_slippage, false);
+        assert_eq!(dex_slippage.mev_enabled, false);
+        write_state(&dex_slippage, &mut accounts[1].data.borrow_mut()).unwrap();
+
+        // The owner re-enables it, one more loss doesn't trip it again
         assert_eq!(
-            set_liquidity_threshold(&program_id, &accounts, 1000).is_ok(),
+            enable_mev(&program_id, &accounts, true).is_ok(),
             true
         );
+        let mut dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.breaker_recovery_remaining, 2);
+        record_trade_outcome(&mut dex_slippage, false);
+        record_trade_outcome(&mut dex_slippage, false);
+        assert_eq!(dex_slippage.mev_enabled, true);
+
+        // Two consecutive wins re-arm it
+        record_trade_outcome(&mut dex_slippage, true);
+        record_trade_outcome(&mut dex_slippage, true);
+        assert_eq!(dex_slippage.breaker_recovery_remaining, 0);
+        record_trade_outcome(&mut dex_slippage, false);
+        assert_eq!(dex_slippage.mev_enabled, true);
+        record_trade_outcome(&mut dex_slippage, false);
+        assert_eq!(dex_slippage.mev_enabled, false);
     }
 
     #[test]
-    fn test_withdraw_funds() {
+    fn test_get_price_from_router_applies_router_fee() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let token_in = Pubkey::new_unique();
+        let token_out = Pubkey::new_unique();
+
+        let mut router_lamports = 0;
+        let mut token_in_lamports = 0;
+        let mut token_out_lamports = 0;
+        let mut router_data = vec![];
+        let mut token_in_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let mut token_out_data = token_account_data(Pubkey::new_unique(), router, 1_000_000);
+        let router_info = AccountInfo::new(
+            &router,
+            false,
+            false,
+            &mut router_lamports,
+            &mut router_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        );
+        let token_in_info = AccountInfo::new(
+            &token_in,
+            false,
+            false,
+            &mut token_in_lamports,
+            &mut token_in_data,
+            &spl_token::id(),
+            false,
+            Epoch::default(),
+        );
+        let token_out_info = AccountInfo::new(
+            &token_out,
+            false,
+            false,
+            &mut token_out_lamports,
+            &mut token_out_data,
+            &spl_token::id(),
+            false,
+            Epoch::default(),
+        );
+
+        let mut dex_slippage = DexSlippage::default();
+        let without_fee =
+            get_price_from_router(&dex_slippage, &router_info, &token_in_info, &token_out_info, 10_000).unwrap();
+        assert_eq!(without_fee.expected, 9_900);
+
+        // A 0.30% AMM only puts 9970 of the 10000 in against the reserves
+        dex_slippage.router_fees[0] = (router, 30);
+        let with_fee =
+            get_price_from_router(&dex_slippage, &router_info, &token_in_info, &token_out_info, 10_000).unwrap();
+        assert_eq!(with_fee.expected, 9_871);
+        assert_eq!(with_fee.expected < without_fee.expected, true);
+
+        // Another router's fee doesn't apply
+        dex_slippage.router_fees[0] = (Pubkey::new_unique(), 30);
+        let other_router =
+            get_price_from_router(&dex_slippage, &router_info, &token_in_info, &token_out_info, 10_000).unwrap();
+        assert_eq!(other_router.expected, without_fee.expected);
+    }
+
+    #[test]
+    fn test_confirm_profit_reverts_below_expected_min() {
         let program_id = Pubkey::new_unique();
         let owner = Pubkey::new_unique();
         let state_account = Pubkey::new_unique();
-        let receiver = Pubkey::new_unique();
+        let token = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
 
-        let mut state_data = vec![0u8; DexSlippage::LEN];
+        let mut owner_lamports = 0;
+        let mut state_lamports = 0;
+        let mut token_lamports = 0;
+        let mut owner_data = vec![];
+        let mut token_data = token_account_data(mint, owner, 1_000);
+        let mut state_data = state_account_data(&DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        });
         let accounts = vec![
             AccountInfo::new(
                 &owner,
                 true,
-                true,
-                &mut [],
-                &mut state_data,
+                false,
+                &mut owner_lamports,
+                &mut owner_data,
                 &program_id,
                 false,
                 Epoch::default(),
@@ -766,28 +10322,564 @@ mod tests {
             AccountInfo::new(
                 &state_account,
                 false,
-                false,
-                &mut [],
+                true,
+                &mut state_lamports,
                 &mut state_data,
                 &program_id,
                 false,
                 Epoch::default(),
             ),
             AccountInfo::new(
-                &receiver,
+                &token,
                 false,
-                true,
-                &mut [],
-                &mut state_data,
-                &program_id,
+                false,
+                &mut token_lamports,
+                &mut token_data,
+                &spl_token::id(),
                 false,
                 Epoch::default(),
             ),
         ];
 
+        // Nothing to confirm against before a snapshot
+        let res = confirm_profit(&program_id, &accounts, 0);
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+
+        assert_eq!(snapshot_balance(&program_id, &accounts).is_ok(), true);
+
+        // The bundle only gained 50
+        accounts[2]
+            .data
+            .borrow_mut()
+            .copy_from_slice(&token_account_data(mint, owner, 1_050));
+        let res = confirm_profit(&program_id, &accounts, 100);
+        assert_eq!(res, Err(ProgramError::InvalidArgument));
+
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.bundle_snapshot_account, token);
+        assert_eq!(dex_slippage.bundle_snapshot_balance, 1_000);
+
+        // Meeting the minimum confirms and consumes the snapshot
+        assert_eq!(confirm_profit(&program_id, &accounts, 50).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.bundle_snapshot_account, Pubkey::default());
+    }
+
+    #[test]
+    fn test_volatility_widens_slippage() {
+        let mut dex_slippage = DexSlippage {
+            slippage_percent: 1,
+            min_volatility_slippage_bps: 50,
+            max_volatility_slippage_bps: 2_000,
+            ..DexSlippage::default()
+        };
+        let observe = |dex_slippage: &mut DexSlippage, reserve_b: u64| {
+            record_price_observation(
+                dex_slippage,
+                &ReserveSnapshot {
+                    stage: SNAPSHOT_BEFORE_MEV,
+                    reserve_a: 1_000,
+                    reserve_b,
+                },
+            )
+        };
+
+        // No history yet, the band's floor applies
+        assert_eq!(effective_slippage_bps(&dex_slippage, 0), 50);
+
+        // Calm prices stay at the floor
+        for reserve_b in [1_000, 1_001, 1_000, 1_001] {
+            observe(&mut dex_slippage, reserve_b);
+        }
+        assert_eq!(price_volatility_bps(&dex_slippage), 9);
+        let calm_bps = effective_slippage_bps(&dex_slippage, 0);
+        assert_eq!(calm_bps, 50);
+
+        // 10% swings push the older, calm observations out and widen it
+        for reserve_b in [1_100, 1_000, 1_100, 1_000, 1_100, 1_000, 1_100, 1_000] {
+            observe(&mut dex_slippage, reserve_b);
+        }
+        let volatile_bps = effective_slippage_bps(&dex_slippage, 0);
+        assert_eq!(volatile_bps > calm_bps, true);
+        assert_eq!(volatile_bps, 948);
+
+        // Still bounded by the band's ceiling
+        dex_slippage.max_volatility_slippage_bps = 500;
+        assert_eq!(effective_slippage_bps(&dex_slippage, 0), 500);
+
+        // Without a band the static slippage applies
+        dex_slippage.max_volatility_slippage_bps = 0;
+        assert_eq!(effective_slippage_bps(&dex_slippage, 0), 100);
+    }
+
+    #[tokio::test]
+    async fn test_initialize_writes_all_fields() {
+        let program_id = Pubkey::new_unique();
+        let program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(initialize_processor),
+        );
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+
+        let (state_pda, _) = Pubkey::find_program_address(&[b"state", &[0], &0u64.to_le_bytes()], &program_id);
+
+        // Non-default values throughout, and an owner other than the payer
+        let config = DexSlippage {
+            owner: Pubkey::new_unique(),
+            arb_tx_price: 5_000,
+            enable_trading: true,
+            token_pair: 3,
+            trading_balance_in_tokens: 1_000_000,
+            is_slippage_set: true,
+            slippage_percent: 2,
+            mev_enabled: true,
+            liquidity_threshold: 10_000,
+            compound: true,
+            threshold: 1,
+            min_profit_threshold: -50,
+            withdraw_delay_slots: 20,
+            instant_withdraw_limit: 500,
+            log_level: LOG_LEVEL_VERBOSE,
+            base_mint: Pubkey::new_unique(),
+            quote_mint: Pubkey::new_unique(),
+            retry_slippage_step_bps: 25,
+            max_retry_slippage_bps: 300,
+            max_spread_bps: 150,
+            router_filter_mode: RouterFilterMode::Allowlist,
+            guardian: Pubkey::new_unique(),
+            safe_mode: true,
+            strategy: MevStrategy::SpotArb,
+            oracle_quorum: 2,
+            buy_slippage_bps: 40,
+            sell_slippage_bps: 60,
+            min_hops: TRIANGULAR_HOPS,
+            fee_bps: 100,
+            fee_recipient: Pubkey::new_unique(),
+            max_interleg_deviation_bps: 75,
+            min_operating_lamports: 1_000_000,
+            max_consecutive_losses: 3,
+            breaker_recovery_wins: 2,
+            min_volatility_slippage_bps: 50,
+            max_volatility_slippage_bps: 500,
+            ..DexSlippage::default()
+        };
+        let mut instruction_data = vec![0u8];
+        instruction_data.extend(0u64.to_le_bytes());
+        instruction_data.extend(config.try_to_vec().unwrap());
+        let instruction = Instruction::new_with_bytes(
+            program_id,
+            &instruction_data,
+            vec![
+                AccountMeta::new(payer.pubkey(), true),
+                AccountMeta::new(state_pda, false),
+                AccountMeta::new_readonly(solana_program::system_program::id(), false),
+                AccountMeta::new_readonly(solana_program::sysvar::rent::id(), false),
+            ],
+        );
+        let transaction = Transaction::new_signed_with_payer(
+            &[instruction],
+            Some(&payer.pubkey()),
+            &[&payer],
+            recent_blockhash,
+        );
+        assert_eq!(banks_client.process_transaction(transaction).await.is_ok(), true);
+
+        let state = banks_client.get_account(state_pda).await.unwrap().unwrap();
+        let dex_slippage = DexSlippage::deserialize(&mut &state.data[..]).unwrap();
+
+        // The payer owns the new state, everything else is as submitted
+        assert_eq!(dex_slippage.owner, payer.pubkey());
+        assert_eq!(dex_slippage.arb_tx_price, config.arb_tx_price);
+        assert_eq!(dex_slippage.enable_trading, config.enable_trading);
+        assert_eq!(dex_slippage.token_pair, config.token_pair);
+        assert_eq!(dex_slippage.trading_balance_in_tokens, config.trading_balance_in_tokens);
+        assert_eq!(dex_slippage.is_slippage_set, config.is_slippage_set);
+        assert_eq!(dex_slippage.slippage_percent, config.slippage_percent);
+        assert_eq!(dex_slippage.mev_enabled, config.mev_enabled);
+        assert_eq!(dex_slippage.liquidity_threshold, config.liquidity_threshold);
+        assert_eq!(dex_slippage.compound, config.compound);
+        assert_eq!(dex_slippage.mev_in_progress, config.mev_in_progress);
+        assert_eq!(dex_slippage.outstanding_loan, config.outstanding_loan);
+        assert_eq!(dex_slippage.pending_owner, config.pending_owner);
+        assert_eq!(dex_slippage.pending_owner_slot, config.pending_owner_slot);
+        assert_eq!(dex_slippage.pending_owner_window_slots, config.pending_owner_window_slots);
+        assert_eq!(dex_slippage.owners, config.owners);
+        assert_eq!(dex_slippage.threshold, config.threshold);
+        assert_eq!(dex_slippage.min_profit_threshold, config.min_profit_threshold);
+        assert_eq!(dex_slippage.withdraw_delay_slots, config.withdraw_delay_slots);
+        assert_eq!(dex_slippage.instant_withdraw_limit, config.instant_withdraw_limit);
+        assert_eq!(dex_slippage.pending_withdraw_amount, config.pending_withdraw_amount);
+        assert_eq!(dex_slippage.pending_withdraw_slot, config.pending_withdraw_slot);
+        assert_eq!(dex_slippage.total_profit, config.total_profit);
+        assert_eq!(dex_slippage.last_profit_nonce, config.last_profit_nonce);
+        assert_eq!(dex_slippage.log_level, config.log_level);
+        assert_eq!(dex_slippage.migration_destinations, config.migration_destinations);
+        assert_eq!(dex_slippage.allow_zero_amount, config.allow_zero_amount);
+        assert_eq!(dex_slippage.base_mint, config.base_mint);
+        assert_eq!(dex_slippage.quote_mint, config.quote_mint);
+        assert_eq!(dex_slippage.retry_slippage_step_bps, config.retry_slippage_step_bps);
+        assert_eq!(dex_slippage.max_retry_slippage_bps, config.max_retry_slippage_bps);
+        assert_eq!(dex_slippage.paused, config.paused);
+        assert_eq!(dex_slippage.auto_pause_on_discrepancy, config.auto_pause_on_discrepancy);
+        assert_eq!(dex_slippage.max_oracle_confidence_bps, config.max_oracle_confidence_bps);
+        assert_eq!(dex_slippage.liquidity_ratio_scale, config.liquidity_ratio_scale);
+        assert_eq!(dex_slippage.optimization_iterations, config.optimization_iterations);
+        assert_eq!(dex_slippage.arbitrage_iterations, config.arbitrage_iterations);
+        assert_eq!(dex_slippage.unswept_profit, config.unswept_profit);
+        assert_eq!(dex_slippage.profit_in_sol, config.profit_in_sol);
+        assert_eq!(dex_slippage.attempts_this_slot, config.attempts_this_slot);
+        assert_eq!(dex_slippage.attempts_slot, config.attempts_slot);
+        assert_eq!(dex_slippage.max_attempts_per_slot, config.max_attempts_per_slot);
+        assert_eq!(dex_slippage.rebalance_remainder_to_a, config.rebalance_remainder_to_a);
+        assert_eq!(dex_slippage.approved_delegate, config.approved_delegate);
+        assert_eq!(dex_slippage.max_spread_bps, config.max_spread_bps);
+        assert_eq!(dex_slippage.router_filter_mode, config.router_filter_mode);
+        assert_eq!(dex_slippage.filtered_routers, config.filtered_routers);
+        assert_eq!(dex_slippage.cached_plan, config.cached_plan);
+        assert_eq!(dex_slippage.max_plan_age_slots, config.max_plan_age_slots);
+        assert_eq!(dex_slippage.guardian, config.guardian);
+        assert_eq!(dex_slippage.min_slippage_change_interval_slots, config.min_slippage_change_interval_slots);
+        assert_eq!(dex_slippage.last_slippage_change_slot, config.last_slippage_change_slot);
+        assert_eq!(dex_slippage.max_fallback_amount, config.max_fallback_amount);
+        assert_eq!(dex_slippage.position_caps, config.position_caps);
+        assert_eq!(dex_slippage.liquidity_threshold_mint, config.liquidity_threshold_mint);
+        assert_eq!(dex_slippage.safe_mode, config.safe_mode);
+        assert_eq!(dex_slippage.daily_withdraw_limit, config.daily_withdraw_limit);
+        assert_eq!(dex_slippage.withdrawn_today, config.withdrawn_today);
+        assert_eq!(dex_slippage.withdraw_window_start_slot, config.withdraw_window_start_slot);
+        assert_eq!(dex_slippage.approval_slot, config.approval_slot);
+        assert_eq!(dex_slippage.approval_ttl_slots, config.approval_ttl_slots);
+        assert_eq!(dex_slippage.profit_mint, config.profit_mint);
+        assert_eq!(dex_slippage.profit_conversion_router, config.profit_conversion_router);
+        assert_eq!(dex_slippage.trade_count, config.trade_count);
+        assert_eq!(dex_slippage.recent_profits, config.recent_profits);
+        assert_eq!(dex_slippage.recent_profits_next, config.recent_profits_next);
+        assert_eq!(dex_slippage.max_discrepancy_tolerance, config.max_discrepancy_tolerance);
+        assert_eq!(dex_slippage.strategy, config.strategy);
+        assert_eq!(dex_slippage.oracles, config.oracles);
+        assert_eq!(dex_slippage.oracle_quorum, config.oracle_quorum);
+        assert_eq!(dex_slippage.oracle_band_bps, config.oracle_band_bps);
+        assert_eq!(dex_slippage.buy_slippage_bps, config.buy_slippage_bps);
+        assert_eq!(dex_slippage.sell_slippage_bps, config.sell_slippage_bps);
+        assert_eq!(dex_slippage.compound_threshold, config.compound_threshold);
+        assert_eq!(dex_slippage.pending_compound, config.pending_compound);
+        assert_eq!(dex_slippage.min_hops, config.min_hops);
+        assert_eq!(dex_slippage.fee_bps, config.fee_bps);
+        assert_eq!(dex_slippage.fee_recipient, config.fee_recipient);
+        assert_eq!(dex_slippage.accrued_fees, config.accrued_fees);
+        assert_eq!(dex_slippage.max_interleg_deviation_bps, config.max_interleg_deviation_bps);
+        assert_eq!(dex_slippage.min_operating_lamports, config.min_operating_lamports);
+        assert_eq!(dex_slippage.max_consecutive_losses, config.max_consecutive_losses);
+        assert_eq!(dex_slippage.consecutive_losses, config.consecutive_losses);
+        assert_eq!(dex_slippage.breaker_recovery_wins, config.breaker_recovery_wins);
+        assert_eq!(dex_slippage.breaker_recovery_remaining, config.breaker_recovery_remaining);
+        assert_eq!(dex_slippage.router_fees, config.router_fees);
+        assert_eq!(dex_slippage.bundle_snapshot_account, config.bundle_snapshot_account);
+        assert_eq!(dex_slippage.bundle_snapshot_balance, config.bundle_snapshot_balance);
+        assert_eq!(dex_slippage.price_history, config.price_history);
+        assert_eq!(dex_slippage.price_history_next, config.price_history_next);
+        assert_eq!(dex_slippage.min_volatility_slippage_bps, config.min_volatility_slippage_bps);
+        assert_eq!(dex_slippage.max_volatility_slippage_bps, config.max_volatility_slippage_bps);
+    }
+
+    #[test]
+    fn test_profit_delta_mismatch() {
+        let mut dex_slippage = DexSlippage::default();
+
+        // Unchecked by default
+        assert_eq!(check_profit_delta(&dex_slippage, 1_000, 1_000, 500).is_ok(), true);
+
+        dex_slippage.max_profit_delta_mismatch = 10;
+
+        // Within the bound either way
+        assert_eq!(check_profit_delta(&dex_slippage, 1_000, 1_500, 500).is_ok(), true);
+        assert_eq!(check_profit_delta(&dex_slippage, 1_000, 1_510, 500).is_ok(), true);
+        assert_eq!(check_profit_delta(&dex_slippage, 1_000, 1_490, 500).is_ok(), true);
+
+        // An inconsistent delta is caught, too high or too low
         assert_eq!(
-            withdraw_funds(&program_id, &accounts).is_ok(),
-            true
+            check_profit_delta(&dex_slippage, 1_000, 1_900, 500),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            check_profit_delta(&dex_slippage, 1_000, 1_000, 500),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_profit_delta_checked_against_residual() {
+        let program_id = Pubkey::new_unique();
+        let router = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let mut fixture = SwapFixture::new(
+            program_id,
+            router,
+            token_account_data(mint, program_id, 1_000),
+            token_account_data(mint, program_id, 1_000),
+        );
+        let accounts = fixture.accounts();
+
+        // 100 own -> 125 out: the arbitrage reports 125, only 25 of it is profit.
+        // The output account doesn't move, within 30 of the residual but not of 125.
+        for (max_profit_delta_mismatch, expected) in [(30, Ok(())), (20, Err(ProgramError::InvalidAccountData))] {
+            let mut dex_slippage = DexSlippage {
+                enable_trading: true,
+                mev_enabled: true,
+                strategy: MevStrategy::SpotArb,
+                max_profit_delta_mismatch,
+                ..DexSlippage::default()
+            };
+            let res = perform_mev(
+                &mut dex_slippage,
+                &accounts[0],
+                None,
+                &accounts[1],
+                &accounts[2],
+                &accounts[1..],
+                None,
+                100,
+                0,
+                1,
+            );
+            assert_eq!(res, expected);
+        }
+    }
+
+    #[test]
+    fn test_handler_on_allocated_size_state() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        // Laid out like a real account: LEN bytes, the unset Options leaving zeros at the end
+        let serialized = DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        }
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(serialized.len() < DexSlippage::LEN, true);
+
+        let mut state_data = vec![0u8; DexSlippage::LEN];
+        state_data[..serialized.len()].copy_from_slice(&serialized);
+        let mut fixture = SetterFixture::new(program_id, owner, state_data);
+        let accounts = fixture.accounts();
+
+        assert_eq!(set_slippage(&program_id, &accounts, 5).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.owner, owner);
+        assert_eq!(dex_slippage.slippage_percent, 5);
+    }
+
+    #[test]
+    fn test_shrinking_state_leaves_no_stale_bytes() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        let serialized = DexSlippage {
+            owner,
+            ..DexSlippage::default()
+        }
+        .try_to_vec()
+        .unwrap();
+
+        let mut state_data = vec![0u8; DexSlippage::LEN];
+        state_data[..serialized.len()].copy_from_slice(&serialized);
+        let mut fixture = SetterFixture::new(program_id, owner, state_data);
+        let accounts = fixture.accounts();
+
+        // Setting a delegate grows the state by a key, clearing it shrinks it again
+        let delegate = Pubkey::new_unique();
+        assert_eq!(set_approved_delegate(&program_id, &accounts, Some(delegate)).is_ok(), true);
+        assert_eq!(set_approved_delegate(&program_id, &accounts, None).is_ok(), true);
+        assert_eq!(accounts[1].data.borrow()[serialized.len()..].iter().all(|byte| *byte == 0), true);
+
+        // The account stays readable and writable
+        assert_eq!(set_slippage(&program_id, &accounts, 5).is_ok(), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(dex_slippage.approved_delegate, None);
+        assert_eq!(dex_slippage.slippage_percent, 5);
+    }
+
+    fn state_migration_processor(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> ProgramResult {
+        match instruction_data {
+            [] => migrate_state(program_id, accounts),
+            [slippage_percent] => set_slippage(program_id, accounts, *slippage_percent),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_setter_on_v1_state_after_migration() {
+        let program_id = Pubkey::new_unique();
+        let owner = Keypair::new();
+        let state_account = Pubkey::new_unique();
+        let rent = Rent::default();
+
+        let mut program_test = ProgramTest::new(
+            "solana_mev_engine",
+            program_id,
+            processor!(state_migration_processor),
+        );
+        let state_data = DexSlippageV1 {
+            owner: owner.pubkey(),
+            arb_tx_price: 5_000,
+            slippage_percent: 3,
+            ..DexSlippageV1::default()
+        }
+        .try_to_vec()
+        .unwrap();
+        program_test.add_account(
+            state_account,
+            Account {
+                lamports: rent.minimum_balance(state_data.len()),
+                data: state_data,
+                owner: program_id,
+                ..Account::default()
+            },
+        );
+        program_test.add_account(
+            owner.pubkey(),
+            Account {
+                lamports: 1_000_000_000,
+                ..Account::default()
+            },
+        );
+
+        let (mut banks_client, payer, recent_blockhash) = program_test.start().await;
+        let mut results = vec![];
+        for instruction_data in [vec![7u8], vec![], vec![8u8]] {
+            let mut accounts = vec![
+                AccountMeta::new(owner.pubkey(), true),
+                AccountMeta::new(state_account, false),
+            ];
+            if instruction_data.is_empty() {
+                accounts.push(AccountMeta::new_readonly(solana_program::system_program::id(), false));
+            }
+            let transaction = Transaction::new_signed_with_payer(
+                &[Instruction::new_with_bytes(program_id, &instruction_data, accounts)],
+                Some(&payer.pubkey()),
+                &[&payer, &owner],
+                recent_blockhash,
+            );
+            results.push(banks_client.process_transaction(transaction).await);
+        }
+
+        // The v1 account reads but can't take the current state until migrated
+        assert_eq!(
+            results[0].as_ref().unwrap_err().unwrap(),
+            TransactionError::InstructionError(0, InstructionError::AccountDataTooSmall)
+        );
+        assert_eq!(results[1].is_ok(), true);
+        assert_eq!(results[2].is_ok(), true);
+
+        let state = banks_client.get_account(state_account).await.unwrap().unwrap();
+        assert_eq!(state.data.len(), DexSlippage::LEN);
+        assert_eq!(state.lamports >= rent.minimum_balance(DexSlippage::LEN), true);
+        let dex_slippage = DexSlippage::from_versioned_slice(&state.data).unwrap();
+        assert_eq!(dex_slippage.owner, owner.pubkey());
+        assert_eq!(dex_slippage.arb_tx_price, 5_000);
+        assert_eq!(dex_slippage.slippage_percent, 8);
+    }
+
+    #[test]
+    fn test_owner_must_sign_at_threshold_one() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let dex_slippage = DexSlippage {
+            owner,
+            threshold: 1,
+            ..DexSlippage::default()
+        };
+
+        let mut owner_lamports = 0;
+        let mut owner_data = vec![];
+        let accounts = vec![AccountInfo::new(
+            &owner,
+            false,
+            false,
+            &mut owner_lamports,
+            &mut owner_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        )];
+
+        assert_eq!(
+            assert_owner(&dex_slippage, &accounts[0], &accounts),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_owner_counts_once() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+
+        // Stored before duplicates were rejected
+        let mut owners = [Pubkey::default(); MAX_OWNERS];
+        owners[0] = owner;
+        owners[1] = owner;
+        let dex_slippage = DexSlippage {
+            owner,
+            owners,
+            threshold: 2,
+            ..DexSlippage::default()
+        };
+
+        let mut owner_lamports = 0;
+        let mut owner_data = vec![];
+        let accounts = vec![AccountInfo::new(
+            &owner,
+            true,
+            false,
+            &mut owner_lamports,
+            &mut owner_data,
+            &program_id,
+            false,
+            Epoch::default(),
+        )];
+
+        assert_eq!(
+            assert_owner(&dex_slippage, &accounts[0], &accounts),
+            Err(ProgramError::MissingRequiredSignature)
         );
     }
+
+    #[test]
+    fn test_set_owners_rejects_malformed_sets() {
+        let owner = Pubkey::new_unique();
+        let co_owner = Pubkey::new_unique();
+
+        let mut owners = [Pubkey::default(); MAX_OWNERS];
+        owners[0] = owner;
+        owners[1] = co_owner;
+        assert_eq!(validate_owners(&owner, &owners, 2).is_ok(), true);
+
+        // Twice the same key
+        let mut duplicated = owners;
+        duplicated[1] = owner;
+        assert_eq!(validate_owners(&owner, &duplicated, 2), Err(ProgramError::InvalidArgument));
+
+        // A default key between listed owners
+        let mut gapped = [Pubkey::default(); MAX_OWNERS];
+        gapped[0] = owner;
+        gapped[2] = co_owner;
+        assert_eq!(validate_owners(&owner, &gapped, 2), Err(ProgramError::InvalidArgument));
+
+        // The owner left out
+        let mut without_owner = [Pubkey::default(); MAX_OWNERS];
+        without_owner[0] = co_owner;
+        without_owner[1] = Pubkey::new_unique();
+        assert_eq!(validate_owners(&owner, &without_owner, 2), Err(ProgramError::InvalidArgument));
+
+        // Unreachable threshold
+        assert_eq!(validate_owners(&owner, &owners, 3), Err(ProgramError::InvalidArgument));
+    }
 }